@@ -88,23 +88,61 @@ function AgentCard(props: IAgentProps) {
       case "Pid":
         scope = `Pid(${nfo[0].scope.pid})`;
         break;
+      case "Descendants":
+        scope = `Pid(${nfo[0].scope.pid}+)`;
+        break;
+      case "Pids":
+        scope = `Pids(${nfo[0].scope.pids.join(", ")})`;
+        break;
+      case "Cgroup":
+        scope = `Cgroup(${nfo[0].scope.path})`;
+        break;
+      case "ProcessName":
+        scope = `Name(${nfo[0].scope.pattern}*)`;
+        break;
       case "SystemWide":
         scope = `*`;
     };
     switch (nfo[0].type) {
       case 'Perf':
-        txt = `${nfo[0].type}(${nfo[0].period}) @ ${scope}`;
+        let sampling = nfo[0].sampling.type === "Period" ? nfo[0].sampling.period : `${nfo[0].sampling.hz}Hz`;
+        let aggregate = nfo[0].aggregate ? ", aggregated" : "";
+        txt = `${nfo[0].type}(${nfo[0].event}, ${sampling}${aggregate}) @ ${scope}`;
         break;
       case 'Uprobe':
+      case 'Latency':
         txt = `${nfo[0].type}(${nfo[0].uprobe}) @ ${scope}`;
         break;
+      case 'WallClock':
+        txt = `${nfo[0].type}(${nfo[0].period}) @ ${scope}`;
+        break;
+      case 'Kprobe':
+        txt = `${nfo[0].type}(${nfo[0].function}) @ ${scope}`;
+        break;
+      case 'Tracepoint':
+        txt = `${nfo[0].type}(${nfo[0].category}:${nfo[0].name}) @ ${scope}`;
+        break;
+      case 'Memory':
+      case 'OffCpu':
+        txt = `${nfo[0].type} @ ${scope}`;
+        break;
     };
 
     var baseURL = window.document.URL;
-    const data_url = new URL("/api/calltree", baseURL);
-    data_url.search = new URLSearchParams({host_name: props.host_name, probe: JSON.stringify(nfo[0])}).toString();
-    const flamegraph_url = new URL("/flamegraph/app.html", baseURL);
-    flamegraph_url.searchParams.append("profileURL", data_url.toString());
+    let flamegraph = (by_value: boolean) => {
+      const data_url = new URL("/api/calltree", baseURL);
+      data_url.search = new URLSearchParams({host_name: props.host_name, probe: JSON.stringify(nfo[0]), by_value: String(by_value)}).toString();
+      const flamegraph_url = new URL("/flamegraph/app.html", baseURL);
+      flamegraph_url.searchParams.append("profileURL", data_url.toString());
+      return flamegraph_url;
+    };
+    // latency samples count one call each and carry its duration as the value
+    let by_value = (nfo[0].type === 'Uprobe' && nfo[0].value_arg !== null) || nfo[0].type === 'Memory' || nfo[0].type === 'Latency';
+    const flamegraph_url = flamegraph(by_value);
+    // the latency histogram at the leaves counts calls
+    const calls_link = nfo[0].type === 'Latency'
+      ? <> (<Link color="black" onClick={() => window.open(flamegraph(false))}>calls</Link>)</>
+      : null;
 
           return <ListItem
             key={JSON.stringify(nfo[0])}
@@ -118,7 +156,7 @@ function AgentCard(props: IAgentProps) {
               </AvatarWrapperSuccess>
             </ListItemAvatar> */}
             <ListItemText
-              primary={<Link color="black" onClick={() => window.open(flamegraph_url)}>{txt}</Link>}
+              primary={<><Link color="black" onClick={() => window.open(flamegraph_url)}>{txt}</Link>{calls_link}</>}
               primaryTypographyProps={{
                 variant: 'body1',
                 fontWeight: 'bold',
@@ -138,6 +176,11 @@ function AgentCard(props: IAgentProps) {
           </ListItem>
   });
 
+  // lost samples can't be attributed to a probe, so the whole agent is flagged
+  let loss = agent.sample_loss;
+  let loss_pct = loss && loss.produced > 0 ? 100 * loss.lost / loss.produced : 0;
+  let subheader = loss_pct > 0 ? <Text color="warning">{`${loss_pct.toFixed(2)}% of samples lost, profiles are incomplete`}</Text> : null;
+
   let stop_agent = async () => {
     await fetch(`/api/agent/halt?name=${props.host_name}`);
   }
@@ -159,6 +202,7 @@ function AgentCard(props: IAgentProps) {
           }
           action={agent.is_halted? null : <Button color="error" onClick={() => stop_agent()}>Stop</Button>}
           title={props.host_name}
+          subheader={subheader}
           titleTypographyProps={{
             variant: 'body1',
             fontWeight: 'bold',
@@ -1,27 +1,72 @@
 import { Box, Button, Card, FormControl, FormGroup, InputLabel, MenuItem, Modal, Select, TextField, Typography } from "@mui/material";
 import { useState } from "react";
-import { INewProbeModalProps, IScope, make_perf_probe, make_uprobe_probe, ProbeTypes, start_probe } from "./types";
+import { INewProbeModalProps, IPerfEventKind, IScope, make_perf_probe, make_uprobe_probe, make_offcpu_probe, make_wallclock_probe, make_kprobe_probe, make_latency_probe, make_memory_probe, make_tracepoint_probe, ProbeTypes, start_probe } from "./types";
 
 
 export function NewProbeModal(props: INewProbeModalProps) {
   let [mode, setMode] = useState<ProbeTypes>("Perf");
   let [uprobe, setUprobe] = useState("libc:malloc");
+  let [valueArg, setValueArg] = useState("");
+  let [kprobe, setKprobe] = useState("tcp_sendmsg");
+  let [tracepoint, setTracepoint] = useState("syscalls:sys_enter_write");
   let [period, setPeriod] = useState(4000000);
+  let [event, setEvent] = useState<IPerfEventKind>("TaskClock");
+  let [byFrequency, setByFrequency] = useState(false);
   let [scope, setScope] = useState<IScope>({type: "SystemWide", pid: 0} as any);
+  let [pidsText, setPidsText] = useState("");
 
   let args;
   if (mode == "Perf")
+  {
+    args = <>
+      <Select key="event" value={event} onChange={(e) => setEvent(e.target.value as IPerfEventKind)}>
+        {["TaskClock", "CpuClock", "PageFaults", "ContextSwitches", "CpuMigrations", "Cycles", "Instructions", "CacheMisses", "BranchMisses"]
+          .map((k) => <MenuItem key={k} value={k}>{k}</MenuItem>)}
+      </Select>
+      <Select key="sampling" value={byFrequency ? "Frequency" : "Period"} onChange={(e) => setByFrequency(e.target.value === "Frequency")}>
+        <MenuItem value="Period">Period</MenuItem>
+        <MenuItem value="Frequency">Frequency (Hz)</MenuItem>
+      </Select>
+      <TextField label={byFrequency ? "Frequency" : "Period"} inputProps={{ inputMode: 'numeric', pattern: '[0-9]*' }} value={period} onChange={(e) => setPeriod(parseInt(e.target.value))} />
+    </>
+  } else if (mode == "WallClock")
   {
     args = <TextField label="Period" inputProps={{ inputMode: 'numeric', pattern: '[0-9]*' }} value={period} onChange={(e) => setPeriod(parseInt(e.target.value))} />
   } else if (mode == "Uprobe")
+  {
+    args = <>
+      <TextField label="uprobe" inputProps={{ pattern: '.+:.+' }} value={uprobe} onChange={(e) => setUprobe(e.target.value)} />
+      <TextField label="value argument (optional)" inputProps={{ inputMode: 'numeric', pattern: '[0-9]*' }} value={valueArg} onChange={(e) => setValueArg(e.target.value)} />
+    </>
+  } else if (mode == "Latency")
   {
     args = <TextField label="uprobe" inputProps={{ pattern: '.+:.+' }} value={uprobe} onChange={(e) => setUprobe(e.target.value)} />
+  } else if (mode == "Kprobe")
+  {
+    args = <TextField label="kernel function" value={kprobe} onChange={(e) => setKprobe(e.target.value)} />
+  } else if (mode == "Tracepoint")
+  {
+    args = <TextField label="tracepoint" inputProps={{ pattern: '.+:.+' }} value={tracepoint} onChange={(e) => setTracepoint(e.target.value)} />
   }
 
   let pid = 
         <TextField label="PID" inputProps={{ inputMode: 'numeric', pattern: '[0-9]*' }} value={(scope as any).pid}
         onChange={(e) => setScope({...scope, pid: parseInt(e.target.value)} as any)} />
     ;
+  let scopeArgs = null;
+  if (scope.type === "Pid" || scope.type === "Descendants") {
+    scopeArgs = pid;
+  } else if (scope.type === "Pids") {
+    scopeArgs = <TextField label="PIDs" inputProps={{ pattern: '[0-9,]*' }} value={pidsText}
+        onChange={(e) => {
+          setPidsText(e.target.value);
+          setScope({type: "Pids", pids: e.target.value.split(",").filter((p) => p !== "").map((p) => parseInt(p))});
+        }} />
+  } else if (scope.type === "Cgroup") {
+    scopeArgs = <TextField label="cgroup path" value={scope.path} onChange={(e) => setScope({type: "Cgroup", path: e.target.value})} />
+  } else if (scope.type === "ProcessName") {
+    scopeArgs = <TextField label="process name prefix" value={scope.pattern} onChange={(e) => setScope({type: "ProcessName", pattern: e.target.value})} />
+  }
 
   return <Modal
       open={props.open}
@@ -48,6 +93,12 @@ export function NewProbeModal(props: INewProbeModalProps) {
                 >
                     <MenuItem value="Perf">Perf</MenuItem>
                     <MenuItem value="Uprobe">UProbe</MenuItem>
+                    <MenuItem value="Latency">Latency</MenuItem>
+                    <MenuItem value="Memory">Memory (live heap)</MenuItem>
+                    <MenuItem value="Kprobe">KProbe</MenuItem>
+                    <MenuItem value="Tracepoint">Tracepoint</MenuItem>
+                    <MenuItem value="OffCpu">Off-CPU</MenuItem>
+                    <MenuItem value="WallClock">Wall-clock</MenuItem>
                 </Select>
 
                 {args}
@@ -61,13 +112,17 @@ export function NewProbeModal(props: INewProbeModalProps) {
                     id="scope"
                     value={scope.type}
                     label="Scope"
-                    onChange={(e) => setScope({type: e.target.value as any})}
+                    onChange={(e) => setScope({type: e.target.value as any, pid: 0, pids: [], path: "", pattern: ""} as any)}
                 >
                     <MenuItem value="SystemWide">SystemWide</MenuItem>
                     <MenuItem value="Pid">Pid</MenuItem>
+                    <MenuItem value="Descendants">Pid and children</MenuItem>
+                    <MenuItem value="Pids">Pids</MenuItem>
+                    <MenuItem value="Cgroup">Cgroup</MenuItem>
+                    <MenuItem value="ProcessName">Process name</MenuItem>
                 </Select>
 
-                { scope.type === "SystemWide" ? null : pid }
+                {scopeArgs}
             </FormControl>
 
           <Button
@@ -75,9 +130,23 @@ export function NewProbeModal(props: INewProbeModalProps) {
             onClick={() => {
                 let probe;
                 if (mode == "Perf") {
-                    probe = make_perf_probe(scope, period);
+                    let sampling = byFrequency ? {type: "Frequency", hz: period} as const : {type: "Period", period} as const;
+                    probe = make_perf_probe(scope, event, sampling);
                 } else if (mode == "Uprobe") {
-                    probe = make_uprobe_probe(scope, uprobe);
+                    probe = make_uprobe_probe(scope, uprobe, valueArg === "" ? null : parseInt(valueArg));
+                } else if (mode == "Latency") {
+                    probe = make_latency_probe(scope, uprobe);
+                } else if (mode == "Memory") {
+                    probe = make_memory_probe(scope);
+                } else if (mode == "Kprobe") {
+                    probe = make_kprobe_probe(scope, kprobe);
+                } else if (mode == "Tracepoint") {
+                    let [category, name] = tracepoint.split(":");
+                    probe = make_tracepoint_probe(scope, category, name);
+                } else if (mode == "OffCpu") {
+                    probe = make_offcpu_probe(scope);
+                } else if (mode == "WallClock") {
+                    probe = make_wallclock_probe(scope, period);
                 }
                 start_probe(props.name, probe);
                 props.handleClose();
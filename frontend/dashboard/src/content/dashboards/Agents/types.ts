@@ -1,4 +1,4 @@
-export type ProbeTypes = "Perf" | "Uprobe";
+export type ProbeTypes = "Perf" | "Uprobe" | "OffCpu" | "WallClock" | "Kprobe" | "Tracepoint" | "Latency" | "Memory";
 
 interface IProbeState {
   is_running: boolean;
@@ -14,9 +14,15 @@ export interface IAgentProps {
   agent: IAgent;
 }
 
+export interface ISampleLoss {
+  produced: number;
+  lost: number;
+}
+
 export interface IAgent {
   is_halted: boolean,
   probes: IProbeInfo[];
+  sample_loss: ISampleLoss;
 }
 
 export interface IAgents {
@@ -45,20 +51,82 @@ export interface INewProbeModalProps {
 export type IScope = {
   type: "Pid";
   pid: number;
+} | {
+  type: "Descendants";
+  pid: number;
+} | {
+  type: "Pids";
+  pids: number[];
+} | {
+  type: "Cgroup";
+  path: string;
+} | {
+  type: "ProcessName";
+  pattern: string;
 } | {
   type: "SystemWide";
 };
 
+export type IPerfEventKind =
+  "TaskClock" | "CpuClock" | "PageFaults" | "ContextSwitches" | "CpuMigrations" |
+  "Cycles" | "Instructions" | "CacheMisses" | "BranchMisses";
+
+export type ISampling = {
+  type: "Period";
+  period: number;
+} | {
+  type: "Frequency";
+  hz: number;
+};
+
 export type IPerfProbe = {
   type: "Perf";
   scope: IScope;
-  period: number;
+  event: IPerfEventKind;
+  sampling: ISampling;
+  aggregate?: boolean;
 }
 
 export type IUprobeProbe = {
   type: "Uprobe";
   scope: IScope;
   uprobe: string;
+  value_arg: number | null;
+}
+
+export type IOffCpuProbe = {
+  type: "OffCpu";
+  scope: IScope;
+}
+
+export type IWallClockProbe = {
+  type: "WallClock";
+  scope: IScope;
+  period: number;
+}
+
+export type ILatencyProbe = {
+  type: "Latency";
+  scope: IScope;
+  uprobe: string;
+}
+
+export type IMemoryProbe = {
+  type: "Memory";
+  scope: IScope;
+}
+
+export type IKprobeProbe = {
+  type: "Kprobe";
+  scope: IScope;
+  function: string;
+}
+
+export type ITracepointProbe = {
+  type: "Tracepoint";
+  scope: IScope;
+  category: string;
+  name: string;
 }
 
 export type ICallTreeParams = {
@@ -66,21 +134,70 @@ export type ICallTreeParams = {
   host_name: string;
 }
 
-export type IProbe = IPerfProbe | IUprobeProbe;
+export type IProbe = IPerfProbe | IUprobeProbe | IOffCpuProbe | IWallClockProbe | IKprobeProbe | ITracepointProbe | ILatencyProbe | IMemoryProbe;
 
-export function make_perf_probe(scope: IScope, period: number): IPerfProbe {
+export function make_perf_probe(scope: IScope, event: IPerfEventKind, sampling: ISampling): IPerfProbe {
     return {
         type: "Perf",
         scope,
-        period,
+        event,
+        sampling,
     };
 }
 
-export function make_uprobe_probe(scope: IScope, uprobe: string): IUprobeProbe {
+export function make_uprobe_probe(scope: IScope, uprobe: string, value_arg: number | null): IUprobeProbe {
     return {
         type: "Uprobe",
         scope,
         uprobe,
+        value_arg,
+    };
+}
+
+export function make_offcpu_probe(scope: IScope): IOffCpuProbe {
+    return {
+        type: "OffCpu",
+        scope,
+    };
+}
+
+export function make_wallclock_probe(scope: IScope, period: number): IWallClockProbe {
+    return {
+        type: "WallClock",
+        scope,
+        period,
+    };
+}
+
+export function make_latency_probe(scope: IScope, uprobe: string): ILatencyProbe {
+    return {
+        type: "Latency",
+        scope,
+        uprobe,
+    };
+}
+
+export function make_memory_probe(scope: IScope): IMemoryProbe {
+    return {
+        type: "Memory",
+        scope,
+    };
+}
+
+export function make_kprobe_probe(scope: IScope, func: string): IKprobeProbe {
+    return {
+        type: "Kprobe",
+        scope,
+        function: func,
+    };
+}
+
+export function make_tracepoint_probe(scope: IScope, category: string, name: string): ITracepointProbe {
+    return {
+        type: "Tracepoint",
+        scope,
+        category,
+        name,
     };
 }
 
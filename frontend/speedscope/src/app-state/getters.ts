@@ -68,6 +68,13 @@ export const getFrameToColorBucket = memoizeByReference(
     frameToColorBucket.set(CodeType.Native, 100)
     frameToColorBucket.set(CodeType.Python, 255)
     frameToColorBucket.set(CodeType.ProcessRoot, 150)
+    frameToColorBucket.set(CodeType.Thread, 170)
+    frameToColorBucket.set(CodeType.OnCpu, 60)
+    frameToColorBucket.set(CodeType.OffCpu, 210)
+    frameToColorBucket.set(CodeType.Latency, 230)
+    frameToColorBucket.set(CodeType.Truncated, 30)
+    frameToColorBucket.set(CodeType.UnwindError, 20)
+    frameToColorBucket.set(CodeType.Gil, 190)
     console.log(frameToColorBucket);
 
     return frameToColorBucket
@@ -11,6 +11,13 @@ export enum CodeType {
     Python = "Python",
     Kernel = "Kernel",
     ProcessRoot = "ProcessRoot",
+    Thread = "Thread",
+    OnCpu = "OnCpu",
+    OffCpu = "OffCpu",
+    Latency = "Latency",
+    Truncated = "Truncated",
+    UnwindError = "UnwindError",
+    Gil = "Gil",
 }
 
 export interface IResolvedFrame {
@@ -0,0 +1,30 @@
+use crate::{pidtgid::PidTgid, python::state::FRAME_MAX_LEN, NativeStack};
+
+/// Unique stacks counted in the kernel between two drains
+pub const MAX_AGG_STACKS: u32 = 8192;
+
+/// Kernel frames kept with an aggregated stack
+pub const MAX_AGG_KERNEL_STACK: usize = 64;
+
+/// A unique stack of an aggregating probe along with its count.
+/// The kernel frames are copied, a stack id could be reused before the drain.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct AggStack {
+    /// samples since the last drain
+    pub count: u64,
+    /// the first thread the stack was seen in, stacks are aggregated per process
+    pub pidtgid: PidTgid,
+    pub probe_id: u32,
+    pub kernel_frames: [u64; MAX_AGG_KERNEL_STACK],
+    pub kernel_len: u32,
+    pub native_stack: NativeStack,
+    /// ids into `PY_SYMBOLS`, leaf first
+    pub python_ids: [i32; FRAME_MAX_LEN],
+    /// see `PythonStack::eval_entry`
+    pub python_entry: [bool; FRAME_MAX_LEN],
+    pub python_len: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AggStack {}
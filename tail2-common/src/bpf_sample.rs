@@ -1,14 +1,36 @@
-use crate::{pidtgid::PidTgid, python::state::PythonStack, NativeStack, MAX_USER_STACK};
+use crate::{pidtgid::PidTgid, python::state::PythonStack, scope::COMM_LEN, NativeStack, MAX_USER_STACK};
+
+/// Whether the task was running or blocked when the sample was taken
+#[repr(u32)]
+#[cfg_attr(feature="user", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SampleKind {
+    OnCpu = 0,
+    OffCpu = 1,
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct BpfSample {
     pub pidtgid: PidTgid,
+    /// set by the agent from `ktime_ns`
     pub ts_ms: u64,
+    /// `bpf_ktime_get_ns` when the sample was taken
+    pub ktime_ns: u64,
+    pub cpu: u32,
+    /// name of the thread
+    pub comm: [u8; COMM_LEN],
+    pub cgroup_id: u64,
+    /// How much this sample counts for: 1 for a plain sample, nanoseconds for off-cpu samples
+    pub weight: u64,
+    pub kind: SampleKind,
+    /// Optional value read from a probe argument, e.g. the size passed to malloc
+    pub value: Option<u64>,
     pub kernel_stack_id: i64,
     pub native_stack: NativeStack,
     pub python_stack: Option<PythonStack>,
-    pub idx: usize,
+    /// id of the probe the sample was taken for, see `Probes`
+    pub probe_id: u32,
 }
 
 #[cfg(feature = "user")]
@@ -11,13 +11,35 @@ pub mod bpf_sample;
 pub mod native;
 pub mod python;
 pub mod tracemgmt;
+pub mod memory;
+pub mod scope;
+pub mod aggregate;
 
 pub use native::native_stack::NativeStack;
 
 /// Maximum number of frames to unwind
-pub const MAX_USER_STACK: usize = 40;
+pub const MAX_USER_STACK: usize = 256;
+
+/// Frames unwound per bpf program, deeper stacks continue in a tail call
+pub const UNWIND_CHUNK: usize = 32;
+
+/// Maximum number of probes with per-probe config attached at the same time
+pub const MAX_PROBES: u32 = 1024;
+
+/// Maximum number of probes scoped to a set of pids at the same time, exiting processes are
+/// dropped from the set of each of them
+pub const MAX_PIDS_SCOPES: u32 = 64;
+
+/// Switched out tasks whose stacks an off-cpu probe keeps, each takes a `BpfSample`.
+/// The agent config can change it on load.
+pub const DEFAULT_OFFCPU_STACKS: u32 = 128;
+
+/// Size of the ring buffer samples are sent through, a power of two
+pub const RING_BUF_SIZE: u32 = 16 << 20;
 
 pub enum ConfigMapKey {
     DEV = 0,
     INO = 1,
+    /// 1 to send samples through the ring buffer instead of the perf event array
+    RINGBUF = 2,
 }
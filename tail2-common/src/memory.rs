@@ -0,0 +1,34 @@
+use crate::{pidtgid::PidTgid, NativeStack};
+
+/// Key of a live allocation, pointers are only unique within a process
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AllocKey {
+    pub ptr: u64,
+    pub tgid: u32,
+    pub probe_id: u32,
+}
+
+/// An allocation that hasn't been freed yet
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct LiveAlloc {
+    /// key into the allocation stacks map
+    pub stack_id: u64,
+    pub size: u64,
+}
+
+/// Native stack of an allocation site
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemStack {
+    pub pidtgid: PidTgid,
+    pub native_stack: NativeStack,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AllocKey {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LiveAlloc {}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MemStack {}
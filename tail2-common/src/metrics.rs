@@ -27,10 +27,18 @@ iterable_enum! {
     #[repr(u32)]
     vis pub enum Metrics {
         SentStackCount,
+        /// The ring buffer was full, the sample was dropped
+        ErrSend_RingFull,
+        /// Samples the kernel dropped from a full perf buffer, counted in user space
+        ErrSend_PerfLost,
 
         ErrSample_CantAlloc,
         ErrSample_NoPidInfo,
         ErrSample_BinarySearch,
+        /// The pc isn't in any executable mapping with an unwind table
+        ErrSample_NoMapping,
+        /// Jumping to the next unwinder chunk failed, the stack was cut short
+        ErrSample_TailCall,
 
         ErrUnw_InvalidRule,
         ErrUnw_CouldNotReadStack,
@@ -39,10 +47,41 @@ iterable_enum! {
         ErrUnw_IntegerOverflow,
         ErrUnw_ReturnAddressIsNull,
 
+        /// Unable to read a field of the sched_switch tracepoint
+        ErrOffCpu_ReadTracepoint,
+        /// Unable to store the stack of a task being switched out
+        ErrOffCpu_CantStore,
+        /// A task was switched in but its switch-out stack was evicted
+        ErrOffCpu_NoStack,
+
+        /// Unable to store the entry timestamp of a probed function
+        ErrLatency_CantStore,
+        /// The probed function recursed deeper than the calls we time per thread
+        ErrLatency_TooDeep,
+
+        /// Unable to record an allocation, the live allocation map is full
+        ErrMem_CantStore,
+        /// The stack of a live allocation was evicted, it is reported without its frames
+        ErrMem_NoStack,
+
+        /// Unable to count a stack, the aggregation maps are full until the next drain
+        ErrAgg_CantStore,
+
+        /// Unable to read the pid or clone flags of a new task
+        ErrFollow_ReadTracepoint,
+        /// Unable to add a forked child to the scope, the pid set or the pending set is full
+        ErrFollow_CantStore,
+
         /// New pid seen in the tracee
         TraceMgmt_NewPid,
         TraceMgmt_NewPidAlreadyNotified,
         TraceMgmt_PidErr,
+        /// A traced process exited and its unwind info was evicted
+        TraceMgmt_PidExit,
+        /// A traced process called exec and its unwind info was evicted
+        TraceMgmt_PidExec,
+        /// A traced process mapped new executable code, e.g. with dlopen
+        TraceMgmt_NewMap,
 
         ErrPy_NoStack,
         /// No error
@@ -85,6 +124,8 @@ iterable_enum! {
         ///
         ErrPy_READ_FRAME,
         ErrPy_GET_FIRST_ARG,
+        /// Unable to assign an id to a python symbol, `PY_SYMBOLS` is full
+        ErrPy_SYMBOLS_FULL,
         ErrPy_FIRST_ARG_NOT_FOUND,
 
         /// Enum Max
@@ -1,12 +1,16 @@
 use core::mem::MaybeUninit;
 
-use crate::{pidtgid::PidTgid, MAX_USER_STACK};
+use crate::{metrics::Metrics, pidtgid::PidTgid, MAX_USER_STACK};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct NativeStack {
     pub native_stack: [usize; MAX_USER_STACK],
     pub unwind_success: Option<usize>,
+    /// the stack was deeper than `MAX_USER_STACK`, the root end is missing
+    pub truncated: bool,
+    /// why the unwinder gave up, the stack holds the frames it got through
+    pub unwind_error: Option<Metrics>,
 }
 
 impl NativeStack {
@@ -14,6 +18,8 @@ impl NativeStack {
         Self {
             native_stack: [0; MAX_USER_STACK],
             unwind_success: None,
+            truncated: false,
+            unwind_error: None,
         }
     }
 
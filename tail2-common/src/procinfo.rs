@@ -4,40 +4,51 @@ use crate::{
     runtime_type::RuntimeType,
 };
 
-/// 2 ^ 20
-pub const MAX_ROWS_PER_PROC: usize = 130_000;
+/// Size of the row pool shared by all modules, 2 ^ 22
+pub const MAX_UNWIND_ROWS: u32 = 1 << 22;
+
+/// Steps needed to binary search the largest table that fits in the pool
+pub const MAX_SEARCH_STEPS: usize = 23;
+
+/// Executable mappings kept per process
+pub const MAX_MAPPINGS_PER_PROC: usize = 64;
 
 #[cfg(feature = "x86_64")]
 type UnwindRule = UnwindRuleX86_64;
 #[cfg(feature = "aarch64")]
 type UnwindRule = UnwindRuleAarch64;
 
+/// Row of the `UNWIND_ROWS` pool
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UnwindRow {
+    /// relative to the start of the mapping
+    pub pc: usize,
+    pub rule: UnwindRule,
+}
+
+/// An executable mapping of a process and where the unwind table of its module lives
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModuleMapping {
+    pub avma_start: usize,
+    pub avma_end: usize,
+    /// the table is `UNWIND_ROWS[rows_start..rows_start + rows_len]`
+    pub rows_start: u32,
+    pub rows_len: u32,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ProcInfo {
-    pub rows: [(usize, UnwindRule); MAX_ROWS_PER_PROC],
-    pub rows_len: usize,
+    pub mappings: [ModuleMapping; MAX_MAPPINGS_PER_PROC],
+    pub mappings_len: usize,
     pub runtime_type: RuntimeType,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ProcInfo {}
-
 #[cfg(feature = "user")]
-unsafe fn unsafe_allocate<T>() -> Box<T> {
-    let mut grid_box: Box<T>;
-    use std::alloc::{alloc, dealloc, Layout};
-    let layout = Layout::new::<T>();
-    let ptr = unsafe { alloc(layout) as *mut T };
-    grid_box = unsafe { Box::from_raw(ptr) };
-    grid_box
-}
-
-#[cfg(feature = "user")]
-impl ProcInfo {
-    pub fn boxed() -> Box<Self> {
-        unsafe { unsafe_allocate() }
-    }
-}
+unsafe impl aya::Pod for UnwindRow {}
 
 #[cfg(feature = "user")]
 pub mod user {
@@ -71,7 +82,10 @@ pub mod user {
                 .context("unable to convert OsStr to str")?
                 .to_owned();
             if base_name.starts_with("python") || base_name.starts_with("libpython") {
-                return Ok(RuntimeType::python(path, &base_name, paths));
+                return Ok(RuntimeType::python(path, &base_name, paths).unwrap_or_else(|| {
+                    tracing::warn!("unsupported python {}, only native stacks are sampled", path.mod_name);
+                    RuntimeType::Unknown
+                }));
             }
         }
         Ok(RuntimeType::Unknown)
@@ -79,36 +93,73 @@ pub mod user {
 
     impl ProcInfo {
         /// Build a ProcInfo with a list of paths and their offsets
-        /// It must be allocated on the heap or it will segfault(!)
-        pub fn build(mut infos: &[ProcMapRow]) -> Result<Box<ProcInfo>> {
-            let mut ret = ProcInfo::boxed();
-            ret.runtime_type = detect_runtime_type(infos)?;
-
-            // build rows
-            let mut rows = Vec::new();
-            for ProcMapRow {
-                avma, unwind_table, ..
-            } in infos
-            {
-                for UnwindTableRow {
-                    start_address,
-                    rule,
-                } in &unwind_table.rows
-                {
-                    rows.push((*start_address + *avma, *rule));
+        /// `load_table` places the unwind table of a module in the shared row pool
+        /// and returns its (start, len)
+        pub fn build(
+            infos: &[ProcMapRow],
+            mut load_table: impl FnMut(&ProcMapRow) -> Result<(u32, u32)>,
+        ) -> Result<Box<ProcInfo>> {
+            let mut ret = Box::new(ProcInfo {
+                mappings: [ModuleMapping::default(); MAX_MAPPINGS_PER_PROC],
+                mappings_len: 0,
+                runtime_type: detect_runtime_type(infos)?,
+            });
+
+            let mut mappings = Vec::new();
+            for info in infos {
+                match load_table(info) {
+                    Ok((rows_start, rows_len)) => mappings.push(ModuleMapping {
+                        avma_start: info.avma,
+                        avma_end: info.avma_end,
+                        rows_start,
+                        rows_len,
+                    }),
+                    Err(e) => tracing::warn!("no unwind table for {}: {}", info.mod_name, e),
                 }
             }
+            mappings.sort_by_key(|m| m.avma_start);
 
-            let len = rows.len().min(MAX_ROWS_PER_PROC);
-            ret.rows_len = len;
-            ret.rows[..len].copy_from_slice(&rows.as_slice()[..len]);
+            if mappings.len() > MAX_MAPPINGS_PER_PROC {
+                tracing::warn!("{} executable mappings, only unwinding the first {}", mappings.len(), MAX_MAPPINGS_PER_PROC);
+            }
+            let len = mappings.len().min(MAX_MAPPINGS_PER_PROC);
+            ret.mappings_len = len;
+            ret.mappings[..len].copy_from_slice(&mappings[..len]);
             Ok(ret)
         }
     }
 
     pub struct ProcMapRow {
         pub avma: usize,
+        pub avma_end: usize,
         pub mod_name: String,
+        /// device and inode of the file, tell apart modules without a build id
+        pub dev: (i32, i32),
+        pub inode: u64,
+        /// identifies the module across processes
+        pub debug_id: String,
         pub unwind_table: Arc<UnwindTable>,
     }
+
+    impl std::fmt::Debug for ProcMapRow {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("ProcMapRow")
+                .field("avma", &self.avma)
+                .field("avma_end", &self.avma_end)
+                .field("mod_name", &self.mod_name)
+                .field("debug_id", &self.debug_id)
+                .field("rows", &self.unwind_table.rows.len())
+                .finish()
+        }
+    }
+
+    impl ProcMapRow {
+        /// Rows of the unwind table, as stored in `UNWIND_ROWS`
+        pub fn unwind_rows(&self) -> impl Iterator<Item = UnwindRow> + '_ {
+            self.unwind_table.rows.iter().map(|UnwindTableRow { start_address, rule }| UnwindRow {
+                pc: *start_address,
+                rule: *rule,
+            })
+        }
+    }
 }
@@ -0,0 +1,337 @@
+use std::{borrow::Cow, collections::HashMap, fs::File, path::{Path, PathBuf}};
+
+use anyhow::{ensure, Context, Result};
+use gimli::{AttributeValue, Dwarf, Encoding, EndianSlice, Operation, Reader, RunTimeEndian, Unit, UnitOffset};
+use object::{Object, ObjectSection};
+
+use super::offsets::{FrameLayout, PythonOffsets};
+
+/// Separate debug files live under this root, by build id or by debuglink
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// Types the offsets are read from, by typedef name
+const TYPES: &[&str] = &[
+    "PyObject",
+    "PyASCIIObject",
+    "PyTypeObject",
+    "PyThreadState",
+    "PyInterpreterState",
+    "_PyRuntimeState",
+    "PyFrameObject",
+    "_PyCFrame",
+    "_PyInterpreterFrame",
+    "PyCodeObject",
+    "PyTupleObject",
+    "PyBytesObject",
+    "PyStringObject",
+    "_gil_runtime_state",
+];
+
+/// Read the offsets of this exact libpython or python binary from its DWARF, or from its
+/// separate debug file. Fields the debug info doesn't have keep their value from `base`.
+pub fn python_offsets(path: &Path, base: &PythonOffsets) -> Result<PythonOffsets> {
+    let file = File::open(path)?;
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    let obj = object::File::parse(&*mmap)?;
+    if obj.section_by_name(".debug_info").is_some() {
+        return from_object(&obj, base);
+    }
+
+    let debug_path = separate_debug_file(path, &obj).context("no debug info")?;
+    let file = File::open(debug_path)?;
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    from_object(&object::File::parse(&*mmap)?, base)
+}
+
+fn separate_debug_file(path: &Path, obj: &object::File) -> Option<PathBuf> {
+    if let Ok(Some(id)) = obj.build_id() {
+        if id.len() > 1 {
+            let hex: String = id.iter().map(|b| format!("{b:02x}")).collect();
+            let p = Path::new(DEBUG_DIR).join(".build-id").join(&hex[..2]).join(format!("{}.debug", &hex[2..]));
+            if p.exists() {
+                return Some(p);
+            }
+        }
+    }
+
+    let (name, _crc) = obj.gnu_debuglink().ok()??;
+    let name = std::str::from_utf8(name).ok()?;
+    let dir = path.parent()?;
+    [
+        dir.join(".debug").join(name),
+        Path::new(DEBUG_DIR).join(dir.strip_prefix("/").ok()?).join(name),
+        dir.join(name),
+    ]
+    .into_iter()
+    .find(|p| p.exists() && p != path)
+}
+
+fn from_object(obj: &object::File, base: &PythonOffsets) -> Result<PythonOffsets> {
+    let endian = if obj.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+    let sections = Dwarf::load(|id| -> Result<Cow<[u8]>> {
+        Ok(match obj.section_by_name(id.name()) {
+            Some(section) => section.uncompressed_data()?,
+            None => Cow::Borrowed(&[]),
+        })
+    })?;
+    let borrow: &dyn for<'a> Fn(&'a Cow<[u8]>) -> EndianSlice<'a, RunTimeEndian> =
+        &|section| EndianSlice::new(section, endian);
+    let dwarf = sections.borrow(borrow);
+
+    Types::index(&dwarf)?.offsets(base)
+}
+
+/// Definitions of `TYPES`, each from the first unit that has it
+struct Types<'d, R: Reader> {
+    dwarf: &'d Dwarf<R>,
+    units: Vec<Unit<R>>,
+    defs: HashMap<String, (usize, UnitOffset)>,
+}
+
+impl<'d, R: Reader> Types<'d, R> {
+    fn index(dwarf: &'d Dwarf<R>) -> Result<Self> {
+        let mut units = vec![];
+        let mut defs = HashMap::new();
+        let mut headers = dwarf.units();
+        while let Some(header) = headers.next()? {
+            if defs.len() == TYPES.len() {
+                break;
+            }
+
+            let unit = dwarf.unit(header)?;
+            let mut found = false;
+            let mut entries = unit.entries();
+            while let Some((_, entry)) = entries.next_dfs()? {
+                if entry.tag() != gimli::DW_TAG_typedef && entry.tag() != gimli::DW_TAG_structure_type {
+                    continue;
+                }
+                let name = match entry.attr_value(gimli::DW_AT_name)? {
+                    Some(name) => dwarf.attr_string(&unit, name)?.to_string_lossy()?.into_owned(),
+                    None => continue,
+                };
+                if !TYPES.contains(&name.as_str()) || defs.contains_key(&name) {
+                    continue;
+                }
+                if let Some(def) = definition(&unit, entry.offset())? {
+                    defs.insert(name, (units.len(), def));
+                    found = true;
+                }
+            }
+            if found {
+                units.push(unit);
+            }
+        }
+
+        Ok(Self { dwarf, units, defs })
+    }
+
+    fn offsets(&self, base: &PythonOffsets) -> Result<PythonOffsets> {
+        let mut ret = *base;
+        let mut found = 0;
+        let mut set = |field: &mut usize, candidates: &[(&str, &str)]| -> Result<()> {
+            for (ty, path) in candidates {
+                if let Some(offset) = self.offset(ty, path)? {
+                    *field = offset;
+                    found += 1;
+                    return Ok(());
+                }
+            }
+            Ok(())
+        };
+
+        set(&mut ret.py_object.ob_type, &[("PyObject", "ob_type")])?;
+        set(&mut ret.py_type_object.tp_name, &[("PyTypeObject", "tp_name")])?;
+        set(&mut ret.py_thread_state.next, &[("PyThreadState", "next")])?;
+        set(&mut ret.py_thread_state.interp, &[("PyThreadState", "interp")])?;
+        set(&mut ret.py_thread_state.thread, &[("PyThreadState", "thread_id"), ("PyThreadState", "thread")])?;
+        set(&mut ret.py_interpreter_state.tstate_head, &[("PyInterpreterState", "tstate_head"), ("PyInterpreterState", "threads.head")])?;
+        set(&mut ret.py_runtime_state.interp_main, &[("_PyRuntimeState", "interpreters.main")])?;
+        set(&mut ret.py_runtime_state.tstate_current, &[("_PyRuntimeState", "gilstate.tstate_current")])?;
+        set(&mut ret.py_interpreter_state.gil, &[("PyInterpreterState", "ceval.gil")])?;
+        set(&mut ret.py_gil_runtime_state.locked, &[("_gil_runtime_state", "locked")])?;
+        set(&mut ret.py_gil_runtime_state.last_holder, &[("_gil_runtime_state", "last_holder")])?;
+        if base.frames == FrameLayout::FrameObject {
+            set(&mut ret.py_thread_state.frame, &[("PyThreadState", "frame")])?;
+            set(&mut ret.py_frame_object.f_back, &[("PyFrameObject", "f_back")])?;
+            set(&mut ret.py_frame_object.f_code, &[("PyFrameObject", "f_code")])?;
+            set(&mut ret.py_frame_object.f_lineno, &[("PyFrameObject", "f_lineno")])?;
+            set(&mut ret.py_frame_object.f_localsplus, &[("PyFrameObject", "f_localsplus")])?;
+            set(&mut ret.py_frame_object.f_lasti, &[("PyFrameObject", "f_lasti")])?;
+        } else {
+            set(&mut ret.py_thread_state.cframe, &[("PyThreadState", "cframe")])?;
+            set(&mut ret.py_cframe.current_frame, &[("_PyCFrame", "current_frame")])?;
+            set(&mut ret.py_interpreter_frame.previous, &[("_PyInterpreterFrame", "previous")])?;
+            set(&mut ret.py_interpreter_frame.f_code, &[("_PyInterpreterFrame", "f_code")])?;
+            set(&mut ret.py_interpreter_frame.owner, &[("_PyInterpreterFrame", "owner")])?;
+            set(&mut ret.py_interpreter_frame.is_entry, &[("_PyInterpreterFrame", "is_entry")])?;
+            set(&mut ret.py_interpreter_frame.localsplus, &[("_PyInterpreterFrame", "localsplus")])?;
+            set(&mut ret.py_interpreter_frame.prev_instr, &[("_PyInterpreterFrame", "prev_instr")])?;
+            set(&mut ret.py_code_object.co_qualname, &[("PyCodeObject", "co_qualname")])?;
+            set(&mut ret.py_code_object.co_code_adaptive, &[("PyCodeObject", "co_code_adaptive")])?;
+        }
+        set(&mut ret.py_code_object.co_filename, &[("PyCodeObject", "co_filename")])?;
+        set(&mut ret.py_code_object.co_name, &[("PyCodeObject", "co_name")])?;
+        set(&mut ret.py_code_object.co_varnames, &[("PyCodeObject", "co_varnames"), ("PyCodeObject", "co_localsplusnames")])?;
+        set(&mut ret.py_code_object.co_firstlineno, &[("PyCodeObject", "co_firstlineno")])?;
+        set(&mut ret.py_code_object.co_linetable, &[("PyCodeObject", "co_linetable"), ("PyCodeObject", "co_lnotab")])?;
+        set(&mut ret.py_tuple_object.ob_item, &[("PyTupleObject", "ob_item")])?;
+        set(&mut ret.py_bytes_object.ob_size, &[("PyBytesObject", "ob_base.ob_size"), ("PyStringObject", "ob_size")])?;
+        set(&mut ret.py_bytes_object.ob_sval, &[("PyBytesObject", "ob_sval"), ("PyStringObject", "ob_sval")])?;
+
+        // the characters follow the header of compact ascii strings, 2.7 has no such type
+        if let Some(size) = self.size("PyASCIIObject")? {
+            ret.string.data = size;
+            found += 1;
+        }
+
+        ensure!(found > 0, "no python types in the debug info");
+        Ok(ret)
+    }
+
+    fn size(&self, ty: &str) -> Result<Option<usize>> {
+        let Some(&(idx, def)) = self.defs.get(ty) else { return Ok(None) };
+        let entry = self.units[idx].entry(def)?;
+        Ok(entry.attr_value(gimli::DW_AT_byte_size)?.and_then(|v| v.udata_value()).map(|v| v as usize))
+    }
+
+    /// Offset of a possibly nested field, e.g. "threads.head"
+    fn offset(&self, ty: &str, path: &str) -> Result<Option<usize>> {
+        let Some(&(idx, mut def)) = self.defs.get(ty) else { return Ok(None) };
+        let unit = &self.units[idx];
+        let mut total = 0;
+        let mut fields = path.split('.').peekable();
+        while let Some(field) = fields.next() {
+            let Some((offset, member_ty)) = self.member(unit, def, field)? else { return Ok(None) };
+            total += offset;
+            if fields.peek().is_some() {
+                match member_ty {
+                    Some(member_ty) => match definition(unit, member_ty)? {
+                        Some(member_def) => def = member_def,
+                        None => return Ok(None),
+                    },
+                    None => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(total))
+    }
+
+    /// Offset and type of a field, fields of anonymous structs and unions belong to the parent
+    fn member(&self, unit: &Unit<R>, parent: UnitOffset, name: &str) -> Result<Option<(usize, Option<UnitOffset>)>> {
+        let mut tree = unit.entries_tree(Some(parent))?;
+        let mut children = tree.root()?.children();
+        while let Some(child) = children.next()? {
+            let entry = child.entry();
+            if entry.tag() != gimli::DW_TAG_member {
+                continue;
+            }
+            let Some(offset) = member_location(unit.encoding(), entry.attr_value(gimli::DW_AT_data_member_location)?)? else { continue };
+            let ty = match entry.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(ty)) => Some(ty),
+                _ => None,
+            };
+
+            match entry.attr_value(gimli::DW_AT_name)? {
+                Some(member_name) => {
+                    if self.dwarf.attr_string(unit, member_name)?.to_string_lossy()? == name {
+                        return Ok(Some((offset, ty)));
+                    }
+                }
+                None => {
+                    let Some(ty) = ty else { continue };
+                    let Some(anon) = definition(unit, ty)? else { continue };
+                    if let Some((inner, inner_ty)) = self.member(unit, anon, name)? {
+                        return Ok(Some((offset + inner, inner_ty)));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Offset of a member: a constant, or the `DW_OP_plus_uconst` expression older compilers emit.
+/// Members of unions have none. `None` for any other expression, their offset isn't fixed
+fn member_location<R: Reader>(encoding: Encoding, location: Option<AttributeValue<R>>) -> Result<Option<usize>> {
+    let Some(location) = location else { return Ok(Some(0)) };
+    if let Some(offset) = location.udata_value() {
+        return Ok(Some(offset as usize));
+    }
+    let AttributeValue::Exprloc(expr) = location else { return Ok(None) };
+    let mut ops = expr.operations(encoding);
+    match (ops.next()?, ops.next()?) {
+        (Some(Operation::PlusConstant { value }), None) => Ok(Some(value as usize)),
+        _ => Ok(None),
+    }
+}
+
+/// The struct or union a type resolves to through typedefs and qualifiers, `None` for declarations
+fn definition<R: Reader>(unit: &Unit<R>, mut offset: UnitOffset) -> Result<Option<UnitOffset>> {
+    for _ in 0..8 {
+        let entry = unit.entry(offset)?;
+        let tag = entry.tag();
+        if tag == gimli::DW_TAG_structure_type || tag == gimli::DW_TAG_union_type {
+            let declaration = matches!(entry.attr_value(gimli::DW_AT_declaration)?, Some(AttributeValue::Flag(true)));
+            return Ok((!declaration).then_some(offset));
+        }
+        if tag != gimli::DW_TAG_typedef && tag != gimli::DW_TAG_const_type && tag != gimli::DW_TAG_volatile_type {
+            return Ok(None);
+        }
+        match entry.attr_value(gimli::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(next)) => offset = next,
+            _ => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use gimli::{Expression, Format, LittleEndian};
+
+    use super::*;
+    use crate::python::offsets::{test::check, PY311_OFFSETS, PY312_OFFSETS};
+
+    /// Extract from a base that lacks some fields, the result has to match the real build
+    fn extract(fixture: &str, base: &PythonOffsets) -> PythonOffsets {
+        let mut bare = *base;
+        bare.py_object.ob_type = 0;
+        bare.string.data = 0;
+        bare.py_interpreter_state.tstate_head = 0;
+        bare.py_interpreter_frame.previous = 0;
+        bare.py_code_object.co_qualname = 0;
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures").join(fixture);
+        python_offsets(&path, &bare).unwrap()
+    }
+
+    #[test]
+    fn test_py311() {
+        check(include_str!("../../../tests/fixtures/python/3.11.txt"), &extract("python/3.11-types.debug", &PY311_OFFSETS));
+    }
+
+    #[test]
+    fn test_py312() {
+        check(include_str!("../../../tests/fixtures/python/3.12.txt"), &extract("python/3.12-types.debug", &PY312_OFFSETS));
+    }
+
+    #[test]
+    fn test_no_debug_info() {
+        for fixture in ["x86_64/malloc", "aarch64/malloc"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures").join(fixture);
+            assert!(python_offsets(&path, &PY311_OFFSETS).is_err(), "{fixture}");
+        }
+    }
+
+    #[test]
+    fn test_member_location() {
+        let encoding = Encoding { format: Format::Dwarf32, version: 4, address_size: 8 };
+        let expr = |ops: &'static [u8]| Some(AttributeValue::Exprloc(Expression(EndianSlice::new(ops, LittleEndian))));
+
+        assert_eq!(member_location(encoding, Some(AttributeValue::Udata(24))).unwrap(), Some(24));
+        assert_eq!(member_location::<EndianSlice<LittleEndian>>(encoding, None).unwrap(), Some(0));
+        // DW_OP_plus_uconst 200
+        assert_eq!(member_location(encoding, expr(&[0x23, 0xc8, 0x01])).unwrap(), Some(200));
+        // DW_OP_dup, DW_OP_deref, DW_OP_plus: a virtual base
+        assert_eq!(member_location(encoding, expr(&[0x12, 0x06, 0x22])).unwrap(), None);
+    }
+}
@@ -2,6 +2,8 @@ use self::offsets::*;
 
 pub mod offsets;
 pub mod state;
+#[cfg(feature = "user")]
+pub mod dwarf;
 
 #[repr(C)]
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -12,8 +14,9 @@ pub struct PythonVersion {
 }
 
 impl PythonVersion {
-    pub fn offsets(&self) -> PythonOffsets {
-        match &self {
+    /// Offsets of the version, `None` for versions whose frames can't be walked
+    pub fn offsets(&self) -> Option<PythonOffsets> {
+        Some(match &self {
             PythonVersion {
                 major: 2,
                 minor: _,
@@ -38,14 +41,24 @@ impl PythonVersion {
                 major: 3,
                 minor: 9,
                 patch: _,
-            } => PY38_OFFSETS, // 39 is same as 38
+            } => PY39_OFFSETS,
             PythonVersion {
                 major: 3,
                 minor: 10,
                 patch: _,
             } => PY310_OFFSETS,
-            _ => PY310_OFFSETS,
-        }
+            PythonVersion {
+                major: 3,
+                minor: 11,
+                patch: _,
+            } => PY311_OFFSETS,
+            PythonVersion {
+                major: 3,
+                minor: 12,
+                patch: _,
+            } => PY312_OFFSETS,
+            _ => return None,
+        })
     }
 }
 
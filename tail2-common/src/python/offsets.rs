@@ -1,44 +1,120 @@
 structstruck::strike! {
     #[repr(C)]
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct PythonOffsets {
-        pub py_object: #[derive(Clone, Copy, Debug)] struct {
+        pub py_object: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub ob_type: usize
         },
-        pub string: #[derive(Clone, Copy, Debug)] struct {
+        pub string: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub data: usize,
             pub size: i64,
         },
-        pub py_type_object: #[derive(Clone, Copy, Debug)] struct {
+        pub py_type_object: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub tp_name: usize
         },
-        pub py_thread_state: #[derive(Clone, Copy, Debug)] struct {
+        pub py_thread_state: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub next: usize,
             pub interp: usize,
             pub frame: usize,
             pub thread: usize,
+            /// `_PyCFrame *cframe`, 3.11+
+            pub cframe: usize,
         },
-        pub py_interpreter_state: #[derive(Clone, Copy, Debug)] struct {
+        pub py_interpreter_state: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub tstate_head: usize,
+            /// `struct _gil_runtime_state *ceval.gil`, 3.12+
+            pub gil: usize,
         },
-        pub py_runtime_state: #[derive(Clone, Copy, Debug)] struct {
+        pub py_runtime_state: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub interp_main: usize,
+            /// `gilstate.tstate_current`, the thread holding the GIL, 3.7 to 3.11
+            pub tstate_current: usize,
         },
-        pub py_frame_object: #[derive(Clone, Copy, Debug)] struct {
+        /// 3.12+
+        pub py_gil_runtime_state: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
+            pub locked: usize,
+            pub last_holder: usize,
+        },
+        pub py_frame_object: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub f_back: usize,
             pub f_code: usize,
             pub f_lineno: usize,
             pub f_localsplus: usize,
+            pub f_lasti: usize,
+        },
+        /// 3.11+
+        pub py_cframe: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
+            pub current_frame: usize,
+        },
+        /// 3.11+, replaces `PyFrameObject` in the chain of frames
+        pub py_interpreter_frame: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
+            pub previous: usize,
+            pub f_code: usize,
+            pub owner: usize,
+            /// 3.11, set on the first frame each call of the eval loop runs
+            pub is_entry: usize,
+            pub localsplus: usize,
+            pub prev_instr: usize,
         },
-        pub py_code_object: #[derive(Clone, Copy, Debug)] struct {
+        pub py_code_object: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub co_filename: usize,
             pub co_name: usize,
             pub co_varnames: usize,
             pub co_firstlineno: usize,
+            /// `co_lnotab` up to 3.9
+            pub co_linetable: usize,
+            /// 3.11+
+            pub co_qualname: usize,
+            /// 3.11+, where the bytecode starts
+            pub co_code_adaptive: usize,
         },
-        pub py_tuple_object: #[derive(Clone, Copy, Debug)] struct {
+        /// `PyStringObject` in 2.7
+        pub py_bytes_object: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
+            pub ob_size: usize,
+            pub ob_sval: usize,
+        },
+        pub py_tuple_object: #[derive(Clone, Copy, Debug, PartialEq, Eq)] struct {
             pub ob_item: usize,
         },
+        pub frames: FrameLayout,
+    }
+}
+
+/// How the frames of a thread are chained
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameLayout {
+    /// `PyThreadState.frame`, then `PyFrameObject.f_back`, up to 3.10
+    FrameObject = 0,
+    /// `PyThreadState.cframe->current_frame`, then `_PyInterpreterFrame.previous`, 3.11
+    InterpreterFrame = 1,
+    /// as 3.11, with shim frames owned by the C stack in between, 3.12
+    InterpreterFrameShims = 2,
+}
+
+impl PythonOffsets {
+    /// Offset of the code object in a frame
+    pub fn frame_code(&self) -> usize {
+        match self.frames {
+            FrameLayout::FrameObject => self.py_frame_object.f_code,
+            _ => self.py_interpreter_frame.f_code,
+        }
+    }
+
+    /// Offset of the pointer to the calling frame
+    pub fn frame_back(&self) -> usize {
+        match self.frames {
+            FrameLayout::FrameObject => self.py_frame_object.f_back,
+            _ => self.py_interpreter_frame.previous,
+        }
+    }
+
+    /// Offset of the first local, `self` or `cls` for methods
+    pub fn frame_localsplus(&self) -> usize {
+        match self.frames {
+            FrameLayout::FrameObject => self.py_frame_object.f_localsplus,
+            _ => self.py_interpreter_frame.localsplus,
+        }
     }
 }
 
@@ -53,6 +129,9 @@ There are a couple of exceptions:
    and `size` is the offset to the 32-bit integer representing the length in bytes (not characters)
 2 PyRuntimeStateinterp_main - corresponds to offsetof(_PyRuntimeState, interpretersmain)
 3 PyThreadStatethread - this field's name is "thread_id" in some Python versions
+4 PyCodeObjectco_varnames - corresponds to co_localsplusnames from 3.11 on
+5 PyCodeObjectco_linetable - corresponds to co_lnotab up to 3.9
+Fields that don't exist in a version are 0, `frames` tells which set applies
 */
 
 pub const PY27_OFFSETS: PythonOffsets = PythonOffsets {
@@ -67,24 +146,45 @@ pub const PY27_OFFSETS: PythonOffsets = PythonOffsets {
         interp: 8,
         frame: 16,
         thread: 144,
+        cframe: 0, // N/A
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 8,
+        gil: 0, // N/A
     },
-    py_interpreter_state: PyInterpreterState { tstate_head: 8 },
     py_runtime_state: PyRuntimeState {
         interp_main: 0, // N/A
+        tstate_current: 0, // N/A
     },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 0, last_holder: 0 }, // N/A
     py_frame_object: PyFrameObject {
         f_back: 24,
         f_code: 32,
         f_lineno: 124,
         f_localsplus: 376,
+        f_lasti: 120,
+    },
+    py_cframe: PyCframe { current_frame: 0 }, // N/A
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 0, // N/A
+        f_code: 0,
+        owner: 0,
+        is_entry: 0,
+        localsplus: 0,
+        prev_instr: 0,
     },
     py_code_object: PyCodeObject {
         co_filename: 80,
         co_name: 88,
         co_varnames: 56,
         co_firstlineno: 96,
+        co_linetable: 104, // co_lnotab
+        co_qualname: 0, // N/A
+        co_code_adaptive: 0,
     },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 36 },
     py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::FrameObject,
 };
 
 pub const PY36_OFFSETS: PythonOffsets = PythonOffsets {
@@ -99,24 +199,45 @@ pub const PY36_OFFSETS: PythonOffsets = PythonOffsets {
         interp: 16,
         frame: 24,
         thread: 152,
+        cframe: 0, // N/A
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 8,
+        gil: 0, // N/A
     },
-    py_interpreter_state: PyInterpreterState { tstate_head: 8 },
     py_runtime_state: PyRuntimeState {
         interp_main: 0, // N/A
+        tstate_current: 0, // N/A
     },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 0, last_holder: 0 }, // N/A
     py_frame_object: PyFrameObject {
         f_back: 24,
         f_code: 32,
         f_lineno: 124,
         f_localsplus: 376,
+        f_lasti: 120,
+    },
+    py_cframe: PyCframe { current_frame: 0 }, // N/A
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 0, // N/A
+        f_code: 0,
+        owner: 0,
+        is_entry: 0,
+        localsplus: 0,
+        prev_instr: 0,
     },
     py_code_object: PyCodeObject {
         co_filename: 96,
         co_name: 104,
         co_varnames: 64,
         co_firstlineno: 36,
+        co_linetable: 112, // co_lnotab
+        co_qualname: 0, // N/A
+        co_code_adaptive: 0,
     },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 32 },
     py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::FrameObject,
 };
 
 pub const PY37_OFFSETS: PythonOffsets = PythonOffsets {
@@ -131,22 +252,45 @@ pub const PY37_OFFSETS: PythonOffsets = PythonOffsets {
         interp: 16,
         frame: 24,
         thread: 176,
+        cframe: 0, // N/A
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 8,
+        gil: 0, // N/A
     },
-    py_interpreter_state: PyInterpreterState { tstate_head: 8 },
-    py_runtime_state: PyRuntimeState { interp_main: 40 },
+    py_runtime_state: PyRuntimeState {
+        interp_main: 40,
+        tstate_current: 1480,
+    },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 0, last_holder: 0 }, // N/A
     py_frame_object: PyFrameObject {
         f_back: 24,
         f_code: 32,
         f_lineno: 108,
         f_localsplus: 360,
+        f_lasti: 104,
+    },
+    py_cframe: PyCframe { current_frame: 0 }, // N/A
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 0, // N/A
+        f_code: 0,
+        owner: 0,
+        is_entry: 0,
+        localsplus: 0,
+        prev_instr: 0,
     },
     py_code_object: PyCodeObject {
         co_filename: 96,
         co_name: 104,
         co_varnames: 64,
         co_firstlineno: 36,
+        co_linetable: 112, // co_lnotab
+        co_qualname: 0, // N/A
+        co_code_adaptive: 0,
     },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 32 },
     py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::FrameObject,
 };
 
 pub const PY38_OFFSETS: PythonOffsets = PythonOffsets {
@@ -161,22 +305,53 @@ pub const PY38_OFFSETS: PythonOffsets = PythonOffsets {
         interp: 16,
         frame: 24,
         thread: 176,
+        cframe: 0, // N/A
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 8,
+        gil: 0, // N/A
+    },
+    py_runtime_state: PyRuntimeState {
+        interp_main: 40,
+        tstate_current: 1368,
     },
-    py_interpreter_state: PyInterpreterState { tstate_head: 8 },
-    py_runtime_state: PyRuntimeState { interp_main: 40 },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 0, last_holder: 0 }, // N/A
     py_frame_object: PyFrameObject {
         f_back: 24,
         f_code: 32,
         f_lineno: 108,
         f_localsplus: 360,
+        f_lasti: 104,
+    },
+    py_cframe: PyCframe { current_frame: 0 }, // N/A
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 0, // N/A
+        f_code: 0,
+        owner: 0,
+        is_entry: 0,
+        localsplus: 0,
+        prev_instr: 0,
     },
     py_code_object: PyCodeObject {
         co_filename: 104,
         co_name: 112,
         co_varnames: 72,
         co_firstlineno: 40,
+        co_linetable: 120, // co_lnotab
+        co_qualname: 0, // N/A
+        co_code_adaptive: 0,
     },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 32 },
     py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::FrameObject,
+};
+
+pub const PY39_OFFSETS: PythonOffsets = PythonOffsets {
+    py_runtime_state: PyRuntimeState {
+        interp_main: 40,
+        tstate_current: 568,
+    },
+    ..PY38_OFFSETS
 };
 
 pub const PY310_OFFSETS: PythonOffsets = PythonOffsets {
@@ -191,22 +366,300 @@ pub const PY310_OFFSETS: PythonOffsets = PythonOffsets {
         interp: 16,
         frame: 24,
         thread: 176,
+        cframe: 0, // N/A
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 8,
+        gil: 0, // N/A
     },
-    py_interpreter_state: PyInterpreterState { tstate_head: 8 },
     py_runtime_state: PyRuntimeState {
         interp_main: 40, // N/A
+        tstate_current: 568,
     },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 0, last_holder: 0 }, // N/A
     py_frame_object: PyFrameObject {
         f_back: 24,
         f_code: 32,
         f_lineno: 100,
         f_localsplus: 352,
+        f_lasti: 96,
+    },
+    py_cframe: PyCframe { current_frame: 0 }, // N/A
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 0, // N/A
+        f_code: 0,
+        owner: 0,
+        is_entry: 0,
+        localsplus: 0,
+        prev_instr: 0,
     },
     py_code_object: PyCodeObject {
         co_filename: 104,
         co_name: 112,
         co_varnames: 72,
         co_firstlineno: 40,
+        co_linetable: 120,
+        co_qualname: 0, // N/A
+        co_code_adaptive: 0,
+    },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 32 },
+    py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::FrameObject,
+};
+
+pub const PY311_OFFSETS: PythonOffsets = PythonOffsets {
+    py_object: PyObject { ob_type: 8 },
+    string: String {
+        data: 48, // sizeof(PyASCIIObject)
+        size: 16, // offsetof(PyVarObject, ob_size)
+    },
+    py_type_object: PyTypeObject { tp_name: 24 },
+    py_thread_state: PyThreadState {
+        next: 8,
+        interp: 16,
+        frame: 0, // N/A
+        thread: 152,
+        cframe: 56,
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 16,
+        gil: 0, // N/A
+    },
+    py_runtime_state: PyRuntimeState {
+        interp_main: 48,
+        tstate_current: 576,
     },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 0, last_holder: 0 }, // N/A
+    py_frame_object: PyFrameObject {
+        f_back: 0, // N/A
+        f_code: 0,
+        f_lineno: 0,
+        f_localsplus: 0,
+        f_lasti: 0,
+    },
+    py_cframe: PyCframe { current_frame: 8 },
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 48,
+        f_code: 32,
+        owner: 69,
+        is_entry: 68,
+        localsplus: 72,
+        prev_instr: 56,
+    },
+    py_code_object: PyCodeObject {
+        co_filename: 112,
+        co_name: 120,
+        co_varnames: 96,
+        co_firstlineno: 72,
+        co_linetable: 136,
+        co_qualname: 128,
+        co_code_adaptive: 184,
+    },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 32 },
     py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::InterpreterFrame,
 };
+
+pub const PY312_OFFSETS: PythonOffsets = PythonOffsets {
+    py_object: PyObject { ob_type: 8 },
+    string: String {
+        data: 40, // sizeof(PyASCIIObject), wstr is gone
+        size: 16, // offsetof(PyVarObject, ob_size)
+    },
+    py_type_object: PyTypeObject { tp_name: 24 },
+    py_thread_state: PyThreadState {
+        next: 8,
+        interp: 16,
+        frame: 0, // N/A
+        thread: 136,
+        cframe: 56,
+    },
+    py_interpreter_state: PyInterpreterState {
+        tstate_head: 72,
+        gil: 384,
+    },
+    py_runtime_state: PyRuntimeState {
+        interp_main: 48,
+        tstate_current: 0, // N/A
+    },
+    py_gil_runtime_state: PyGilRuntimeState { locked: 16, last_holder: 8 },
+    py_frame_object: PyFrameObject {
+        f_back: 0, // N/A
+        f_code: 0,
+        f_lineno: 0,
+        f_localsplus: 0,
+        f_lasti: 0,
+    },
+    py_cframe: PyCframe { current_frame: 0 },
+    py_interpreter_frame: PyInterpreterFrame {
+        previous: 8,
+        f_code: 0,
+        owner: 70,
+        is_entry: 0, // N/A, entries are under a shim frame
+        localsplus: 72,
+        prev_instr: 56,
+    },
+    py_code_object: PyCodeObject {
+        co_filename: 112,
+        co_name: 120,
+        co_varnames: 96,
+        co_firstlineno: 68,
+        co_linetable: 136,
+        co_qualname: 128,
+        co_code_adaptive: 192,
+    },
+    py_bytes_object: PyBytesObject { ob_size: 16, ob_sval: 32 },
+    py_tuple_object: PyTupleObject { ob_item: 24 },
+    frames: FrameLayout::InterpreterFrameShims,
+};
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::python::PythonVersion;
+
+    /// `offsetof` output of tests/fixtures/python/offsets.c for a real build
+    pub(crate) fn check(fixture: &str, offsets: &PythonOffsets) {
+        for line in fixture.lines() {
+            let (name, value) = line.split_once(": ").unwrap();
+            let value: usize = value.parse().unwrap();
+            let ours = match name {
+                "ob_type" => offsets.py_object.ob_type,
+                "str.data" => offsets.string.data,
+                "ob_size" => offsets.string.size as usize,
+                "tp_name" => offsets.py_type_object.tp_name,
+                "ts.next" => offsets.py_thread_state.next,
+                "ts.interp" => offsets.py_thread_state.interp,
+                "ts.cframe" => offsets.py_thread_state.cframe,
+                "ts.thread_id" => offsets.py_thread_state.thread,
+                "interp.threads.head" => offsets.py_interpreter_state.tstate_head,
+                "runtime.interpreters.main" => offsets.py_runtime_state.interp_main,
+                "cframe.current_frame" => offsets.py_cframe.current_frame,
+                "frame.previous" => offsets.py_interpreter_frame.previous,
+                "frame.f_code" => offsets.py_interpreter_frame.f_code,
+                "frame.owner" => offsets.py_interpreter_frame.owner,
+                "frame.is_entry" => offsets.py_interpreter_frame.is_entry,
+                "frame.localsplus" => offsets.py_interpreter_frame.localsplus,
+                "co_filename" => offsets.py_code_object.co_filename,
+                "co_name" => offsets.py_code_object.co_name,
+                "co_localsplusnames" => offsets.py_code_object.co_varnames,
+                "co_firstlineno" => offsets.py_code_object.co_firstlineno,
+                "tuple.ob_item" => offsets.py_tuple_object.ob_item,
+                "runtime.gilstate.tstate_current" => offsets.py_runtime_state.tstate_current,
+                "interp.ceval.gil" => offsets.py_interpreter_state.gil,
+                "gil.locked" => offsets.py_gil_runtime_state.locked,
+                "gil.last_holder" => offsets.py_gil_runtime_state.last_holder,
+                "frame.prev_instr" => offsets.py_interpreter_frame.prev_instr,
+                "co_linetable" => offsets.py_code_object.co_linetable,
+                "co_qualname" => offsets.py_code_object.co_qualname,
+                "co_code_adaptive" => offsets.py_code_object.co_code_adaptive,
+                "bytes.ob_sval" => offsets.py_bytes_object.ob_sval,
+                _ => continue,
+            };
+            assert_eq!(ours, value, "{name}");
+        }
+    }
+
+    /// the fixtures only have the GIL holder before 3.11
+    #[test]
+    fn test_tstate_current() {
+        check(include_str!("../../../tests/fixtures/python/3.7.txt"), &PY37_OFFSETS);
+        check(include_str!("../../../tests/fixtures/python/3.8.txt"), &PY38_OFFSETS);
+        check(include_str!("../../../tests/fixtures/python/3.9.txt"), &PY39_OFFSETS);
+        check(include_str!("../../../tests/fixtures/python/3.10.txt"), &PY310_OFFSETS);
+    }
+
+    #[test]
+    fn test_py311() {
+        check(include_str!("../../../tests/fixtures/python/3.11.txt"), &PY311_OFFSETS);
+    }
+
+    #[test]
+    fn test_py312() {
+        check(include_str!("../../../tests/fixtures/python/3.12.txt"), &PY312_OFFSETS);
+    }
+
+    /// Memory of a python process, dumped by tests/fixtures/python/frames.py
+    struct Memory {
+        runtime: usize,
+        reads: Vec<(usize, Vec<u8>)>,
+    }
+
+    impl Memory {
+        fn parse(fixture: &str) -> Self {
+            let mut lines = fixture.lines();
+            let runtime = lines.next().unwrap().strip_prefix("runtime: ").unwrap();
+            let reads = lines
+                .map(|line| {
+                    let (addr, hex) = line.split_once(": ").unwrap();
+                    let bytes = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect();
+                    (usize::from_str_radix(addr, 16).unwrap(), bytes)
+                })
+                .collect();
+            Self { runtime: usize::from_str_radix(runtime, 16).unwrap(), reads }
+        }
+
+        fn read(&self, addr: usize, len: usize) -> &[u8] {
+            let (start, bytes) = self
+                .reads
+                .iter()
+                .find(|(start, bytes)| (*start..start + bytes.len()).contains(&addr))
+                .unwrap_or_else(|| panic!("{addr:x} wasn't dumped"));
+            &bytes[addr - start..addr - start + len]
+        }
+
+        fn ptr(&self, addr: usize) -> usize {
+            usize::from_le_bytes(self.read(addr, 8).try_into().unwrap())
+        }
+    }
+
+    /// Names of the frames of the dumping thread, the way tail2-ebpf walks them,
+    /// split per call of the eval loop
+    fn walk(mem: &Memory, offsets: &PythonOffsets) -> Vec<Vec<String>> {
+        let interp = mem.ptr(mem.runtime + offsets.py_runtime_state.interp_main);
+        let tstate = mem.ptr(interp + offsets.py_interpreter_state.tstate_head);
+        let cframe = mem.ptr(tstate + offsets.py_thread_state.cframe);
+        let mut frame = mem.ptr(cframe + offsets.py_cframe.current_frame);
+
+        let mut calls = vec![vec![]];
+        while frame != 0 {
+            let shim = offsets.frames == FrameLayout::InterpreterFrameShims
+                && mem.read(frame + offsets.py_interpreter_frame.owner, 1)[0] == 3;
+            if !shim {
+                let code = mem.ptr(frame + offsets.frame_code());
+                let name = mem.read(mem.ptr(code + offsets.py_code_object.co_qualname) + offsets.string.data, 64);
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                calls.last_mut().unwrap().push(String::from_utf8(name[..len].to_vec()).unwrap());
+            }
+            let entry = match offsets.frames {
+                FrameLayout::InterpreterFrameShims => shim,
+                _ => mem.read(frame + offsets.py_interpreter_frame.is_entry, 1)[0] != 0,
+            };
+            if entry {
+                calls.push(vec![]);
+            }
+            frame = mem.ptr(frame + offsets.frame_back());
+        }
+        calls.retain(|c| !c.is_empty());
+        calls
+    }
+
+    #[test]
+    fn test_walk_frames() {
+        // `leaf` is called back from C, in a call of the eval loop of its own
+        let expected = [vec!["dump", "leaf"], vec!["middle", "Root.run", "<module>"]];
+        let mem = Memory::parse(include_str!("../../../tests/fixtures/python/3.11-frames.txt"));
+        assert_eq!(walk(&mem, &PY311_OFFSETS), expected);
+        // 3.12 has a shim frame below each call instead of `is_entry`
+        let mem = Memory::parse(include_str!("../../../tests/fixtures/python/3.12-frames.txt"));
+        assert_eq!(walk(&mem, &PY312_OFFSETS), expected);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let version = |major, minor| PythonVersion { major, minor, patch: 0 };
+        assert!(version(3, 12).offsets().is_some());
+        assert!(version(3, 13).offsets().is_none());
+        assert!(version(0, 0).offsets().is_none());
+    }
+}
@@ -4,12 +4,16 @@ use crate::metrics::Metrics;
 
 use super::offsets::PythonOffsets;
 
-pub const PYTHON_STACK_FRAMES_PER_PROG: usize = 80;
-pub const FRAME_MAX_LEN: usize = PYTHON_STACK_FRAMES_PER_PROG;
+/// Frames are 4 byte symbol ids, so deep stacks are cheap to send
+pub const FRAME_MAX_LEN: usize = 256;
 pub const CLASS_NAME_LEN: usize = 32;
 pub const FUNCTION_NAME_LEN: usize = 64;
 pub const FILE_NAME_LEN: usize = 128;
 pub const TASK_COMM_LEN: usize = 16;
+/// Id of a frame whose symbol couldn't be stored in `PY_SYMBOLS`, valid ids are positive
+pub const UNKNOWN_SYMBOL_ID: i32 = -1;
+/// Entries of `PY_SYMBOLS`, the agent caches as many resolved symbols
+pub const PY_SYMBOLS_ENTRIES: u32 = 32768;
 
 #[derive(Copy, Clone, Debug)]
 pub enum StackStatus {
@@ -55,13 +59,13 @@ pub struct pid_data {
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct PythonSymbol {
+    /// `co_firstlineno`
     pub lineno: u32,
     pub classname: [u8; CLASS_NAME_LEN],
     pub name: [u8; FUNCTION_NAME_LEN],
     pub file: [u8; FILE_NAME_LEN],
-    // NOTE: PyFrameObject also has line number but it is typically just the
-    // first line of that function and PyCode_Addr2Line needs to be called
-    // to get the actual line
+    // NOTE: the executing line and the code object aren't part of the symbol, so processes
+    // running the same code share it, see `PythonStack::lasti` and `PythonStack::code`
 }
 
 impl Default for PythonSymbol {
@@ -87,16 +91,34 @@ pub struct PythonStack {
     pub comm: [u8; TASK_COMM_LEN],
     pub error_code: Metrics,
     pub stack_status: StackStatus,
+    /// `PyThreadState` of the sampled thread, 0 if it has none or the GIL holder couldn't be read
+    pub thread_state: usize,
+    /// `PyThreadState` holding the GIL when the sample was taken, 0 if it was released
+    pub gil_holder: usize,
     /// instead of storing symbol name here directly, we add it to another
     /// hashmap with Symbols and only store the ids here
     pub frames_len: usize,
-    pub frames: [PythonSymbol; FRAME_MAX_LEN],
+    /// ids into `PY_SYMBOLS`, leaf first
+    pub frames: [i32; FRAME_MAX_LEN],
+    /// last instruction of every frame: `f_lasti` up to 3.10, bytes into `co_code_adaptive`
+    /// from 3.11, negative if unknown
+    pub lasti: [i32; FRAME_MAX_LEN],
+    /// address of the code object of every frame, user space reads its line table from there
+    pub code: [usize; FRAME_MAX_LEN],
+    /// whether a frame is the first one a call of the eval loop ran, the frames up to the next
+    /// entry ran in the same call. Every frame is one up to 3.10.
+    pub eval_entry: [bool; FRAME_MAX_LEN],
 }
 
 impl PythonStack {
     pub fn uninit() -> Self {
         unsafe { core::mem::zeroed() }
     }
+
+    /// Whether the sampled thread held the GIL, `None` if that's unknown
+    pub fn holds_gil(&self) -> Option<bool> {
+        (self.thread_state != 0).then_some(self.thread_state == self.gil_holder)
+    }
 }
 
 impl Debug for PythonStack {
@@ -105,8 +127,13 @@ impl Debug for PythonStack {
             .field("comm", &self.comm)
             .field("error_code", &self.error_code)
             .field("stack_status", &self.stack_status)
+            .field("thread_state", &self.thread_state)
+            .field("gil_holder", &self.gil_holder)
             .field("stack_len", &self.frames_len)
-            .field("stack", &self.frames)
+            .field("stack", &&self.frames[..self.frames_len.min(FRAME_MAX_LEN)])
+            .field("lasti", &&self.lasti[..self.frames_len.min(FRAME_MAX_LEN)])
+            .field("code", &&self.code[..self.frames_len.min(FRAME_MAX_LEN)])
+            .field("eval_entry", &&self.eval_entry[..self.frames_len.min(FRAME_MAX_LEN)])
             .finish()
     }
 }
@@ -1,4 +1,5 @@
 use crate::python::{
+    offsets::PythonOffsets,
     state::{pid_data, pthreads_impl},
     PythonVersion,
 };
@@ -10,6 +11,8 @@ pub enum RuntimeType {
         pid_data: pid_data,
         is_lib: bool,
         version: PythonVersion,
+        /// from the debug info of this process's python if it has any, else the table for `version`
+        offsets: PythonOffsets,
     },
 }
 
@@ -35,6 +38,13 @@ impl RuntimeType {
         }
     }
 
+    pub fn python_offsets(&self) -> Option<&PythonOffsets> {
+        match self {
+            RuntimeType::Unknown => None,
+            RuntimeType::Python { offsets, .. } => Some(offsets),
+        }
+    }
+
     pub fn python_pid_data(&self) -> pid_data {
         match self {
             RuntimeType::Unknown => unimplemented!(),
@@ -62,26 +72,16 @@ pub mod user {
 
     use super::*;
     use crate::procinfo::user::ProcMapRow;
-    use crate::python::state::py_globals;
+    use crate::python::{dwarf, state::py_globals};
     use core::str::from_utf8_unchecked;
-    use std::{io::Read, path::Path};
+    use std::{io::Read, os::unix::fs::MetadataExt, path::Path, sync::Mutex};
 
     impl RuntimeType {
-        pub fn python(row: &ProcMapRow, base_name: &str, paths: &[ProcMapRow]) -> Self {
+        /// `None` if the version of the python isn't supported
+        pub fn python(row: &ProcMapRow, base_name: &str, paths: &[ProcMapRow]) -> Option<Self> {
             let is_lib = base_name.starts_with("libpython");
-            let mut version = PythonVersion {
-                major: 0,
-                minor: 0,
-                patch: 0,
-            };
-            if let Some(v_str) = base_name.split("python").last() {
-                let mut segs = v_str.split('.');
-                version.major = segs.next().unwrap().parse().unwrap();
-                version.minor = segs.next().unwrap().parse().unwrap();
-                // if let Ok(v) = to_python_version(&row.mod_name, v_str) {
-                //     version = v;
-                // }
-            }
+            let version = python_version(base_name)?;
+            let offsets = python_offsets(&row.mod_name, &version)?;
 
             let mut globals: py_globals = Default::default();
 
@@ -105,18 +105,57 @@ pub mod user {
             } else {
                 pthreads_impl::PTI_GLIBC
             };
-            RuntimeType::Python {
+            Some(RuntimeType::Python {
                 is_lib,
                 version,
+                offsets,
                 pid_data: pid_data {
                     pthreads_impl,
                     globals,
                     interp: 0,
                 },
-            }
+            })
         }
     }
 
+    /// Version in the name of a python binary or libpython, e.g. python3.11 or libpython3.11.so.1.0
+    pub fn python_version(base_name: &str) -> Option<PythonVersion> {
+        let v_str = base_name.split("python").last()?;
+        let mut segs = v_str.split('.');
+        Some(PythonVersion {
+            major: segs.next()?.parse().ok()?,
+            // python3.7m
+            minor: segs.next()?.trim_end_matches(|c: char| !c.is_ascii_digit()).parse().ok()?,
+            patch: 0,
+        })
+    }
+
+    /// Offsets of a python binary, read once per file since every process of a host shares it.
+    /// `None` if the version isn't supported
+    pub fn python_offsets(path: &str, version: &PythonVersion) -> Option<PythonOffsets> {
+        static CACHE: Mutex<Vec<((u64, u64), PythonOffsets)>> = Mutex::new(Vec::new());
+
+        let base = version.offsets()?;
+        let key = match std::fs::metadata(path) {
+            Ok(meta) => (meta.dev(), meta.ino()),
+            Err(_) => return Some(base),
+        };
+        let mut cache = CACHE.lock().unwrap();
+        if let Some((_, offsets)) = cache.iter().find(|(k, _)| *k == key) {
+            return Some(*offsets);
+        }
+
+        let offsets = match dwarf::python_offsets(Path::new(path), &base) {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                tracing::info!("using the offsets of python {}.{} for {}: {}", version.major, version.minor, path, e);
+                base
+            }
+        };
+        cache.push((key, offsets));
+        Some(offsets)
+    }
+
     #[derive(Default, Debug)]
     struct PyInfo {
         v_addr: usize,
@@ -0,0 +1,46 @@
+/// Max length of a process name, including the nul terminator
+pub const COMM_LEN: usize = 16;
+
+/// How a probe decides in-kernel whether a task is in its scope
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScopeKind {
+    /// `tgid` is the process
+    Pid = 0,
+    /// the process is in the `SCOPE_PIDS` set of the probe, which `follow_fork` may extend.
+    /// `tgid` is the root of the followed processes, or 0 for a fixed set.
+    Pids = 1,
+    /// the task is in the cgroup v2 with id `cgroup_id` at depth `cgroup_level`, or in one below it
+    Cgroup = 2,
+    /// the name of the task starts with `comm[..comm_len]`
+    ProcessName = 3,
+}
+
+/// Per-probe filter for programs that can't be scoped on attach
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeScope {
+    pub kind: ScopeKind,
+    pub tgid: u32,
+    pub cgroup_id: u64,
+    pub comm: [u8; COMM_LEN],
+    pub comm_len: u32,
+    /// depth of the cgroup below the root, which is level 0
+    pub cgroup_level: u32,
+}
+
+impl ProbeScope {
+    pub const fn empty(kind: ScopeKind) -> Self {
+        Self {
+            kind,
+            tgid: 0,
+            cgroup_id: 0,
+            comm: [0; COMM_LEN],
+            comm_len: 0,
+            cgroup_level: 0,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ProbeScope {}
@@ -10,7 +10,7 @@ use duckdb::Connection;
 use duckdb::OptionalExt;
 use anyhow::Result;
 use fnv::FnvHashMap;
-use tail2::calltree::UnsymbolizedCallTree;
+use tail2::calltree::{UnsymbolizedCallTree, UnsymbolizedCallTreeV0};
 use tail2::Mergeable;
 use tail2::dto::ModuleMapping;
 use tail2::symbolication::module::Module;
@@ -24,6 +24,23 @@ use crate::tile::Tile;
 use self::module_table::DbBackedModuleMap;
 
 
+/// Stored call trees start with this byte, followed by the bincode encoded tree. Rows written
+/// before frames had values are a bare bincode `Option`, which starts with 0 or 1.
+const CT_FORMAT: u8 = 2;
+
+fn encode_ct(ct: &Option<UnsymbolizedCallTree>) -> Vec<u8> {
+    let mut bytes = vec![CT_FORMAT];
+    bincode::serialize_into(&mut bytes, ct).unwrap();
+    bytes
+}
+
+fn decode_ct(bytes: &[u8]) -> Option<UnsymbolizedCallTree> {
+    match bytes.first() {
+        Some(&CT_FORMAT) => bincode::deserialize(&bytes[1..]).unwrap(),
+        _ => bincode::deserialize::<Option<UnsymbolizedCallTreeV0>>(bytes).unwrap().map(Into::into),
+    }
+}
+
 /// A row in the database
 pub struct DbRow {
     /// timestamp
@@ -271,7 +288,7 @@ impl Tail2DB {
             .prepare("INSERT INTO samples_1 VALUES (?, ?, ?)")?;
 
         for row in data {
-            let ct_bytes = bincode::serialize(&row.ct).unwrap();
+            let ct_bytes = encode_ct(&row.ct);
             stmt.execute(params![
                 Duration::from_millis(row.ts_ms as u64),
                 ct_bytes,
@@ -347,7 +364,7 @@ impl Tail2DB {
             |row| {
                 Ok(DbRow {
                     ts_ms: row.get::<_, i64>(0)? / 1000,
-                    ct: bincode::deserialize(&row.get::<_, Vec<_>>(1)?).unwrap(),
+                    ct: decode_ct(&row.get::<_, Vec<_>>(1)?),
                     n: row.get(2)?,
                 })
             },
@@ -382,7 +399,7 @@ impl Tail2DB {
 
         // If we found a matching sample, return it
         if let Some((ct_bytes, n)) = ret {
-            let ct = bincode::deserialize(&ct_bytes).unwrap();
+            let ct = decode_ct(&ct_bytes);
             return Ok(DbResponse {
                 t0: start,
                 t1: start + scale,
@@ -424,7 +441,7 @@ impl Tail2DB {
                 .prepare(&format!("INSERT INTO samples_{scale} VALUES (?, ?, ?)"))?;
             stmt.execute(params![
                 Duration::from_millis(start as u64),
-                bincode::serialize(&merged.calltree).unwrap(),
+                encode_ct(&merged.calltree),
                 merged.n
             ])
             .unwrap();
@@ -512,4 +529,30 @@ mod tests {
         let bytes = bincode::serialize(&UnsymbolizedCallTree::new()).unwrap();
         // dbg!(&bytes.len());
     }
+
+    #[test]
+    fn test_decode_v0() {
+        use tail2::calltree::CallTreeFrameV0;
+        use tail2::dto::UnsymbolizedFrame;
+
+        let frames = [UnsymbolizedFrame::None, UnsymbolizedFrame::Truncated];
+        let ct = UnsymbolizedCallTree::from_weighted_frames(&frames, 3, 64);
+        assert_eq!(decode_ct(&encode_ct(&Some(ct.clone()))).unwrap().arena.count(), ct.arena.count());
+
+        // a tree stored before frames had values
+        let v0 = UnsymbolizedCallTreeV0 {
+            arena: ct.arena.clone().map(|f| CallTreeFrameV0 {
+                item: f.item,
+                total_samples: f.total_samples,
+                self_samples: f.self_samples,
+            }),
+            root: ct.root,
+        };
+        let decoded = decode_ct(&bincode::serialize(&Some(v0)).unwrap()).unwrap();
+        let leaf = decoded.root.descendants(&decoded.arena).last().unwrap();
+        let leaf = decoded.arena[leaf].get();
+        assert_eq!(leaf.item, UnsymbolizedFrame::Truncated);
+        assert_eq!((leaf.total_samples, leaf.self_samples, leaf.total_value), (3, 3, 0));
+        assert!(decode_ct(&bincode::serialize(&None::<UnsymbolizedCallTreeV0>).unwrap()).is_none());
+    }
 }
@@ -0,0 +1,93 @@
+use core::{mem::size_of, sync::atomic::{AtomicU64, Ordering}};
+
+use aya_bpf::{bindings::BPF_NOEXIST, helpers::gen::bpf_get_stack, maps::HashMap, BpfContext};
+use tail2_common::{aggregate::{AggStack, MAX_AGG_KERNEL_STACK}, bpf_sample::BpfSample, metrics::Metrics, python::state::FRAME_MAX_LEN, MAX_USER_STACK};
+
+use crate::{helpers::{FNV_OFFSET, FNV_PRIME}, maps::{AGGREGATE, AGG_ACTIVE, AGG_BUF, AGG_STACKS_0, AGG_STACKS_1}};
+
+pub(crate) fn is_aggregating(probe_id: u32) -> bool {
+    unsafe { AGGREGATE.get(&probe_id) }.is_some()
+}
+
+/// Count the stacks of `sample` in the active `AGG_STACKS_*` map instead of sending it,
+/// only the first occurrence of a stack is copied
+pub(crate) fn aggregate<C: BpfContext>(ctx: &C, sample: &BpfSample) -> Result<(), Metrics> {
+    let st = unsafe { &mut *(AGG_BUF.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+
+    st.python_len = 0;
+    if let Some(py) = &sample.python_stack {
+        for i in 0..FRAME_MAX_LEN {
+            if i >= py.frames_len {
+                break;
+            }
+            st.python_ids[i] = py.frames[i];
+            st.python_entry[i] = py.eval_entry[i];
+            st.python_len = i as u32 + 1;
+        }
+    }
+
+    // the tail called continuations run on the stack of the sampled task
+    let size = unsafe { bpf_get_stack(ctx.as_ptr(), st.kernel_frames.as_mut_ptr() as *mut _, (MAX_AGG_KERNEL_STACK * size_of::<u64>()) as u32, 0) };
+    st.kernel_len = if size > 0 { (size as usize / size_of::<u64>()) as u32 } else { 0 };
+
+    let len = sample.native_stack.unwind_success.unwrap_or(0);
+    let mut hash = (FNV_OFFSET ^ sample.pidtgid.tgid() as u64).wrapping_mul(FNV_PRIME);
+    hash = (hash ^ sample.probe_id as u64).wrapping_mul(FNV_PRIME);
+    for i in 0..MAX_AGG_KERNEL_STACK {
+        if i >= st.kernel_len as usize {
+            break;
+        }
+        hash = (hash ^ st.kernel_frames[i]).wrapping_mul(FNV_PRIME);
+    }
+    for i in 0..MAX_USER_STACK {
+        if i >= len {
+            break;
+        }
+        hash = (hash ^ sample.native_stack.native_stack[i] as u64).wrapping_mul(FNV_PRIME);
+    }
+    for i in 0..FRAME_MAX_LEN {
+        if i >= st.python_len as usize {
+            break;
+        }
+        hash = (hash ^ (st.python_ids[i] as u32 as u64 | (st.python_entry[i] as u64) << 32)).wrapping_mul(FNV_PRIME);
+    }
+
+    let stacks: &HashMap<u64, AggStack> = match AGG_ACTIVE.get(0) {
+        Some(&1) => &AGG_STACKS_1,
+        _ => &AGG_STACKS_0,
+    };
+    if count(stacks, hash) {
+        return Ok(());
+    }
+
+    st.count = 1;
+    st.pidtgid = sample.pidtgid;
+    st.probe_id = sample.probe_id;
+    st.native_stack.unwind_success = sample.native_stack.unwind_success;
+    st.native_stack.truncated = sample.native_stack.truncated;
+    st.native_stack.unwind_error = sample.native_stack.unwind_error;
+    for i in 0..MAX_USER_STACK {
+        if i >= len {
+            break;
+        }
+        st.native_stack.native_stack[i] = sample.native_stack.native_stack[i];
+    }
+
+    if stacks.insert(&hash, st, BPF_NOEXIST as u64).is_ok() || count(stacks, hash) {
+        Ok(())
+    } else {
+        Err(Metrics::ErrAgg_CantStore)
+    }
+}
+
+/// Count one more sample of a stack that's already stored
+fn count(stacks: &HashMap<u64, AggStack>, hash: u64) -> bool {
+    match stacks.get_ptr_mut(&hash) {
+        Some(st) => {
+            unsafe { (*(core::ptr::addr_of_mut!((*st).count) as *const AtomicU64)).fetch_add(1, Ordering::Relaxed) };
+            true
+        }
+        // not seen yet, or another cpu lost the race to insert it and the map is full
+        None => false,
+    }
+}
@@ -0,0 +1,78 @@
+use aya_bpf::{helpers::bpf_get_current_pid_tgid, macros::tracepoint, programs::TracePointContext};
+use tail2_common::{metrics::Metrics, MAX_PIDS_SCOPES};
+
+use crate::{helpers::{get_pid_tgid, in_scope, probe_id, thread_key}, maps::{PIDS_SCOPES, SCOPE_PENDING, SCOPE_PIDS}, sample::incr_metric};
+
+// offsets into /sys/kernel/debug/tracing/events/task/task_newtask/format
+const NEWTASK_PID_OFFSET: usize = 8;
+const NEWTASK_CLONE_FLAGS_OFFSET: usize = 32;
+
+const CLONE_THREAD: u64 = 0x10000;
+
+/// attached to task:task_newtask next to the probe programs of a `Scope::Descendants` probe
+#[tracepoint(name="follow_fork")] fn follow_fork(ctx: TracePointContext) { fork(&ctx, probe_id(&ctx)); }
+/// attached to sched:sched_process_exec next to `follow_fork`
+#[tracepoint(name="follow_exec")] fn follow_exec(ctx: TracePointContext) { exec(probe_id(&ctx)); }
+
+fn fork(ctx: &TracePointContext, probe_id: u32) {
+    if let Err(e) = fork_inner(ctx, probe_id) {
+        incr_metric(e);
+    }
+}
+
+/// The tracepoint fires in the parent, a child process of a process in scope joins the scope.
+/// The tracepoint has the pid of the child in the root namespace while the scope is in the
+/// namespace of the agent, so the child waits in `SCOPE_PENDING` until it runs, see `pids_contain`.
+fn fork_inner(ctx: &TracePointContext, probe_id: u32) -> Result<(), Metrics> {
+    if !in_scope(probe_id, get_pid_tgid()) {
+        return Ok(());
+    }
+
+    let flags: u64 = unsafe { ctx.read_at(NEWTASK_CLONE_FLAGS_OFFSET) }.map_err(|_| Metrics::ErrFollow_ReadTracepoint)?;
+    if flags & CLONE_THREAD != 0 {
+        // threads are in the scope of their process
+        return Ok(());
+    }
+    let child_pid: i32 = unsafe { ctx.read_at(NEWTASK_PID_OFFSET) }.map_err(|_| Metrics::ErrFollow_ReadTracepoint)?;
+    SCOPE_PENDING
+        .insert(&thread_key(probe_id, child_pid as u32), &1, 0)
+        .map_err(|_| Metrics::ErrFollow_CantStore)
+}
+
+/// a child that execs right away joins the scope before the new program runs
+fn exec(probe_id: u32) {
+    let _ = in_scope(probe_id, get_pid_tgid());
+}
+
+/// Whether the process of the current task is in the pid set of the probe.
+/// A child waiting in `SCOPE_PENDING` moves to `SCOPE_PIDS` the first time it's seen.
+pub(crate) fn pids_contain(probe_id: u32, tgid: u32) -> bool {
+    let key = thread_key(probe_id, tgid);
+    if unsafe { SCOPE_PIDS.get(&key) }.is_some() {
+        return true;
+    }
+
+    let root_tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    let pending = thread_key(probe_id, root_tgid);
+    if unsafe { SCOPE_PENDING.get(&pending) }.is_none() {
+        return false;
+    }
+    let _ = SCOPE_PENDING.remove(&pending);
+    if SCOPE_PIDS.insert(&key, &1, 0).is_err() {
+        incr_metric(Metrics::ErrFollow_CantStore);
+    }
+    true
+}
+
+/// Drop an exiting process from the pid sets, so a process reusing the pid isn't in scope
+pub(crate) fn forget_scope(tgid: u32) {
+    let root_tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    for i in 0..MAX_PIDS_SCOPES {
+        let probe_id = match PIDS_SCOPES.get(i) {
+            Some(&id) if id != 0 => id - 1,
+            _ => continue,
+        };
+        let _ = SCOPE_PIDS.remove(&thread_key(probe_id, tgid));
+        let _ = SCOPE_PENDING.remove(&thread_key(probe_id, root_tgid));
+    }
+}
@@ -1,7 +1,7 @@
-use aya_bpf::{bindings::{bpf_pidns_info, task_struct}, helpers::{bpf_get_ns_current_pid_tgid, bpf_get_current_task, bpf_get_current_pid_tgid}};
-use tail2_common::{ConfigMapKey, pidtgid::PidTgid};
+use aya_bpf::{bindings::{bpf_pidns_info, task_struct}, helpers::{bpf_get_ns_current_pid_tgid, bpf_get_current_task, bpf_get_current_pid_tgid, bpf_get_attach_cookie, bpf_get_current_ancestor_cgroup_id, bpf_get_current_comm}, BpfContext};
+use tail2_common::{ConfigMapKey, pidtgid::PidTgid, scope::{ProbeScope, ScopeKind, COMM_LEN}};
 
-use crate::maps::CONFIG;
+use crate::{follow::pids_contain, maps::{CONFIG, PROBE_SCOPE}};
 
 pub fn get_pid_tgid() -> PidTgid {
     let dev = unsafe { CONFIG.get(&(ConfigMapKey::DEV as u32)) }.copied().unwrap_or(1);
@@ -12,4 +12,51 @@ pub fn get_pid_tgid() -> PidTgid {
     // TODO: make a nice wrapper for this so it'll always get initialized correctly.
     unsafe { bpf_get_ns_current_pid_tgid(dev, ino, &mut ns as *mut bpf_pidns_info, core::mem::size_of::<bpf_pidns_info>() as u32); }
     PidTgid::current(ns.pid, ns.tgid)
-}
\ No newline at end of file
+}
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+pub(crate) const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Every attachment carries the id of its probe as the bpf cookie
+#[inline(always)]
+pub fn probe_id<C: BpfContext>(ctx: &C) -> u32 {
+    unsafe { bpf_get_attach_cookie(ctx.as_ptr()) as u32 }
+}
+
+/// Key for per-thread state of a probe
+#[inline(always)]
+pub fn thread_key(probe_id: u32, tid: u32) -> u64 {
+    (probe_id as u64) << 32 | tid as u64
+}
+
+/// Whether the task belongs to the processes the probe is restricted to
+pub fn in_scope(probe_id: u32, pid_tgid: PidTgid) -> bool {
+    let scope = match unsafe { PROBE_SCOPE.get(&probe_id) } {
+        Some(scope) => scope,
+        None => return true,
+    };
+    match scope.kind {
+        ScopeKind::Pid => scope.tgid == pid_tgid.tgid(),
+        ScopeKind::Pids => pids_contain(probe_id, pid_tgid.tgid()),
+        // the ancestor at the level of the scope's cgroup, so nested cgroups are in scope too
+        ScopeKind::Cgroup => unsafe { bpf_get_current_ancestor_cgroup_id(scope.cgroup_level as i32) } == scope.cgroup_id,
+        ScopeKind::ProcessName => comm_matches(scope),
+    }
+}
+
+/// threads inherit the name of the process unless they rename themselves
+fn comm_matches(scope: &ProbeScope) -> bool {
+    let comm = match bpf_get_current_comm() {
+        Ok(comm) => comm,
+        Err(_) => return false,
+    };
+    for i in 0..COMM_LEN {
+        if i >= scope.comm_len as usize {
+            break;
+        }
+        if comm[i] != scope.comm[i] {
+            return false;
+        }
+    }
+    true
+}
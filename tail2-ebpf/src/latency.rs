@@ -0,0 +1,89 @@
+use aya_bpf::{macros::{uprobe, uretprobe}, programs::ProbeContext, helpers::bpf_ktime_get_ns};
+use tail2_common::{metrics::Metrics, bpf_sample::SampleKind};
+
+use crate::{helpers::{get_pid_tgid, in_scope, probe_id, thread_key}, maps::{LATENCY_START, STACK_BUF}, sample::{fill_sample, finish_sample, incr_metric}, user::UnwindDone};
+
+/// Nested calls of the probed function that are timed per thread, deeper recursion is only counted
+pub(crate) const MAX_LATENCY_DEPTH: usize = 16;
+
+/// Entry timestamps of the running calls of a thread, innermost last
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct LatencyStack {
+    /// calls that haven't returned yet, can be more than `MAX_LATENCY_DEPTH`
+    pub depth: u32,
+    pub _pad: u32,
+    pub start: [u64; MAX_LATENCY_DEPTH],
+}
+
+#[uprobe(name="latency_enter")] fn latency_enter(ctx: ProbeContext) { enter(probe_id(&ctx)); }
+#[uretprobe(name="latency_exit")] fn latency_exit(ctx: ProbeContext) { exit(&ctx, probe_id(&ctx)); }
+
+fn enter(probe_id: u32) {
+    let pid_tgid = get_pid_tgid();
+    if !in_scope(probe_id, pid_tgid) {
+        return;
+    }
+    let key = thread_key(probe_id, pid_tgid.pid());
+    let now = unsafe { bpf_ktime_get_ns() };
+    let stack = match unsafe { LATENCY_START.get_ptr_mut(&key) } {
+        Some(stack) => stack,
+        None => {
+            let empty = LatencyStack { depth: 0, _pad: 0, start: [0; MAX_LATENCY_DEPTH] };
+            if unsafe { LATENCY_START.insert(&key, &empty, 0) }.is_err() {
+                incr_metric(Metrics::ErrLatency_CantStore);
+                return;
+            }
+            match unsafe { LATENCY_START.get_ptr_mut(&key) } {
+                Some(stack) => stack,
+                None => return,
+            }
+        }
+    };
+    let stack = unsafe { &mut *stack };
+    let depth = stack.depth as usize;
+    if depth < MAX_LATENCY_DEPTH {
+        stack.start[depth] = now;
+    } else {
+        incr_metric(Metrics::ErrLatency_TooDeep);
+    }
+    stack.depth += 1;
+}
+
+fn exit(ctx: &ProbeContext, probe_id: u32) {
+    if let Err(e) = exit_inner(ctx, probe_id) {
+        incr_metric(e);
+    }
+}
+
+/// on return the callee frame is gone, so the stack is the one of the caller.
+/// Every call counts once, with its duration as the value.
+fn exit_inner(ctx: &ProbeContext, probe_id: u32) -> Result<(), Metrics> {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let key = thread_key(probe_id, get_pid_tgid().pid());
+    let stack = match unsafe { LATENCY_START.get_ptr_mut(&key) } {
+        Some(stack) => unsafe { &mut *stack },
+        // the probe was attached while the function was running
+        None => return Ok(()),
+    };
+    if stack.depth == 0 {
+        return Ok(());
+    }
+    stack.depth -= 1;
+    let depth = stack.depth as usize;
+    if depth >= MAX_LATENCY_DEPTH {
+        return Ok(());
+    }
+    let start = stack.start[depth];
+    if depth == 0 {
+        let _ = unsafe { LATENCY_START.remove(&key) };
+    }
+
+    let sample = unsafe { &mut *(STACK_BUF.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    fill_sample(ctx, sample, probe_id)?;
+    sample.weight = 1;
+    sample.value = Some(now.saturating_sub(start));
+    sample.kind = SampleKind::OnCpu;
+
+    finish_sample(ctx, sample, UnwindDone::Output, 0, 0)
+}
@@ -0,0 +1,87 @@
+use aya_bpf::{helpers::{bpf_get_current_task, bpf_probe_read}, macros::tracepoint, programs::TracePointContext};
+use tail2_common::metrics::Metrics;
+
+use crate::{vmlinux::task_struct, follow::forget_scope, helpers::get_pid_tgid, maps::{MMAP_PENDING, PIDS, PID_REPORTS}, sample::incr_metric, tracemgmt::{pid_info_exists, report_pid_event}};
+
+// offsets into /sys/kernel/debug/tracing/events/syscalls/sys_{enter,exit}_mmap/format
+const MMAP_PROT_OFFSET: usize = 32;
+const MMAP_FD_OFFSET: usize = 48;
+const MMAP_RET_OFFSET: usize = 16;
+
+const PROT_EXEC: u64 = 4;
+
+/// attached once on startup to sched:sched_process_exit, fires for every exiting thread
+#[tracepoint(name="proc_exit")] fn proc_exit(ctx: TracePointContext) { exit(&ctx); }
+/// attached once on startup to sched:sched_process_exec
+#[tracepoint(name="proc_exec")] fn proc_exec(ctx: TracePointContext) { exec(&ctx); }
+/// attached once on startup to syscalls:sys_enter_mmap and syscalls:sys_exit_mmap
+#[tracepoint(name="mmap_enter")] fn mmap_enter(ctx: TracePointContext) { mmap(&ctx); }
+#[tracepoint(name="mmap_exit")] fn mmap_exit(ctx: TracePointContext) { mmap_ret(&ctx); }
+
+fn exit(ctx: &TracePointContext) {
+    let pid_tgid = get_pid_tgid();
+    // the entry of the main thread stands for the process until the last thread exits, see `mmap`
+    if pid_tgid.pid() != pid_tgid.tgid() {
+        forget_pid(pid_tgid.pid());
+    }
+
+    // user space caches and scopes are per process, the main thread may exit before the others
+    if group_dead() {
+        forget_pid(pid_tgid.tgid());
+        forget_scope(pid_tgid.tgid());
+        report_pid_event(ctx, pid_tgid.tgid(), Metrics::TraceMgmt_PidExit);
+        incr_metric(Metrics::TraceMgmt_PidExit);
+    }
+}
+
+/// Whether the exiting thread is the last one of its process.
+/// `do_exit` decrements `signal->live` before the tracepoint fires.
+fn group_dead() -> bool {
+    let task: *const task_struct = unsafe { bpf_get_current_task() as *const _ };
+    let live = unsafe { bpf_probe_read(&(*task).signal).and_then(|signal| bpf_probe_read(&(*signal).live.counter)) };
+    matches!(live, Ok(0))
+}
+
+/// the old unwind info describes a different binary, so it's rebuilt right away
+fn exec(ctx: &TracePointContext) {
+    let pid_tgid = get_pid_tgid();
+    forget_pid(pid_tgid.pid());
+
+    report_pid_event(ctx, pid_tgid.pid(), Metrics::TraceMgmt_PidExec);
+    incr_metric(Metrics::TraceMgmt_PidExec);
+}
+
+fn forget_pid(pid: u32) {
+    let _ = PIDS.remove(&pid);
+    let _ = PID_REPORTS.remove(&pid);
+}
+
+/// remember file backed executable mappings of traced processes, e.g. from dlopen.
+/// Any thread may map code, user space keeps an entry for the process next to the sampled threads.
+fn mmap(ctx: &TracePointContext) {
+    let pid_tgid = get_pid_tgid();
+    if !pid_info_exists(pid_tgid.tgid()) {
+        return;
+    }
+
+    let prot: u64 = unsafe { ctx.read_at(MMAP_PROT_OFFSET) }.unwrap_or(0);
+    let fd: i64 = unsafe { ctx.read_at(MMAP_FD_OFFSET) }.unwrap_or(-1);
+    if prot & PROT_EXEC != 0 && fd >= 0 {
+        let _ = MMAP_PENDING.insert(&pid_tgid.pid(), &1, 0);
+    }
+}
+
+/// the mapping only shows up in /proc/pid/maps once the syscall returns
+fn mmap_ret(ctx: &TracePointContext) {
+    let pid_tgid = get_pid_tgid();
+    if unsafe { MMAP_PENDING.get(&pid_tgid.pid()) }.is_none() {
+        return;
+    }
+    let _ = MMAP_PENDING.remove(&pid_tgid.pid());
+
+    let ret: i64 = unsafe { ctx.read_at(MMAP_RET_OFFSET) }.unwrap_or(-1);
+    if ret >= 0 {
+        report_pid_event(ctx, pid_tgid.tgid(), Metrics::TraceMgmt_NewMap);
+        incr_metric(Metrics::TraceMgmt_NewMap);
+    }
+}
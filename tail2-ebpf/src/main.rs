@@ -10,3 +10,9 @@ mod sample;
 mod kernel;
 mod tracemgmt;
 mod maps;
+mod offcpu;
+mod latency;
+mod memory;
+mod follow;
+mod lifecycle;
+mod aggregate;
@@ -1,10 +1,15 @@
-use aya_bpf::{macros::map, maps::{PerfEventArray, PerCpuArray, HashMap, StackTrace}};
-use tail2_common::{bpf_sample::BpfSample, procinfo::ProcInfo, metrics::Metrics, tracemgmt::PidEvent};
+use aya_bpf::{macros::map, maps::{Array, PerfEventArray, PerCpuArray, HashMap, LruHashMap, ProgramArray, RingBuf, StackTrace}};
+use crate::{latency::LatencyStack, user::UnwindState};
+use tail2_common::{DEFAULT_OFFCPU_STACKS, MAX_PIDS_SCOPES, MAX_PROBES, RING_BUF_SIZE, aggregate::{AggStack, MAX_AGG_STACKS}, bpf_sample::BpfSample, memory::{AllocKey, LiveAlloc, MemStack}, scope::ProbeScope, procinfo::{ProcInfo, UnwindRow, MAX_UNWIND_ROWS}, metrics::Metrics, tracemgmt::PidEvent};
 
 /// Used to send samples to user space
 #[map(name="STACKS")]
 pub(crate) static mut STACKS: PerfEventArray<BpfSample> = PerfEventArray::new(0);
 
+/// Used to send samples to user space unless the agent is configured for `STACKS`, see `output_sample`
+#[map(name="STACKS_RING")]
+pub(crate) static STACKS_RING: RingBuf = RingBuf::with_byte_size(RING_BUF_SIZE, 0);
+
 /// Used to send pid events to user space
 #[map(name="PID_EVENT")]
 pub(crate) static mut PID_EVENT: PerfEventArray<PidEvent> = PerfEventArray::new(0);
@@ -13,22 +18,109 @@ pub(crate) static mut PID_EVENT: PerfEventArray<PidEvent> = PerfEventArray::new(
 #[map(name="STACK_BUF")]
 pub(crate) static mut STACK_BUF: PerCpuArray<BpfSample> = PerCpuArray::with_max_entries(1, 0);
 
+/// Unwinder state handed from one program to the next tail call
+#[map(name="UNWIND_STATE")]
+pub(crate) static mut UNWIND_STATE: PerCpuArray<UnwindState> = PerCpuArray::with_max_entries(1, 0);
+
+/// Continuations of the user stack unwinder, one per program type, see `Continue`
+#[map(name="UNWIND_PROGS")]
+pub(crate) static UNWIND_PROGS: ProgramArray = ProgramArray::with_max_entries(3, 0);
+
 /// Used to store misc config
 #[map(name="CONFIG")]
 pub(crate) static CONFIG: HashMap<u32, u64> = HashMap::with_max_entries(10, 0);
 
-/// Used to store kernel stacks
+/// Used to store kernel stacks of samples sent to user space
 #[map(name="KERNEL_STACKS")]
-pub(crate) static KERNEL_STACKS: StackTrace = StackTrace::with_max_entries(10, 0);
+pub(crate) static KERNEL_STACKS: StackTrace = StackTrace::with_max_entries(MAX_AGG_STACKS, 0);
 
-/// PID unwind information
+/// Unwind tables of all modules, each loaded once no matter how many processes map it
+#[map(name="UNWIND_ROWS")]
+pub(crate) static UNWIND_ROWS: Array<UnwindRow> = Array::with_max_entries(MAX_UNWIND_ROWS, 0);
+
+/// PID unwind information, evicted on exit and exec
 #[map(name="PIDS")]
 pub(crate) static PIDS: HashMap<u32, ProcInfo> = HashMap::with_max_entries(512, 0);
 
 /// A map of pid -> timestamp of when we recently triggered it
 #[map(name="PID_REPORTS")]
-pub(crate) static PID_REPORTS: HashMap<u32, u64> = HashMap::with_max_entries(512, 0);
+pub(crate) static PID_REPORTS: LruHashMap<u32, u64> = LruHashMap::with_max_entries(512, 0);
+
+/// tid -> present while an executable mmap of a traced process is in flight
+#[map(name="MMAP_PENDING")]
+pub(crate) static MMAP_PENDING: LruHashMap<u32, u8> = LruHashMap::with_max_entries(1024, 0);
 
 /// metrics -> count
 #[map(name="METRICS")]
 pub(crate) static METRICS: HashMap<u32, u64> = HashMap::with_max_entries(Metrics::Max as u32, 0);
+
+/// probe id -> processes the probe is restricted to, for programs that can't be scoped on attach
+#[map(name="PROBE_SCOPE")]
+pub(crate) static PROBE_SCOPE: HashMap<u32, ProbeScope> = HashMap::with_max_entries(MAX_PROBES, 0);
+
+/// (probe id, tgid) -> present when the process is in the scope of a `ScopeKind::Pids` probe,
+/// grows with forked children when descendants are followed
+#[map(name="SCOPE_PIDS")]
+pub(crate) static SCOPE_PIDS: HashMap<u64, u8> = HashMap::with_max_entries(16384, 0);
+
+/// (probe id, pid in the root namespace) -> forked children of processes in `SCOPE_PIDS`
+/// that haven't run yet
+#[map(name="SCOPE_PENDING")]
+pub(crate) static SCOPE_PENDING: LruHashMap<u64, u8> = LruHashMap::with_max_entries(4096, 0);
+
+/// probe id + 1 of the probes with a `ScopeKind::Pids` scope, 0 for a free slot
+#[map(name="PIDS_SCOPES")]
+pub(crate) static PIDS_SCOPES: Array<u32> = Array::with_max_entries(MAX_PIDS_SCOPES, 0);
+
+/// (probe id, tid) -> timestamp of when the task was switched out
+#[map(name="OFFCPU_START")]
+pub(crate) static OFFCPU_START: LruHashMap<u64, u64> = LruHashMap::with_max_entries(1024, 0);
+
+/// (probe id, tid) -> stacks of the task when it was switched out, user space resizes it
+/// on load since every entry is a whole `BpfSample`
+#[map(name="OFFCPU_STACKS")]
+pub(crate) static mut OFFCPU_STACKS: LruHashMap<u64, BpfSample> = LruHashMap::with_max_entries(DEFAULT_OFFCPU_STACKS, 0);
+
+/// (probe id, tid) -> entry timestamps of the calls to the probed function that are running
+#[map(name="LATENCY_START")]
+pub(crate) static mut LATENCY_START: LruHashMap<u64, LatencyStack> = LruHashMap::with_max_entries(1024, 0);
+
+/// probe id -> index of the argument to capture as the sample value
+#[map(name="VALUE_ARG")]
+pub(crate) static VALUE_ARG: HashMap<u32, u32> = HashMap::with_max_entries(MAX_PROBES, 0);
+
+/// (probe id, tid) -> size passed to the allocation function that's running
+#[map(name="MEM_PENDING")]
+pub(crate) static MEM_PENDING: LruHashMap<u64, u64> = LruHashMap::with_max_entries(1024, 0);
+
+/// allocations that haven't been freed yet
+#[map(name="LIVE_ALLOCS")]
+pub(crate) static LIVE_ALLOCS: HashMap<AllocKey, LiveAlloc> = HashMap::with_max_entries(65536, 0);
+
+/// stack id -> native stack of the allocation site
+#[map(name="MEM_STACKS")]
+pub(crate) static MEM_STACKS: LruHashMap<u64, MemStack> = LruHashMap::with_max_entries(4096, 0);
+
+/// probe id -> 1 for probes that count stacks in the kernel instead of sending every sample
+#[map(name="AGGREGATE")]
+pub(crate) static AGGREGATE: HashMap<u32, u8> = HashMap::with_max_entries(MAX_PROBES, 0);
+
+/// stack hash -> unique stack of an aggregating probe and its count.
+/// Samples go to one of the two maps while user space drains the other, see `AGG_ACTIVE`
+#[map(name="AGG_STACKS_0")]
+pub(crate) static AGG_STACKS_0: HashMap<u64, AggStack> = HashMap::with_max_entries(MAX_AGG_STACKS, 0);
+
+#[map(name="AGG_STACKS_1")]
+pub(crate) static AGG_STACKS_1: HashMap<u64, AggStack> = HashMap::with_max_entries(MAX_AGG_STACKS, 0);
+
+/// index of the `AGG_STACKS_*` map samples are counted in, flipped by user space on every drain
+#[map(name="AGG_ACTIVE")]
+pub(crate) static AGG_ACTIVE: Array<u32> = Array::with_max_entries(1, 0);
+
+/// Used as a heap for aggregated stacks
+#[map(name="AGG_BUF")]
+pub(crate) static mut AGG_BUF: PerCpuArray<AggStack> = PerCpuArray::with_max_entries(1, 0);
+
+/// Used as a heap for allocation stacks
+#[map(name="MEM_STACK_BUF")]
+pub(crate) static mut MEM_STACK_BUF: PerCpuArray<MemStack> = PerCpuArray::with_max_entries(1, 0);
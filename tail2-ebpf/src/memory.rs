@@ -0,0 +1,114 @@
+use aya_bpf::{macros::{uprobe, uretprobe}, programs::ProbeContext};
+use tail2_common::{metrics::Metrics, memory::{AllocKey, LiveAlloc}, NativeStack, MAX_USER_STACK};
+
+use crate::{helpers::{get_pid_tgid, in_scope, probe_id, thread_key, FNV_OFFSET, FNV_PRIME}, maps::{LIVE_ALLOCS, MEM_PENDING, MEM_STACKS, MEM_STACK_BUF}, sample::incr_metric, tracemgmt::{pid_info_exists, report_new_pid}, user::sample_user};
+
+#[uprobe(name="mem_malloc")] fn mem_malloc(ctx: ProbeContext) { malloc(&ctx, probe_id(&ctx)); }
+#[uprobe(name="mem_calloc")] fn mem_calloc(ctx: ProbeContext) { calloc(&ctx, probe_id(&ctx)); }
+#[uprobe(name="mem_realloc")] fn mem_realloc(ctx: ProbeContext) { realloc(&ctx, probe_id(&ctx)); }
+#[uprobe(name="mem_free")] fn mem_free(ctx: ProbeContext) { free(&ctx, probe_id(&ctx)); }
+/// shared by the malloc, calloc and realloc return probes
+#[uretprobe(name="mem_alloc_ret")] fn mem_alloc_ret(ctx: ProbeContext) { alloc_ret(&ctx, probe_id(&ctx)); }
+
+fn malloc(ctx: &ProbeContext, probe_id: u32) {
+    let size: u64 = ctx.arg(0).unwrap_or(0);
+    set_pending(probe_id, size);
+}
+
+fn calloc(ctx: &ProbeContext, probe_id: u32) {
+    let nmemb: u64 = ctx.arg(0).unwrap_or(0);
+    let size: u64 = ctx.arg(1).unwrap_or(0);
+    set_pending(probe_id, nmemb.saturating_mul(size));
+}
+
+/// the old block is treated as freed, the new one is recorded on return
+fn realloc(ctx: &ProbeContext, probe_id: u32) {
+    let ptr: u64 = ctx.arg(0).unwrap_or(0);
+    let size: u64 = ctx.arg(1).unwrap_or(0);
+    remove_alloc(probe_id, ptr);
+    set_pending(probe_id, size);
+}
+
+fn free(ctx: &ProbeContext, probe_id: u32) {
+    let ptr: u64 = ctx.arg(0).unwrap_or(0);
+    remove_alloc(probe_id, ptr);
+}
+
+/// allocations out of scope have no pending size, so their return is skipped as well
+fn set_pending(probe_id: u32, size: u64) {
+    let pid_tgid = get_pid_tgid();
+    if !in_scope(probe_id, pid_tgid) {
+        return;
+    }
+    let key = thread_key(probe_id, pid_tgid.pid());
+    if MEM_PENDING.insert(&key, &size, 0).is_err() {
+        incr_metric(Metrics::ErrMem_CantStore);
+    }
+}
+
+fn remove_alloc(probe_id: u32, ptr: u64) {
+    if ptr == 0 {
+        return;
+    }
+    let key = AllocKey { ptr, tgid: get_pid_tgid().tgid(), probe_id };
+    let _ = LIVE_ALLOCS.remove(&key);
+}
+
+fn alloc_ret(ctx: &ProbeContext, probe_id: u32) {
+    if let Err(e) = alloc_ret_inner(ctx, probe_id) {
+        incr_metric(e);
+    }
+}
+
+fn alloc_ret_inner(ctx: &ProbeContext, probe_id: u32) -> Result<(), Metrics> {
+    let pid_tgid = get_pid_tgid();
+    let pending = thread_key(probe_id, pid_tgid.pid());
+    let size = match unsafe { MEM_PENDING.get(&pending) } {
+        Some(size) => *size,
+        // out of scope, or the probe was attached while the call was running
+        None => return Ok(()),
+    };
+    let _ = MEM_PENDING.remove(&pending);
+
+    let ptr: u64 = ctx.ret().unwrap_or(0);
+    if ptr == 0 {
+        return Ok(());
+    }
+
+    if !pid_info_exists(pid_tgid.pid()) {
+        return report_new_pid(ctx, pid_tgid.pid());
+    }
+
+    let st = unsafe { &mut *(MEM_STACK_BUF.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    st.pidtgid = pid_tgid;
+    st.native_stack = NativeStack::uninit();
+    // the allocation is still tracked when the unwinder fails, it is reported under the error
+    if let Err(e) = sample_user(ctx, &mut st.native_stack, pid_tgid.pid()) {
+        st.native_stack.unwind_error = Some(e);
+    }
+
+    // identical allocation sites share one stored stack, user space reports allocations
+    // whose stack is missing without frames
+    let stack_id = stack_hash(pid_tgid.tgid(), &st.native_stack);
+    if unsafe { MEM_STACKS.get(&stack_id) }.is_none() && MEM_STACKS.insert(&stack_id, st, 0).is_err() {
+        incr_metric(Metrics::ErrMem_CantStore);
+    }
+
+    let key = AllocKey { ptr, tgid: pid_tgid.tgid(), probe_id };
+    let alloc = LiveAlloc { stack_id, size };
+    LIVE_ALLOCS.insert(&key, &alloc, 0).map_err(|_| Metrics::ErrMem_CantStore)?;
+
+    Ok(())
+}
+
+fn stack_hash(tgid: u32, st: &NativeStack) -> u64 {
+    let len = st.unwind_success.unwrap_or(0);
+    let mut hash = (FNV_OFFSET ^ tgid as u64).wrapping_mul(FNV_PRIME);
+    for i in 0..MAX_USER_STACK {
+        if i >= len {
+            break;
+        }
+        hash = (hash ^ st.native_stack[i] as u64).wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
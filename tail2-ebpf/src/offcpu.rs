@@ -0,0 +1,70 @@
+use aya_bpf::{macros::tracepoint, programs::TracePointContext, helpers::bpf_ktime_get_ns};
+use tail2_common::{metrics::Metrics, bpf_sample::SampleKind};
+
+use crate::{helpers::{get_pid_tgid, in_scope, probe_id, thread_key}, maps::{OFFCPU_START, OFFCPU_STACKS, STACK_BUF}, sample::{fill_sample, finish_sample, incr_metric, output_sample}, user::UnwindDone};
+
+// offsets into /sys/kernel/debug/tracing/events/sched/sched_switch/format
+const PREV_PID_OFFSET: usize = 24;
+const PREV_STATE_OFFSET: usize = 32;
+const NEXT_PID_OFFSET: usize = 56;
+
+const TASK_RUNNING: i64 = 0;
+
+#[tracepoint(name="offcpu")] fn offcpu(ctx: TracePointContext) { sched_switch(&ctx, probe_id(&ctx)); }
+
+fn sched_switch(ctx: &TracePointContext, probe_id: u32) {
+    if let Err(e) = sched_switch_inner(ctx, probe_id) {
+        incr_metric(e);
+    }
+}
+
+fn sched_switch_inner(ctx: &TracePointContext, probe_id: u32) -> Result<(), Metrics> {
+    let prev_pid: i32 = unsafe { ctx.read_at(PREV_PID_OFFSET) }.map_err(|_| Metrics::ErrOffCpu_ReadTracepoint)?;
+    let prev_state: i64 = unsafe { ctx.read_at(PREV_STATE_OFFSET) }.map_err(|_| Metrics::ErrOffCpu_ReadTracepoint)?;
+    let next_pid: i32 = unsafe { ctx.read_at(NEXT_PID_OFFSET) }.map_err(|_| Metrics::ErrOffCpu_ReadTracepoint)?;
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    if next_pid != 0 {
+        if let Err(e) = switch_in(ctx, probe_id, next_pid as u32, now) {
+            incr_metric(e);
+        }
+    }
+
+    // preempted tasks are still runnable, only record the ones that block.
+    // this goes last since unwinding may end the program in a tail call
+    if prev_pid != 0 && prev_state != TASK_RUNNING {
+        switch_out(ctx, probe_id, prev_pid as u32, now)?;
+    }
+
+    Ok(())
+}
+
+/// `prev` is still the current task, so its stacks can be captured as usual
+fn switch_out(ctx: &TracePointContext, probe_id: u32, tid: u32, now: u64) -> Result<(), Metrics> {
+    if !in_scope(probe_id, get_pid_tgid()) {
+        return Ok(());
+    }
+
+    let sample = unsafe { &mut *(STACK_BUF.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    fill_sample(ctx, sample, probe_id)?;
+    sample.kind = SampleKind::OffCpu;
+    finish_sample(ctx, sample, UnwindDone::OffCpu, thread_key(probe_id, tid), now)
+}
+
+/// weight the stacks recorded at switch out by how long the task was blocked
+fn switch_in(ctx: &TracePointContext, probe_id: u32, tid: u32, now: u64) -> Result<(), Metrics> {
+    let key = thread_key(probe_id, tid);
+    let start = match unsafe { OFFCPU_START.get(&key) } {
+        Some(start) => *start,
+        None => return Ok(()),
+    };
+    let _ = OFFCPU_START.remove(&key);
+
+    let sample = unsafe { &mut *(OFFCPU_STACKS.get_ptr_mut(&key).ok_or(Metrics::ErrOffCpu_NoStack)?) };
+    sample.weight = now.saturating_sub(start);
+
+    output_sample(ctx, sample);
+    let _ = OFFCPU_STACKS.remove(&key);
+
+    Ok(())
+}
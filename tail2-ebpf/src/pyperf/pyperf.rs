@@ -1,6 +1,6 @@
-use aya_bpf::{maps::{PerCpuArray, PerfEventArray, HashMap}, macros::map, helpers::{bpf_get_current_comm, bpf_probe_read_user, bpf_get_current_task}, BpfContext};
+use aya_bpf::{maps::{PerCpuArray, PerfEventArray, LruHashMap}, macros::map, helpers::{bpf_get_current_comm, bpf_probe_read_user, bpf_get_current_task, bpf_get_smp_processor_id}, bindings::BPF_NOEXIST, BpfContext};
 use aya_log_ebpf::info;
-use tail2_common::{python::{state::{PythonSymbol, PythonStack, StackStatus}}, metrics::Metrics};
+use tail2_common::{python::{state::{PythonSymbol, PythonStack, StackStatus, pid_data, PY_SYMBOLS_ENTRIES}, offsets::PythonOffsets}, metrics::Metrics};
 use crate::{vmlinux::task_struct};
 use crate::maps::PIDS;
 
@@ -34,8 +34,13 @@ const CPU_BITS: i32 = 10;
 const COUNTER_BITS: i32 = (31 - CPU_BITS);
 const MAX_SYMBOLS: i32 = (1 << COUNTER_BITS);
 
+/// Least recently used symbols make room for new ones, a symbol seen again gets a new id
 #[map(name="PY_SYMBOLS")]
-static SYMBOLS: HashMap<PythonSymbol, i32> = HashMap::with_max_entries(32768/* TODO: configurable */, 0);
+static SYMBOLS: LruHashMap<PythonSymbol, i32> = LruHashMap::with_max_entries(PY_SYMBOLS_ENTRIES/* TODO: configurable */, 0);
+
+/// Next counter of every cpu, see `get_symbol_id`
+#[map]
+static SYMBOL_COUNTER: PerCpuArray<u32> = PerCpuArray::with_max_entries(1, 0);
 
 #[map]
 static STATE_HEAP: PerCpuArray<SampleState> = PerCpuArray::with_max_entries(1, 0);
@@ -43,15 +48,40 @@ static STATE_HEAP: PerCpuArray<SampleState> = PerCpuArray::with_max_entries(1, 0
 #[map]
 static EVENTS: PerfEventArray<PythonStack> = PerfEventArray::new(0);
 
+/// Id of `sym` in `PY_SYMBOLS`, the first cpu to see a symbol assigns its id
+pub(crate) fn get_symbol_id(sym: &PythonSymbol) -> Result<i32, Metrics> {
+    if let Some(id) = unsafe { SYMBOLS.get(sym) } {
+        return Ok(*id);
+    }
+
+    let counter = unsafe { &mut *SYMBOL_COUNTER.get_ptr_mut(0).ok_or(Metrics::ErrPy_CANT_ALLOC)? };
+    if *counter as i32 >= MAX_SYMBOLS {
+        return Err(Metrics::ErrPy_SYMBOLS_FULL);
+    }
+    let cpu = unsafe { bpf_get_smp_processor_id() } as i32;
+    let id = (cpu << COUNTER_BITS) | *counter as i32;
+
+    match SYMBOLS.insert(sym, &id, BPF_NOEXIST as u64) {
+        Ok(()) => {
+            *counter += 1;
+            Ok(id)
+        }
+        // another cpu got there first, or the map is full
+        Err(_) => unsafe { SYMBOLS.get(sym) }.copied().ok_or(Metrics::ErrPy_SYMBOLS_FULL),
+    }
+}
+
+/// Read the first chunk of the python stack of the current thread.
+/// Returns the frame to continue from in a tail call, 0 if there's none.
 #[inline(always)]
-pub(crate) fn sample_python<C: BpfContext>(ctx: &C, stack: &mut PythonStack) -> Result<(), Metrics> {
+pub(crate) fn sample_python<C: BpfContext>(ctx: &C, stack: &mut PythonStack) -> Result<usize, Metrics> {
     let task: *const task_struct = unsafe { bpf_get_current_task() as *const _ };
     let pid_tgid = get_pid_tgid();
     let proc_info = unsafe { &mut *PIDS.get_ptr_mut(&pid_tgid.pid()).ok_or(Metrics::ErrPy_NO_PID)? };
 
-    if !proc_info.runtime_type.is_python() {
-        return Ok(());
-    }
+    let Some(offsets) = proc_info.runtime_type.python_offsets() else {
+        return Ok(0);
+    };
 
     let pid_data = &mut proc_info.runtime_type.python_pid_data();
 
@@ -63,8 +93,8 @@ pub(crate) fn sample_python<C: BpfContext>(ctx: &C, stack: &mut PythonStack) ->
 
     stack.stack_status = StackStatus::STACK_STATUS_ERROR;
     stack.error_code = Metrics::ErrPy_NONE;
-
-    let offsets = proc_info.runtime_type.python_version().offsets();
+    stack.thread_state = 0;
+    stack.gil_holder = 0;
 
     if (pid_data.interp == 0) {
         // This is the first time we sample this process (or the GIL is still released).
@@ -113,11 +143,48 @@ pub(crate) fn sample_python<C: BpfContext>(ctx: &C, stack: &mut PythonStack) ->
     // Call get_thread_state to find the PyThreadState of this thread:
     state.get_thread_state_call_count = 0;
 
-    let frame_ptr = get_thread_state(ctx, state, &offsets)?;
+    let gil_holder = unsafe { get_gil_holder(pid_data, offsets) };
+    let frame_ptr = get_thread_state(ctx, state, offsets)?;
+    if let Ok(holder) = gil_holder {
+        stack.gil_holder = holder;
+        stack.thread_state = state.thread_state;
+    }
+
+    stack.frames_len = 0;
+    stack.stack_status = StackStatus::STACK_STATUS_TRUNCATED;
+    read_python_stack(ctx, stack, state, offsets, frame_ptr)
+}
 
-    unsafe { read_python_stack(ctx, stack, state, &offsets, frame_ptr) };
+/// Read the next chunk of a python stack started by `sample_python`
+#[inline(always)]
+pub(crate) fn continue_python<C: BpfContext>(ctx: &C, stack: &mut PythonStack, pid: u32, frame_ptr: usize) -> Result<usize, Metrics> {
+    let proc_info = unsafe { PIDS.get(&pid).ok_or(Metrics::ErrPy_NO_PID)? };
+    let offsets = proc_info.runtime_type.python_offsets().ok_or(Metrics::ErrPy_NO_PID)?;
+    let Some(buf_ptr) = STATE_HEAP.get_ptr_mut(0) else { return Err(Metrics::ErrPy_CANT_ALLOC); };
+    read_python_stack(ctx, stack, unsafe { &mut *buf_ptr }, offsets, frame_ptr)
+}
 
-    // event.error_code = Metrics::ErrPy_CALL_FAILED;
-    // EVENTS.output(ctx, &state.event, 0);
-    Ok(())
-}
\ No newline at end of file
+/// `PyThreadState` of the thread holding the GIL, 0 if it's released
+#[inline(always)]
+unsafe fn get_gil_holder(pid_data: &pid_data, offsets: &PythonOffsets) -> Result<usize, Metrics> {
+    let read = |ptr: usize| bpf_probe_read_user(ptr as *const usize).map_err(|_| Metrics::ErrPy_READ_FRAME);
+    // 3.6-, the current thread state is the GIL holder
+    if pid_data.globals._PyThreadState_Current != 0 {
+        return read(pid_data.globals._PyThreadState_Current);
+    }
+    // 3.7 to 3.11
+    if offsets.py_runtime_state.tstate_current != 0 {
+        return read(pid_data.globals._PyRuntime + offsets.py_runtime_state.tstate_current);
+    }
+    // 3.12+, the GIL belongs to the interpreter and remembers its last holder
+    if offsets.py_interpreter_state.gil == 0 {
+        return Err(Metrics::ErrPy_READ_FRAME);
+    }
+    let gil = read(pid_data.interp + offsets.py_interpreter_state.gil)?;
+    let locked: i32 = bpf_probe_read_user((gil + offsets.py_gil_runtime_state.locked) as *const _)
+        .map_err(|_| Metrics::ErrPy_READ_FRAME)?;
+    if locked == 0 {
+        return Ok(0);
+    }
+    read(gil + offsets.py_gil_runtime_state.last_holder)
+}
@@ -1,30 +1,77 @@
 use core::{mem::{size_of, self, transmute}};
 
-use aya_bpf::{BpfContext, helpers::{bpf_probe_read_user}, cty::c_void, memset};
+use aya_bpf::{BpfContext, helpers::{bpf_probe_read_user, bpf_probe_read_user_buf}, cty::c_void, memset};
 use aya_log_ebpf::info;
-use tail2_common::{python::{state::{PYTHON_STACK_FRAMES_PER_PROG, PythonSymbol, CLASS_NAME_LEN, FILE_NAME_LEN, FRAME_MAX_LEN, PythonStack}, offsets::PythonOffsets}, metrics::Metrics};
+use tail2_common::{python::{state::{PythonSymbol, CLASS_NAME_LEN, FILE_NAME_LEN, FRAME_MAX_LEN, PythonStack, StackStatus, UNKNOWN_SYMBOL_ID}, offsets::{FrameLayout, PythonOffsets}}, metrics::Metrics};
 
-use super::pyperf::SampleState;
+use crate::sample::incr_metric;
 
+use super::pyperf::{get_symbol_id, SampleState};
+
+/// `_PyInterpreterFrame.owner` of the shim frames 3.12 pushes when C calls into Python
+const FRAME_OWNED_BY_CSTACK: u8 = 3;
+
+/// Frames read per program, deeper stacks are continued in a tail call
+pub(crate) const PY_CHUNK: usize = 32;
+
+/// Read up to `PY_CHUNK` frames starting at `frame_ptr`.
+/// Returns the next frame if the stack goes on, 0 once it's complete or full.
 #[inline(always)]
-pub fn read_python_stack<C: BpfContext>(ctx: &C, stack: &mut PythonStack, state: &mut SampleState, offsets: &PythonOffsets, frame_ptr: usize) -> Result<(), Metrics> {
+pub fn read_python_stack<C: BpfContext>(ctx: &C, stack: &mut PythonStack, state: &mut SampleState, offsets: &PythonOffsets, frame_ptr: usize) -> Result<usize, Metrics> {
     let mut cur_frame = frame_ptr;
-    stack.frames_len = 0;
-    for _ in 0..PYTHON_STACK_FRAMES_PER_PROG {
-        unsafe { read_symbol(ctx, &offsets, cur_frame, &mut state.symbol)? };
-        if (stack.frames_len < FRAME_MAX_LEN) {
-            stack.frames[stack.frames_len].copy(&mut state.symbol);
-            stack.frames_len += 1;
+    for _ in 0..PY_CHUNK {
+        let n = stack.frames_len;
+        if n >= FRAME_MAX_LEN {
+            return Ok(0);
+        }
+        if unsafe { is_shim(offsets, cur_frame)? } {
+            // the call of the eval loop that pushed the shim ran the frames since the last one
+            if n > 0 {
+                stack.eval_entry[n - 1] = true;
+            }
+        } else {
+            let code_ptr = unsafe { read_symbol(ctx, &offsets, cur_frame, &mut state.symbol)? };
+            // a full symbol map costs the name of the frame, not the stack
+            stack.frames[n] = get_symbol_id(&state.symbol).unwrap_or_else(|e| {
+                incr_metric(e);
+                UNKNOWN_SYMBOL_ID
+            });
+            stack.lasti[n] = unsafe { read_lasti(offsets, cur_frame, code_ptr) };
+            stack.code[n] = code_ptr;
+            stack.eval_entry[n] = unsafe { is_eval_entry(offsets, cur_frame) };
+            stack.frames_len = n + 1;
         }
 
-        // read next PyFrameObject pointer, update in place
-        cur_frame = unsafe { read(cur_frame + offsets.py_frame_object.f_back)? };
+        // read next frame pointer, update in place
+        cur_frame = unsafe { read(cur_frame + offsets.frame_back())? };
         if cur_frame == 0 {
-            break;
+            stack.stack_status = StackStatus::STACK_STATUS_COMPLETE;
+            return Ok(0);
         }
     }
 
-    Ok(())
+    Ok(cur_frame)
+}
+
+/// Shim frames have no code object worth reporting
+#[inline(always)]
+unsafe fn is_shim(offsets: &PythonOffsets, frame: usize) -> Result<bool, Metrics> {
+    if offsets.frames != FrameLayout::InterpreterFrameShims {
+        return Ok(false);
+    }
+    let owner: u8 = read(frame + offsets.py_interpreter_frame.owner)?;
+    Ok(owner == FRAME_OWNED_BY_CSTACK)
+}
+
+/// Whether the eval loop started its call with this frame, see `PythonStack::eval_entry`.
+/// 3.12 marks the entries with shims instead.
+#[inline(always)]
+unsafe fn is_eval_entry(offsets: &PythonOffsets, frame: usize) -> bool {
+    match offsets.frames {
+        FrameLayout::FrameObject => true,
+        FrameLayout::InterpreterFrame => read::<u8>(frame + offsets.py_interpreter_frame.is_entry).map_or(false, |e| e != 0),
+        FrameLayout::InterpreterFrameShims => false,
+    }
 }
 
 #[inline(always)]
@@ -33,27 +80,40 @@ unsafe fn read<T>(ptr: usize) -> Result<T, Metrics> {
 }
 
 #[inline(always)]
-pub unsafe fn read_symbol<C: BpfContext>(ctx: &C, offsets: &PythonOffsets, frame: usize, sym: &mut PythonSymbol) -> Result<(), Metrics> {
-    let code_ptr: usize = read(frame + offsets.py_frame_object.f_code).unwrap_or_default();
+pub unsafe fn read_symbol<C: BpfContext>(ctx: &C, offsets: &PythonOffsets, frame: usize, sym: &mut PythonSymbol) -> Result<usize, Metrics> {
+    let code_ptr: usize = read(frame + offsets.frame_code()).unwrap_or_default();
     if (code_ptr == 0) {
         return Err(Metrics::ErrPy_FRAME_CODE_IS_NULL);
     }
     sym.lineno = read(code_ptr + offsets.py_code_object.co_firstlineno)?;
-    // tracing::info!(ctx, "lineno: {}", sym.lineno);
-    // get_classname(&offsets, frame, code_ptr, &mut sym.classname)?;
     let pystr_ptr: usize = read(code_ptr + offsets.py_code_object.co_filename)?;
-    // TODO: too big for stack
-    // sym.file = read(pystr_ptr + offsets.string.data)?;
-    // aya_bpf::helpers::gen::bpf_probe_read_user(
-    //     (&mut sym.file).as_mut_ptr() as *mut c_void,
-    //     mem::size_of::<[u8; FILE_NAME_LEN]>() as u32,
-    //     (pystr_ptr + offsets.string.data) as *const c_void,
-    // );
-
-    // read PyCodeObject's name into symbol
-    let pystr_ptr: usize = read(code_ptr + offsets.py_code_object.co_name)?;
+    // straight into the symbol, it's too big for the stack
+    bpf_probe_read_user_buf((pystr_ptr + offsets.string.data) as *const u8, &mut sym.file)
+        .map_err(|_| Metrics::ErrPy_READ_FRAME)?;
+
+    // the qualified name has the class in it, before 3.11 guess the class from the first argument
+    sym.classname = [0; CLASS_NAME_LEN];
+    let name_offset = if offsets.frames == FrameLayout::FrameObject {
+        let _ = get_classname(offsets, frame, code_ptr, &mut sym.classname);
+        offsets.py_code_object.co_name
+    } else {
+        offsets.py_code_object.co_qualname
+    };
+    let pystr_ptr: usize = read(code_ptr + name_offset)?;
     sym.name = read(pystr_ptr + offsets.string.data)?;
-    Ok(())
+    Ok(code_ptr)
+}
+
+/// Last instruction of a frame, see `PythonStack::lasti`
+#[inline(always)]
+unsafe fn read_lasti(offsets: &PythonOffsets, frame: usize, code_ptr: usize) -> i32 {
+    if offsets.frames == FrameLayout::FrameObject {
+        return read(frame + offsets.py_frame_object.f_lasti).unwrap_or(-1);
+    }
+    match read::<usize>(frame + offsets.py_interpreter_frame.prev_instr) {
+        Ok(instr) => (instr as i64 - (code_ptr + offsets.py_code_object.co_code_adaptive) as i64) as i32,
+        Err(_) => -1,
+    }
 }
 
 #[inline(always)]
@@ -75,7 +135,7 @@ unsafe fn get_classname(offsets: &PythonOffsets, cur_frame: usize, code_ptr: usi
     }
     // Read class name from $frame->f_localsplus[0]->ob_type->tp_name.
     // read f_localsplus[0]:
-    let mut tmp: usize = read(cur_frame + offsets.py_frame_object.f_localsplus)?;
+    let mut tmp: usize = read(cur_frame + offsets.frame_localsplus())?;
     if (tmp == 0) {
         // self/cls is a cellvar, deleted, or not an argument. tough luck :/
         return Err(Metrics::ErrPy_FIRST_ARG_NOT_FOUND);
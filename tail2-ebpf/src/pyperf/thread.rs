@@ -1,6 +1,6 @@
 use aya_bpf::{helpers::{bpf_probe_read_user, bpf_probe_read, bpf_get_smp_processor_id}, BpfContext};
 use aya_log_ebpf::{info, error};
-use tail2_common::{python::{state::pthreads_impl, offsets::PythonOffsets}, metrics::Metrics};
+use tail2_common::{python::{state::pthreads_impl, offsets::{FrameLayout, PythonOffsets}}, metrics::Metrics};
 
 use crate::vmlinux::task_struct;
 
@@ -53,8 +53,14 @@ pub fn get_thread_state<C: BpfContext>(ctx: &C, state: &mut SampleState, offsets
         }
     }
 
-    // Get pointer to top frame from PyThreadState
-    let frame_ptr = unsafe { bpf_probe_read_user( (state.thread_state + offsets.py_thread_state.frame) as *const _) };
+    // Get pointer to top frame from PyThreadState, 3.11+ goes through the current _PyCFrame
+    let frame_ptr = match offsets.frames {
+        FrameLayout::FrameObject => unsafe { bpf_probe_read_user((state.thread_state + offsets.py_thread_state.frame) as *const _) },
+        _ => match unsafe { bpf_probe_read_user::<usize>((state.thread_state + offsets.py_thread_state.cframe) as *const _) } {
+            Ok(0) | Err(_) => return Err(Metrics::ErrPy_EMPTY_STACK),
+            Ok(cframe) => unsafe { bpf_probe_read_user((cframe + offsets.py_cframe.current_frame) as *const _) },
+        },
+    };
     match frame_ptr {
         Ok(0) | Err(_) => Err(Metrics::ErrPy_EMPTY_STACK),
         Ok(f) => Ok(f),
@@ -1,60 +1,190 @@
 use aya_bpf::{
-    macros::{uprobe, perf_event},
-    programs::{ProbeContext, PerfEventContext},
-    bindings::bpf_pidns_info, BpfContext
+    macros::{uprobe, kprobe, perf_event, tracepoint},
+    programs::{ProbeContext, PerfEventContext, TracePointContext},
+    bindings::bpf_pidns_info, BpfContext,
+    helpers::{bpf_get_current_cgroup_id, bpf_get_current_comm, bpf_get_smp_processor_id, bpf_ktime_get_ns},
 };
 use aya_log_ebpf::info;
-use tail2_common::{NativeStack, python::state::PythonStack, pidtgid::PidTgid, metrics::Metrics};
-use crate::{pyperf::pyperf::sample_python, user::sample_user, helpers::get_pid_tgid, kernel::sample_kernel, tracemgmt::{pid_info_exists, report_new_pid}, maps::{METRICS, STACKS, STACK_BUF}};
-
-#[uprobe(name="malloc_enter_0")] fn malloc_enter_0(ctx: ProbeContext) { /* let sz = ctx.arg(0).unwrap(); */ sample(&ctx, 0); }
-#[uprobe(name="malloc_enter_1")] fn malloc_enter_1(ctx: ProbeContext) { /* let sz = ctx.arg(0).unwrap(); */ sample(&ctx, 1); }
-#[uprobe(name="malloc_enter_2")] fn malloc_enter_2(ctx: ProbeContext) { /* let sz = ctx.arg(0).unwrap(); */ sample(&ctx, 2); }
-#[uprobe(name="malloc_enter_3")] fn malloc_enter_3(ctx: ProbeContext) { /* let sz = ctx.arg(0).unwrap(); */ sample(&ctx, 3); }
-#[uprobe(name="malloc_enter_4")] fn malloc_enter_4(ctx: ProbeContext) { /* let sz = ctx.arg(0).unwrap(); */ sample(&ctx, 4); }
-
-#[perf_event(name="capture_stack_0")] fn capture_stack_0(ctx: PerfEventContext) { sample(&ctx, 0); }
-#[perf_event(name="capture_stack_1")] fn capture_stack_1(ctx: PerfEventContext) { sample(&ctx, 1); }
-#[perf_event(name="capture_stack_2")] fn capture_stack_2(ctx: PerfEventContext) { sample(&ctx, 2); }
-#[perf_event(name="capture_stack_3")] fn capture_stack_3(ctx: PerfEventContext) { sample(&ctx, 3); }
-#[perf_event(name="capture_stack_4")] fn capture_stack_4(ctx: PerfEventContext) { sample(&ctx, 4); }
-
-fn sample<C: BpfContext>(ctx: &C, idx: usize) {
-    if let Err(e) = sample_inner(ctx, idx) {
+use tail2_common::{ConfigMapKey, NativeStack, scope::COMM_LEN, python::state::PythonStack, pidtgid::PidTgid, metrics::Metrics, bpf_sample::{BpfSample, SampleKind}};
+use crate::{aggregate::{aggregate, is_aggregating}, pyperf::pyperf::{continue_python, sample_python}, user::{start_unwind, continue_unwind, Unwind, UnwindDone, UnwindState}, helpers::{get_pid_tgid, in_scope, probe_id}, kernel::sample_kernel, tracemgmt::{pid_info_exists, report_new_pid}, maps::{CONFIG, METRICS, OFFCPU_START, OFFCPU_STACKS, STACKS, STACKS_RING, STACK_BUF, UNWIND_PROGS, UNWIND_STATE, VALUE_ARG}};
+
+#[uprobe(name="uprobe_enter")] fn uprobe_enter(ctx: ProbeContext) { uprobe_sample(&ctx, probe_id(&ctx)); }
+#[perf_event(name="capture_stack")] fn capture_stack(ctx: PerfEventContext) { scoped_sample(&ctx, probe_id(&ctx)); }
+#[kprobe(name="kprobe_enter")] fn kprobe_enter(ctx: ProbeContext) { scoped_sample(&ctx, probe_id(&ctx)); }
+#[tracepoint(name="tracepoint_enter")] fn tracepoint_enter(ctx: TracePointContext) { scoped_sample(&ctx, probe_id(&ctx)); }
+
+#[perf_event(name="unwind_perf")] fn unwind_perf(ctx: PerfEventContext) { continue_sample(&ctx); }
+#[kprobe(name="unwind_probe")] fn unwind_probe(ctx: ProbeContext) { continue_sample(&ctx); }
+#[tracepoint(name="unwind_tp")] fn unwind_tp(ctx: TracePointContext) { continue_sample(&ctx); }
+
+/// Program types with a continuation in `UNWIND_PROGS`, a tail call can only
+/// jump to a program of the same type
+pub(crate) trait Continue: BpfContext {
+    const UNWIND_PROG: u32;
+}
+
+impl Continue for PerfEventContext {
+    const UNWIND_PROG: u32 = 0;
+}
+
+/// uprobes and uretprobes are kprobe programs too
+impl Continue for ProbeContext {
+    const UNWIND_PROG: u32 = 1;
+}
+
+impl Continue for TracePointContext {
+    const UNWIND_PROG: u32 = 2;
+}
+
+/// kernel probes fire in every process and cgroup or name scopes can't be expressed
+/// on attach, so the scope is checked here
+fn scoped_sample<C: Continue>(ctx: &C, probe_id: u32) {
+    if in_scope(probe_id, get_pid_tgid()) {
+        sample(ctx, probe_id);
+    }
+}
+
+/// capture the probe argument configured in `VALUE_ARG` along with the stacks
+fn uprobe_sample(ctx: &ProbeContext, probe_id: u32) {
+    if !in_scope(probe_id, get_pid_tgid()) {
+        return;
+    }
+    let value = unsafe { VALUE_ARG.get(&probe_id) }.and_then(|arg| ctx.arg::<u64>(*arg as usize));
+    sample_value(ctx, probe_id, value);
+}
+
+fn sample<C: Continue>(ctx: &C, probe_id: u32) {
+    sample_value(ctx, probe_id, None);
+}
+
+fn sample_value<C: Continue>(ctx: &C, probe_id: u32, value: Option<u64>) {
+    if let Err(e) = sample_inner(ctx, probe_id, value) {
         incr_metric(e);
     }
 }
 
-fn sample_inner<C: BpfContext>(ctx: &C, idx: usize) -> Result<(), Metrics> {
+fn sample_inner<C: Continue>(ctx: &C, probe_id: u32, value: Option<u64>) -> Result<(), Metrics> {
     let sample = unsafe { &mut *(STACK_BUF.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    fill_sample(ctx, sample, probe_id)?;
+    sample.weight = 1;
+    sample.value = value;
+    sample.kind = SampleKind::OnCpu;
+
+    let done = if is_aggregating(probe_id) { UnwindDone::Aggregate } else { UnwindDone::Output };
+    finish_sample(ctx, sample, done, 0, 0)
+}
+
+/// Capture the python and kernel stacks of the current task into `sample`,
+/// the user stack is unwound by `finish_sample`
+pub(crate) fn fill_sample<C: BpfContext>(ctx: &C, sample: &mut BpfSample, probe_id: u32) -> Result<(), Metrics> {
     let pid_tgid: PidTgid = get_pid_tgid();
     if !pid_info_exists(pid_tgid.pid()) {
         return report_new_pid(ctx, pid_tgid.pid());
     }
 
     sample.pidtgid = pid_tgid;
-    sample.idx = idx;
+    sample.probe_id = probe_id;
+    sample.value = None;
+    sample.ktime_ns = unsafe { bpf_ktime_get_ns() };
+    sample.cpu = unsafe { bpf_get_smp_processor_id() };
+    sample.comm = bpf_get_current_comm().unwrap_or([0; COMM_LEN]);
+    sample.cgroup_id = unsafe { bpf_get_current_cgroup_id() };
 
     sample.native_stack = NativeStack::uninit();
-    sample_user(ctx, &mut sample.native_stack, pid_tgid.pid())?;
 
+    // deep python stacks are read on in the continuations of `finish_sample`
+    let state = unsafe { &mut *(UNWIND_STATE.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    state.py_frame = 0;
     sample.python_stack = Some(PythonStack::uninit());
     let stack = sample.python_stack.as_mut().ok_or(Metrics::ErrPy_NoStack)?;
-    let result = sample_python(ctx, stack);
-    if let Err(e) = result {
-        incr_metric(e);
+    match sample_python(ctx, stack) {
+        Ok(frame) => state.py_frame = frame,
+        Err(e) => incr_metric(e),
     }
 
     sample.kernel_stack_id = sample_kernel(ctx);
 
-    unsafe {
-        STACKS.output(ctx, sample, 0);
-        incr_metric(Metrics::SentStackCount);
+    Ok(())
+}
+
+/// Unwind the user stack of `sample`, which must live in `STACK_BUF`, and hand it to
+/// `done`. Stacks deeper than one chunk are finished by tail calls, so nothing may
+/// follow this call.
+pub(crate) fn finish_sample<C: Continue>(ctx: &C, sample: &mut BpfSample, done: UnwindDone, key: u64, ts: u64) -> Result<(), Metrics> {
+    let state = unsafe { &mut *(UNWIND_STATE.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    state.done = done;
+    state.key = key;
+    state.ts = ts;
+
+    let unwind = start_unwind(state, &mut sample.native_stack, sample.pidtgid.pid())?;
+    step(ctx, sample, state, unwind)
+}
+
+fn continue_sample<C: Continue>(ctx: &C) {
+    if let Err(e) = continue_sample_inner(ctx) {
+        incr_metric(e);
+    }
+}
+
+/// The python stack is read first, then the native one
+fn continue_sample_inner<C: Continue>(ctx: &C) -> Result<(), Metrics> {
+    let sample = unsafe { &mut *(STACK_BUF.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    let state = unsafe { &mut *(UNWIND_STATE.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+
+    let unwind = if state.py_frame != 0 {
+        let pid = sample.pidtgid.pid();
+        state.py_frame = match sample.python_stack.as_mut() {
+            Some(stack) => continue_python(ctx, stack, pid, state.py_frame).unwrap_or_else(|e| {
+                incr_metric(e);
+                0
+            }),
+            None => 0,
+        };
+        if state.native_pending { Unwind::Pending } else { Unwind::Done }
+    } else {
+        continue_unwind(state, &mut sample.native_stack)?
+    };
+    step(ctx, sample, state, unwind)
+}
+
+fn step<C: Continue>(ctx: &C, sample: &mut BpfSample, state: &mut UnwindState, unwind: Unwind) -> Result<(), Metrics> {
+    state.native_pending = unwind == Unwind::Pending;
+    if state.native_pending || state.py_frame != 0 {
+        // only returns if the tail call failed, the python stack stays truncated
+        let _ = unsafe { UNWIND_PROGS.tail_call(ctx, C::UNWIND_PROG) };
+        incr_metric(Metrics::ErrSample_TailCall);
+        if state.native_pending {
+            sample.native_stack.unwind_success = Some(state.next_frame());
+            sample.native_stack.truncated = true;
+        }
+    }
+
+    match state.done {
+        UnwindDone::Output => output_sample(ctx, sample),
+        UnwindDone::OffCpu => {
+            unsafe { OFFCPU_STACKS.insert(&state.key, sample, 0) }.map_err(|_| Metrics::ErrOffCpu_CantStore)?;
+            OFFCPU_START.insert(&state.key, &state.ts, 0).map_err(|_| Metrics::ErrOffCpu_CantStore)?;
+        }
+        UnwindDone::Aggregate => aggregate(ctx, sample)?,
     }
 
     Ok(())
 }
 
+/// Send a finished sample through whichever transport user space reads
+pub(crate) fn output_sample<C: BpfContext>(ctx: &C, sample: &BpfSample) {
+    let ringbuf = unsafe { CONFIG.get(&(ConfigMapKey::RINGBUF as u32)) }.copied().unwrap_or(0) == 1;
+    if !ringbuf {
+        // perf buffer overflows are only visible to user space, which counts them
+        unsafe { STACKS.output(ctx, sample, 0) };
+        incr_metric(Metrics::SentStackCount);
+    } else if STACKS_RING.output(sample, 0).is_ok() {
+        incr_metric(Metrics::SentStackCount);
+    } else {
+        incr_metric(Metrics::ErrSend_RingFull);
+    }
+}
+
 pub fn incr_metric(key: Metrics) {
     let cnt = unsafe { METRICS.get(&(key as u32)) }.copied().unwrap_or(0);
     let _ = METRICS.insert(&(key as u32), &(cnt+1), 0);
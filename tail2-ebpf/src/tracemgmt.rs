@@ -26,7 +26,7 @@ pub fn report_new_pid<C: BpfContext>(ctx: &C, pid: u32) -> Result<(), Metrics> {
     }
 }
 
-fn report_pid_event<C: BpfContext>(ctx: &C, pid: u32, metrics: Metrics) {
+pub fn report_pid_event<C: BpfContext>(ctx: &C, pid: u32, metrics: Metrics) {
     let data = PidEvent {
         pid,
         event_type: metrics,
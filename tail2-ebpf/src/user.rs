@@ -1,9 +1,9 @@
 use aya_bpf::{helpers::{bpf_probe_read_user, bpf_get_current_task, bpf_task_pt_regs, bpf_get_current_task_btf}, BpfContext, bindings::bpf_pidns_info};
 use aya_log_ebpf::{error, info};
-use tail2_common::{NativeStack, ConfigMapKey, pidtgid::PidTgid, procinfo::{ProcInfo, MAX_ROWS_PER_PROC}, native::unwinding::{aarch64::{unwind_rule::UnwindRuleAarch64, unwindregs::UnwindRegsAarch64}, x86_64::unwind_rule::UnwindRuleX86_64}, MAX_USER_STACK, bpf_sample::BpfSample, native::unwinding::x86_64::unwindregs::UnwindRegsX86_64, metrics::Metrics};
+use tail2_common::{NativeStack, ConfigMapKey, pidtgid::PidTgid, procinfo::{ProcInfo, ModuleMapping, MAX_MAPPINGS_PER_PROC, MAX_SEARCH_STEPS}, native::unwinding::{aarch64::{unwind_rule::UnwindRuleAarch64, unwindregs::UnwindRegsAarch64}, x86_64::unwind_rule::UnwindRuleX86_64}, MAX_USER_STACK, UNWIND_CHUNK, bpf_sample::BpfSample, native::unwinding::x86_64::unwindregs::UnwindRegsX86_64, metrics::Metrics};
 use aya_bpf::bindings::pt_regs;
 
-use crate::maps::PIDS;
+use crate::{maps::{PIDS, UNWIND_ROWS, UNWIND_STATE}, sample::incr_metric};
 
 #[cfg(feature = "x86_64")]
 type UnwindRegs = UnwindRegsX86_64;
@@ -16,26 +16,100 @@ type UnwindRule = UnwindRuleX86_64;
 type UnwindRule = UnwindRuleAarch64;
 
 
-pub(crate) fn sample_user<'a, 'b, C: BpfContext>(ctx: &'a C, st: &mut NativeStack, pid: u32) -> Result<(), Metrics> {
-    /* unwind user stack */
+/// Unwinder position, carried across tail calls in `UNWIND_STATE`
+#[derive(Clone, Copy)]
+pub(crate) struct UnwindState {
+    regs: UnwindRegs,
+    frame: usize,
+    /// index of the next frame to unwind
+    next: usize,
+    pid: u32,
+    /// what to do with the sample once its stack is complete
+    pub done: UnwindDone,
+    /// key and timestamp for `UnwindDone::OffCpu`
+    pub key: u64,
+    pub ts: u64,
+    /// python frame to continue from, 0 once the python stack is read
+    pub py_frame: usize,
+    /// the native stack goes on after the python stack is read
+    pub native_pending: bool,
+}
+
+impl UnwindState {
+    /// frames unwound so far
+    pub(crate) fn next_frame(&self) -> usize {
+        self.next
+    }
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy)]
+pub(crate) enum UnwindDone {
+    /// send `STACK_BUF` to user space
+    Output = 0,
+    /// park `STACK_BUF` in `OFFCPU_STACKS` until the task is switched back in
+    OffCpu = 1,
+    /// count the stack in the active `AGG_STACKS_*` map
+    Aggregate = 2,
+}
+
+#[derive(PartialEq, Eq)]
+pub(crate) enum Unwind {
+    Done,
+    /// `UNWIND_CHUNK` frames were unwound and the stack goes on
+    Pending,
+}
+
+/// Unwind the user stack of the current task up to `UNWIND_CHUNK` frames, for stacks
+/// that aren't continued in a tail call
+pub(crate) fn sample_user<C: BpfContext>(ctx: &C, st: &mut NativeStack, pid: u32) -> Result<(), Metrics> {
+    let state = unsafe { &mut *(UNWIND_STATE.get_ptr_mut(0).ok_or(Metrics::ErrSample_CantAlloc)?) };
+    if start_unwind(state, st, pid)? == Unwind::Pending {
+        st.unwind_success = Some(state.next);
+        st.truncated = true;
+    }
+
+    Ok(())
+}
+
+/// Read the registers of the current task and unwind the first chunk
+pub(crate) fn start_unwind(state: &mut UnwindState, st: &mut NativeStack, pid: u32) -> Result<Unwind, Metrics> {
     let task = unsafe { bpf_get_current_task_btf()};
     let regs = unsafe { bpf_task_pt_regs(task) } as *const _;
     // let regs = ctx.as_ptr() as *const _;
     let pc = get_pc(regs);
-    let mut regs = get_regs(regs);
-    unwind(ctx, st, pc, &mut regs, pid)?;
+    state.regs = get_regs(regs);
+    state.frame = pc;
+    state.next = 1;
+    state.pid = pid;
 
-    Ok(())
+    st.native_stack[0] = pc;
+    st.unwind_success = None;
+    st.truncated = false;
+    st.unwind_error = None;
+    unwind_chunk(state, st)
+}
+
+/// Pick up where the previous program stopped
+pub(crate) fn continue_unwind(state: &mut UnwindState, st: &mut NativeStack) -> Result<Unwind, Metrics> {
+    unwind_chunk(state, st)
+}
+
+/// A failure ends the stack at the last frame unwound instead of dropping the sample
+fn unwind_chunk(state: &mut UnwindState, st: &mut NativeStack) -> Result<Unwind, Metrics> {
+    match unwind_frames(state, st) {
+        Ok(unwind) => Ok(unwind),
+        Err(e) => {
+            incr_metric(e);
+            st.unwind_success = Some(state.next);
+            st.unwind_error = Some(e);
+            Ok(Unwind::Done)
+        }
+    }
 }
 
-fn unwind<C: BpfContext>(
-    ctx: &C,
-    st: &mut NativeStack,
-    pc: usize,
-    regs: &mut UnwindRegs,
-    pid: u32
-) -> Result<(), Metrics> {
-    let proc_info = unsafe { PIDS.get(&pid).ok_or(Metrics::ErrSample_NoPidInfo)? };
+fn unwind_frames(state: &mut UnwindState, st: &mut NativeStack) -> Result<Unwind, Metrics> {
+    let proc_info = unsafe { PIDS.get(&state.pid).ok_or(Metrics::ErrSample_NoPidInfo)? };
 
     let mut read_stack = |addr: u64| {
         let ret = unsafe { bpf_probe_read_user(addr as *const u64) };
@@ -43,54 +117,70 @@ fn unwind<C: BpfContext>(
         ret.map_err(|_|())
     };
 
-    let mut frame = pc;
-    let mut is_first_frame = true;
-    st.native_stack[0] = pc;
-    for i in 1..MAX_USER_STACK {
-        let idx = binary_search(proc_info.rows.as_slice(), frame, proc_info.rows_len)?;
-        let rule = proc_info.rows[idx].1;
+    for _ in 0..UNWIND_CHUNK {
+        let i = state.next;
+        if i >= MAX_USER_STACK {
+            st.unwind_success = Some(MAX_USER_STACK);
+            st.truncated = true;
+            return Ok(Unwind::Done);
+        }
+
+        let mapping = find_mapping(proc_info, state.frame)?;
+        let idx = binary_search(mapping, state.frame - mapping.avma_start)?;
+        let rule = UNWIND_ROWS.get(idx).ok_or(Metrics::ErrSample_BinarySearch)?.rule;
         // tracing::info!(ctx, "rule: {}", rule.as_num());
 
-        match rule.exec(is_first_frame, regs, &mut read_stack) {
+        match rule.exec(i == 1, &mut state.regs, &mut read_stack) {
             Ok(Some(f)) => {
                 st.native_stack[i] = f as usize;
-                frame = f as usize;
+                state.frame = f as usize;
             }
             Ok(None) => {
                 st.unwind_success = Some(i);
-                break;
+                return Ok(Unwind::Done);
             },
             Err(e) => {
                 // tracing::error!(ctx, "error unwinding");
-                st.unwind_success = None;
                 return Err(e.into());
             }
         }
-        is_first_frame = false;
+        state.next = i + 1;
     }
 
-    Ok(())
+    Ok(Unwind::Pending)
+}
+
+/// first level of the lookup: the executable mapping the pc is in
+fn find_mapping(proc_info: &ProcInfo, pc: usize) -> Result<&ModuleMapping, Metrics> {
+    for i in 0..MAX_MAPPINGS_PER_PROC {
+        if i >= proc_info.mappings_len {
+            break;
+        }
+        let mapping = &proc_info.mappings[i];
+        if mapping.avma_start <= pc && pc < mapping.avma_end {
+            return Ok(mapping);
+        }
+    }
+
+    Err(Metrics::ErrSample_NoMapping)
 }
 
 #[inline(always)]
-/// binary search routine that passes the bpf verifier
-fn binary_search(rows: &[(usize, UnwindRule)], pc: usize, right: usize) -> Result<usize, Metrics> {
+/// second level of the lookup: binary search the table of the module in the shared
+/// row pool for the row covering `pc`, in a way that passes the bpf verifier
+fn binary_search(mapping: &ModuleMapping, pc: usize) -> Result<u32, Metrics> {
     let mut left = 0;
-    let mut right = right;
+    let mut right = mapping.rows_len;
     let mut found = 0;
-    for _ in 0..20 {
+    for _ in 0..MAX_SEARCH_STEPS {
         if left >= right {
-          return Ok(found);
+          return Ok(mapping.rows_start + found);
         }
       
         let mid = (left + right) / 2;
 
-        // appease the verifier
-        if mid >= MAX_ROWS_PER_PROC {
-            return Err(Metrics::ErrSample_BinarySearch);
-        }
-
-        if rows[mid].0 <= pc {
+        let row = UNWIND_ROWS.get(mapping.rows_start + mid).ok_or(Metrics::ErrSample_BinarySearch)?;
+        if row.pc <= pc {
           found = mid;
           left = mid + 1;
         } else {
@@ -1,7 +1,7 @@
 use axum::{extract::{State, Query}, response::IntoResponse, http::HeaderMap};
 use reqwest::header;
 use serde::{Serialize, Deserialize};
-use tail2::{calltree::{serialize::Node, CodeType}};
+use tail2::{calltree::{serialize::Node, CodeType}, probes::{Probe, memory::snapshot_range}};
 use axum::response::sse::{Event, Sse};
 use tracing::info;
 use std::{convert::Infallible, time::SystemTime};
@@ -16,12 +16,15 @@ pub struct CallTreeParams {
     host_name: Option<String>,
     db: Option<String>,
     filter: Option<String>,
+    /// render by captured value, e.g. bytes allocated, instead of by sample count
+    by_value: Option<bool>,
 }
 
 // TODO: refactor so we only need db instead of probe + host_name
 pub(crate) async fn calltree<'a>(State(state): State<ServerState>, Query(params): Query<CallTreeParams>) -> String {
     let t = SystemTime::now();
 
+    let probe: Option<Probe> = params.probe.as_ref().map(|p| serde_json::from_str(p).unwrap());
     let db = match params.db {
         Some(db) => {
             let manager = state.manager.lock().await;
@@ -29,11 +32,10 @@ pub(crate) async fn calltree<'a>(State(state): State<ServerState>, Query(params)
         }
         None => {
             let agents = state.agents.lock().await;
-            let probe = serde_json::from_str(&params.probe.unwrap()).unwrap();
             let db = agents
                 .get(&params.host_name.unwrap()).unwrap()
                 .probes
-                .get(&probe).unwrap()
+                .get(probe.as_ref().unwrap()).unwrap()
                 .db
                 .clone();
                 drop(agents);
@@ -45,12 +47,18 @@ pub(crate) async fn calltree<'a>(State(state): State<ServerState>, Query(params)
         (Some(start), Some(end)) => (start, end),
         _ => {
             let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
-            let range = (now - 60 * 1000, now);
-            range
+            match probe {
+                // memory probes post snapshots, only the last one is outstanding
+                Some(Probe::Memory { .. }) => snapshot_range(now),
+                _ => (now - 60 * 1000, now),
+            }
         }
     };
     dbg!(range);
-    let calltree = db.tail2_db.lock().await.range_query(range).unwrap().calltree.unwrap_or_default();
+    let mut calltree = db.tail2_db.lock().await.range_query(range).unwrap().calltree.unwrap_or_default();
+    if params.by_value.unwrap_or(false) {
+        calltree = calltree.by_value();
+    }
 
     let symbols = &mut *state.symbols.lock().await;
     let modules = db.tail2_db.lock().await.modules();
@@ -22,8 +22,9 @@ pub(crate) async fn stack(State(state): State<ServerState>, var: Bytes) -> Resul
     let probe: Probe = serde_json::from_str(&batch.probe).unwrap();
 
     let agents = &mut *state.agents.lock().await;
-    let probe_state = agents
-        .get(&batch.hostname).unwrap()
+    let agent = agents.get_mut(&batch.hostname).unwrap();
+    agent.sample_loss = batch.sample_loss;
+    let probe_state = agent
         .probes
         .get(&probe).unwrap();
     let db = probe_state.db.clone();
@@ -38,8 +39,10 @@ pub(crate) async fn stack(State(state): State<ServerState>, var: Bytes) -> Resul
     for stack in batch.stacks {
         ts = ts.max(stack.ts_ms as i64);
         n += 1;
+        let weight = stack.weight;
+        let value = stack.value.unwrap_or(0);
         let unsym = stack.mix(&batch.modules, &mut *modules);
-        ct.merge(&UnsymbolizedCallTree::from_frames(&unsym));
+        ct.merge(&UnsymbolizedCallTree::from_weighted_frames(&unsym, weight, value));
     }
 
     let db_row = DbRow {
@@ -1,9 +1,10 @@
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
 use anyhow::Result;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHasher};
 use serde::{Serialize};
-use tail2::{client::ws_client::messages::AgentMessage, probes::Probe};
+use tail2::{client::ws_client::messages::AgentMessage, dto::SampleLoss, probes::Probe};
 use tail2_db::{manager::Db};
 use tail2_db::manager::Manager;
 use tail2_db::metadata::Metadata;
@@ -46,6 +47,9 @@ pub struct Tail2Agent {
 
     /// Whether the agent is halted.
     is_halted: bool,
+
+    /// Samples dropped before they reached the agent, as of its latest batch
+    pub sample_loss: SampleLoss,
 }
 
 impl Tail2Agent {
@@ -55,16 +59,33 @@ impl Tail2Agent {
             probes: FnvHashMap::default(),
             tx: Some(tx),
             is_halted: true,
+            sample_loss: SampleLoss::default(),
         }
     }
 
+    /// Each probe gets its own db, named after the agent and the probe.
+    /// The name is a file name, so anything but `[A-Za-z0-9_-]` is replaced and
+    /// a hash of the original name keeps e.g. `a/b` and `a:b` apart.
+    fn probe_metadata(&self, probe: &Probe) -> Metadata {
+        let name = format!("{}_{}", self.name, probe);
+        let mut hasher = FnvHasher::default();
+        name.hash(&mut hasher);
+        let sanitized: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let mut md = Metadata::empty(format!("{}_{:016x}", sanitized, hasher.finish()));
+        md.tags.insert("agent".to_string(), self.name.clone());
+        md.tags.insert("probe".to_string(), probe.to_string());
+        md
+    }
+
     pub async fn process(&mut self, diff: &AgentMessage, manager: Arc<Mutex<Manager>>) -> Result<()> {
         match diff {
             AgentMessage::AddProbe { probe } => {
                 self.is_halted = false;
                 let manager = &mut *manager.lock().await;
-                let mut md = Metadata::empty(self.name.clone());
-                md.tags.insert("agent".to_string(), self.name.clone());
+                let md = self.probe_metadata(probe);
                 let db = manager.create_db(&md).unwrap();
 
                 let info = self.probes
@@ -74,8 +95,7 @@ impl Tail2Agent {
             }
             AgentMessage::StopProbe { probe } => {
                 let manager = &mut *manager.lock().await;
-                let mut md = Metadata::empty(self.name.clone());
-                md.tags.insert("agent".to_string(), self.name.clone());
+                let md = self.probe_metadata(probe);
                 let db = manager.create_db(&md).unwrap();
 
                 let info = self.probes
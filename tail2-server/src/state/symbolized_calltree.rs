@@ -25,8 +25,10 @@ impl Default for SymbolizedCallTree {
 impl SymbolizedCallTree {
     pub fn add_stack_batch(&mut self, batch: StackBatchDto, symbols: &mut SymbolCache) {
         for stack in batch.stacks {
+            let weight = stack.weight;
+            let value = stack.value.unwrap_or(0);
             let unsym = stack.mix(&batch.modules, &mut self.modules);
-            let ct = UnsymbolizedCallTree::from_frames(&unsym);
+            let ct = UnsymbolizedCallTree::from_weighted_frames(&unsym, weight, value);
 
             let ct = ct.symbolize(symbols, &mut self.modules);
 
@@ -1,14 +1,16 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Result, Context};
 use tracing::info;
 
 use crate::{
     client::{run::{get_pid_child, run_until_exit, RunUntil}},
     processes::Processes,
-    Tail2, probes::{Scope, Probe}, symbolication::{module::Module, elf::SymbolCache},
+    Tail2, probes::{Scope, Probe, PerfEventKind, Sampling}, symbolication::{module::Module, elf::SymbolCache},
+    unwind_report::UnwindReport,
 };
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use tokio::sync::mpsc;
 
 #[derive(Debug, Parser)]
 pub struct Opt {
@@ -16,6 +18,36 @@ pub struct Opt {
     pub command: Option<Commands>,
 }
 
+/// Scopes other than a single pid, all filtered in the bpf programs
+#[derive(Args, Debug)]
+pub struct ScopeArgs {
+    /// comma separated pids to listen to
+    #[clap(long, value_delimiter = ',', conflicts_with_all = ["pid", "command", "cgroup", "process_name"])]
+    pids: Vec<u32>,
+    /// cgroup v2 to listen to, relative to /sys/fs/cgroup
+    #[clap(long, conflicts_with_all = ["pid", "command", "process_name"])]
+    cgroup: Option<String>,
+    /// listen to processes whose name starts with this
+    #[clap(long, conflicts_with_all = ["pid", "command"])]
+    process_name: Option<String>,
+    /// also listen to every process forked by --pid or --command, until that process exits
+    #[clap(long, conflicts_with_all = ["pids", "cgroup", "process_name"])]
+    follow: bool,
+}
+
+impl ScopeArgs {
+    fn scope(self, pid: Option<u32>) -> Scope {
+        match (pid, self.cgroup, self.process_name) {
+            (Some(pid), _, _) if self.follow => Scope::Descendants{pid},
+            (Some(pid), _, _) => Scope::Pid{pid},
+            (None, Some(path), _) => Scope::Cgroup{path},
+            (None, None, Some(pattern)) => Scope::ProcessName{pattern},
+            (None, None, None) if !self.pids.is_empty() => Scope::Pids{pids: self.pids},
+            (None, None, None) => Scope::SystemWide,
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Print Unwind table
@@ -24,7 +56,7 @@ pub enum Commands {
     Symbols { paths: Vec<String> },
     /// Print system information
     Processes {},
-    /// Sample callstacks based on elapsed CPU time
+    /// Sample callstacks based on elapsed CPU time, or another perf event
     Sample {
         /// Pid to listen to, if not supplied, listen for events system wide
         /// If it's 0, listen to the tail2 agent itself
@@ -33,9 +65,23 @@ pub enum Commands {
         /// launch child process with the command and attach to its pid
         #[clap(short, long)]
         command: Option<String>,
-        /// sample period
+        #[clap(flatten)]
+        scope: ScopeArgs,
+        /// event to sample on
+        #[clap(value_enum, default_value_t = PerfEventKind::TaskClock, long)]
+        event: PerfEventKind,
+        /// sample period, in number of events
         #[clap(default_value = "4000000", long)]
         period: u64,
+        /// sample frequency in Hz, overrides --period
+        #[clap(long)]
+        frequency: Option<u64>,
+        /// also sample blocked threads, weighting every sample by wall-clock time
+        #[clap(long)]
+        wall_clock: bool,
+        /// count unique stacks in the kernel instead of sending every sample
+        #[clap(long, conflicts_with = "wall_clock")]
+        aggregate: bool,
     },
     /// Attach to a userspace function, e.g. "libc:malloc"
     Uprobe {
@@ -45,10 +91,93 @@ pub enum Commands {
         /// launch child process with the command and attach to its pid
         #[clap(short, long)]
         command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
+        /// uprobe string in the form of "module:function", e.g. "libc:malloc"
+        #[clap(short, long)]
+        uprobe: String,
+        /// weigh samples by this argument, e.g. 0 for the size passed to malloc
+        #[clap(long)]
+        value_arg: Option<u32>,
+    },
+    /// Measure how long calls to a userspace function take, e.g. "libc:malloc"
+    Latency {
+        /// attach to pid
+        #[clap(short, long)]
+        pid: Option<u32>,
+        /// launch child process with the command and attach to its pid
+        #[clap(short, long)]
+        command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
         /// uprobe string in the form of "module:function", e.g. "libc:malloc"
         #[clap(short, long)]
         uprobe: String,
     },
+    /// Track bytes still outstanding per allocation stack to find leaks
+    Memory {
+        /// attach to pid
+        #[clap(short, long)]
+        pid: Option<u32>,
+        /// launch child process with the command and attach to its pid
+        #[clap(short, long)]
+        command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
+    },
+    /// Attach to a kernel function, e.g. "tcp_sendmsg"
+    Kprobe {
+        /// attach to pid
+        #[clap(short, long)]
+        pid: Option<u32>,
+        /// launch child process with the command and attach to its pid
+        #[clap(short, long)]
+        command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
+        /// kernel function name
+        #[clap(short, long)]
+        function: String,
+    },
+    /// Attach to a kernel tracepoint, e.g. "syscalls:sys_enter_write"
+    Tracepoint {
+        /// attach to pid
+        #[clap(short, long)]
+        pid: Option<u32>,
+        /// launch child process with the command and attach to its pid
+        #[clap(short, long)]
+        command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
+        /// tracepoint string in the form of "category:name"
+        #[clap(short, long)]
+        tracepoint: String,
+    },
+    /// Sample callstacks of threads blocked off cpu, weighted by time blocked
+    Offcpu {
+        /// Pid to listen to, if not supplied, listen for events system wide
+        #[clap(short, long)]
+        pid: Option<u32>,
+        /// launch child process with the command and attach to its pid
+        #[clap(short, long)]
+        command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
+    },
+    /// Sample on cpu time and report which modules the native unwinder fails in
+    UnwindReport {
+        /// Pid to listen to, if not supplied, listen for events system wide
+        #[clap(short, long)]
+        pid: Option<u32>,
+        /// launch child process with the command and attach to its pid
+        #[clap(short, long)]
+        command: Option<String>,
+        #[clap(flatten)]
+        scope: ScopeArgs,
+        /// sample period, in ns of cpu time
+        #[clap(default_value = "4000000", long)]
+        period: u64,
+    },
 }
 
 impl Commands {
@@ -78,20 +207,31 @@ impl Commands {
             }
             Commands::Sample {
                 pid,
+                event,
                 period,
+                frequency,
                 command,
+                scope,
+                wall_clock,
+                aggregate,
             } => {
                 let (pid, child) = get_pid_child(pid, command);
 
-                let probe = Arc::new(Probe::Perf{
-                    scope: match pid {
-                        Some(pid) => Scope::Pid {pid},
-                        None => Scope::SystemWide,
-                    },
-                    period,
+                let scope = scope.scope(pid);
+                let probe = Arc::new(if wall_clock {
+                    if event != PerfEventKind::TaskClock || frequency.is_some() {
+                        anyhow::bail!("--wall-clock only samples task clock by period");
+                    }
+                    Probe::WallClock{ scope, period }
+                } else {
+                    let sampling = match frequency {
+                        Some(hz) => Sampling::Frequency { hz },
+                        None => Sampling::Period { period },
+                    };
+                    Probe::Perf{ scope, event, sampling, aggregate }
                 });
 
-                let _attachment = probe.attach(&mut*t2.bpf.lock().await, &*t2.probes.lock().await).await?;
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
                 let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
                 let clis = Arc::clone(&t2.probes.lock().await.clients);
                 run_until_exit(t2.bpf, clis, run_until, None).await?;
@@ -100,21 +240,133 @@ impl Commands {
                 pid,
                 uprobe,
                 command,
+                scope,
+                value_arg,
             } => {
                 let (pid, child) = get_pid_child(pid, command);
                 let probe = Arc::new(Probe::Uprobe{
-                    scope: match pid {
-                        Some(pid) => Scope::Pid{pid},
-                        None => Scope::SystemWide,
-                    },
+                    scope: scope.scope(pid),
                     uprobe,
+                    value_arg,
                 });
 
-                let _attachment = probe.attach(&mut *t2.bpf.lock().await, &*t2.probes.lock().await).await?;
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
                 let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
                 let clis = Arc::clone(&t2.probes.lock().await.clients);
                 run_until_exit(t2.bpf, clis, run_until, None).await?;
             }
+            Commands::Latency {
+                pid,
+                uprobe,
+                command,
+                scope,
+            } => {
+                let (pid, child) = get_pid_child(pid, command);
+                let probe = Arc::new(Probe::Latency{
+                    scope: scope.scope(pid),
+                    uprobe,
+                });
+
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
+                let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
+                let clis = Arc::clone(&t2.probes.lock().await.clients);
+                run_until_exit(t2.bpf, clis, run_until, None).await?;
+            }
+            Commands::Memory {
+                pid,
+                command,
+                scope,
+            } => {
+                let (pid, child) = get_pid_child(pid, command);
+                let probe = Arc::new(Probe::Memory{
+                    scope: scope.scope(pid),
+                });
+
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
+                let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
+                let clis = Arc::clone(&t2.probes.lock().await.clients);
+                run_until_exit(t2.bpf, clis, run_until, None).await?;
+            }
+            Commands::Kprobe {
+                pid,
+                command,
+                scope,
+                function,
+            } => {
+                let (pid, child) = get_pid_child(pid, command);
+                let probe = Arc::new(Probe::Kprobe{
+                    scope: scope.scope(pid),
+                    function,
+                });
+
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
+                let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
+                let clis = Arc::clone(&t2.probes.lock().await.clients);
+                run_until_exit(t2.bpf, clis, run_until, None).await?;
+            }
+            Commands::Tracepoint {
+                pid,
+                command,
+                scope,
+                tracepoint,
+            } => {
+                let (pid, child) = get_pid_child(pid, command);
+                let (category, name) = tracepoint.split_once(':').context("expected category:name")?;
+                let probe = Arc::new(Probe::Tracepoint{
+                    scope: scope.scope(pid),
+                    category: category.to_owned(),
+                    name: name.to_owned(),
+                });
+
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
+                let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
+                let clis = Arc::clone(&t2.probes.lock().await.clients);
+                run_until_exit(t2.bpf, clis, run_until, None).await?;
+            }
+            Commands::Offcpu {
+                pid,
+                command,
+                scope,
+            } => {
+                let (pid, child) = get_pid_child(pid, command);
+                let probe = Arc::new(Probe::OffCpu{
+                    scope: scope.scope(pid),
+                });
+
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
+                let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
+                let clis = Arc::clone(&t2.probes.lock().await.clients);
+                run_until_exit(t2.bpf, clis, run_until, None).await?;
+            }
+            Commands::UnwindReport {
+                pid,
+                command,
+                scope,
+                period,
+            } => {
+                let (pid, child) = get_pid_child(pid, command);
+                let probe = Arc::new(Probe::Perf{
+                    scope: scope.scope(pid),
+                    event: PerfEventKind::TaskClock,
+                    sampling: Sampling::Period { period },
+                    aggregate: false,
+                });
+
+                let (output_tx, mut output_rx) = mpsc::channel(1024);
+                let report = tokio::spawn(async move {
+                    let mut report = UnwindReport::new();
+                    while let Some(sample) = output_rx.recv().await {
+                        report.add(&sample).await;
+                    }
+                    report
+                });
+
+                let _attachment = probe.attach(&t2.bpf, &*t2.probes.lock().await).await?;
+                let run_until = child.map(RunUntil::ChildProcessExits).unwrap_or(RunUntil::CtrlC);
+                let clis = Arc::clone(&t2.probes.lock().await.clients);
+                run_until_exit(t2.bpf, clis, run_until, Some(output_tx)).await?;
+                report.await?.print();
+            }
         }
 
         Ok(())
@@ -14,6 +14,9 @@ where
     pub item: T,
     pub total_samples: u64,
     pub self_samples: u64,
+    /// sum of the values captured with the samples, e.g. bytes allocated
+    pub total_value: u64,
+    pub self_value: u64,
 }
 
 impl<T> CallTreeFrame<T> 
@@ -28,6 +31,8 @@ impl<T> CallTreeFrame<T>
             item: f(self.item),
             total_samples: self.total_samples,
             self_samples: self.self_samples,
+            total_value: self.total_value,
+            self_value: self.self_value,
         }
     }
 
@@ -36,6 +41,32 @@ impl<T> CallTreeFrame<T>
             item,
             total_samples,
             self_samples,
+            total_value: 0,
+            self_value: 0,
+        }
+    }
+}
+
+/// `CallTreeFrame` before it carried values, to read call trees stored in that layout
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CallTreeFrameV0<T> {
+    pub item: T,
+    pub total_samples: u64,
+    pub self_samples: u64,
+}
+
+/// `CallTreeInner` made of `CallTreeFrameV0`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CallTreeInnerV0<T> {
+    pub arena: Arena<CallTreeFrameV0<T>>,
+    pub root: NodeId,
+}
+
+impl<T: Clone + Default + Eq + Serialize + Debug + Hash> From<CallTreeInnerV0<T>> for CallTreeInner<T> {
+    fn from(tree: CallTreeInnerV0<T>) -> Self {
+        Self {
+            arena: tree.arena.map(|f| CallTreeFrame::new(f.item, f.total_samples, f.self_samples)),
+            root: tree.root,
         }
     }
 }
@@ -61,15 +92,24 @@ impl<T: Clone + Default + Eq + Serialize + Debug + Hash> CallTreeInner<T> {
 
     /// create a new linear call tree from frames
     pub fn from_frames(frames: &[T]) -> Self {
+        Self::from_weighted_frames(frames, 1, 0)
+    }
+
+    /// create a new linear call tree from frames, where the stack counts for `weight` samples
+    /// and carries `value`
+    pub fn from_weighted_frames(frames: &[T], weight: u64, value: u64) -> Self {
         let mut tree = Self::new();
         let mut prev = tree.root;
         for (i, f) in frames.iter().enumerate() {
             let is_last = i == (frames.len() - 1);
-            let self_samples = if is_last { 1 } else { 0 };
+            let self_samples = if is_last { weight } else { 0 };
+            let self_value = if is_last { value } else { 0 };
             let new_node = tree.arena.new_node(CallTreeFrame {
                 item: f.clone(),
-                total_samples: 1,
+                total_samples: weight,
                 self_samples,
+                total_value: value,
+                self_value,
             });
             prev.append(new_node, &mut tree.arena);
             prev = new_node;
@@ -114,6 +154,7 @@ impl<T: Clone + Default + Eq + Serialize + Debug + Hash> CallTreeInner<T> {
                         if new_node.children(&new_tree.arena).count() == 0 {
                             let data = new_tree.arena.get_mut(new_node).unwrap().get_mut();
                             data.self_samples = data.total_samples;
+                            data.self_value = data.total_value;
                         }
                     }
                 }
@@ -122,6 +163,16 @@ impl<T: Clone + Default + Eq + Serialize + Debug + Hash> CallTreeInner<T> {
 
         new_tree
     }
+
+    /// use the captured values as sample counts, so the tree can be rendered by value
+    pub fn by_value(mut self) -> Self {
+        for node in self.arena.iter_mut() {
+            let frame = node.get_mut();
+            frame.total_samples = frame.total_value;
+            frame.self_samples = frame.self_value;
+        }
+        self
+    }
 }
 
 impl<T: Clone + Default + Eq + Serialize + Debug + Hash> Mergeable for CallTreeInner<T> {
@@ -148,6 +199,8 @@ impl<T: Clone + Default + Eq + Serialize + Debug + Hash> Mergeable for CallTreeI
                     let my_frame = self.arena.get_mut(my_child).unwrap().get_mut();
                     my_frame.total_samples += other_frame.total_samples;
                     my_frame.self_samples += other_frame.self_samples;
+                    my_frame.total_value += other_frame.total_value;
+                    my_frame.self_value += other_frame.self_value;
                     stack.push((my_child, other_child));
                 } else {
                     let new_node = self.arena.new_node(other_frame.clone());
@@ -304,6 +357,45 @@ mod tests {
         ).unwrap().get(), &CallTreeFrame::new(3, 1, 1));
     }
 
+    #[test]
+    fn test_merge_weighted() {
+        let mut ct1 = CallTreeInner::from_weighted_frames(&[0, 1, 2], 1000, 0);
+        let ct2 = CallTreeInner::from_weighted_frames(&[0, 3], 500, 0);
+        ct1.merge(&ct2);
+
+        assert_eq!(ct1.arena.get(
+            ct1.root.children(&ct1.arena).next().unwrap()
+        ).unwrap().get(), &CallTreeFrame::new(0, 1500, 0));
+        assert_eq!(ct1.arena.get(
+            ct1.root
+                .children(&ct1.arena).next().unwrap()
+                .children(&ct1.arena).next().unwrap()
+                .children(&ct1.arena).next().unwrap()
+        ).unwrap().get(), &CallTreeFrame::new(2, 1000, 1000));
+        assert_eq!(ct1.arena.get(
+            ct1.root
+                .children(&ct1.arena).next().unwrap()
+                .children(&ct1.arena).nth(1).unwrap()
+        ).unwrap().get(), &CallTreeFrame::new(3, 500, 500));
+    }
+
+    #[test]
+    fn test_by_value() {
+        let mut ct1 = CallTreeInner::from_weighted_frames(&[0, 1], 1, 64);
+        let ct2 = CallTreeInner::from_weighted_frames(&[0, 2], 1, 4096);
+        ct1.merge(&ct2);
+        let ct1 = ct1.by_value();
+
+        assert_eq!(ct1.arena.get(
+            ct1.root.children(&ct1.arena).next().unwrap()
+        ).unwrap().get().total_samples, 4160);
+        assert_eq!(ct1.arena.get(
+            ct1.root
+                .children(&ct1.arena).next().unwrap()
+                .children(&ct1.arena).nth(1).unwrap()
+        ).unwrap().get().self_samples, 4096);
+    }
+
     #[test]
     fn test_filter() {
         let ct1 = CallTreeInner::from_frames(&[1, 2, 3, 4, 5]);
@@ -15,6 +15,9 @@ pub mod traits;
 
 pub type UnsymbolizedCallTree = CallTreeInner<UnsymbolizedFrame>;
 pub type CallTree = CallTreeInner<SymbolizedFrame>;
+/// Layout of `UnsymbolizedCallTree` before frames had values
+pub type UnsymbolizedCallTreeV0 = inner::CallTreeInnerV0<UnsymbolizedFrame>;
+pub use self::inner::CallTreeFrameV0;
 
 impl UnsymbolizedCallTree {
     pub fn symbolize(self, symbols: &mut SymbolCache, modules: &mut impl ModuleMapping) -> CallTree {
@@ -30,6 +33,13 @@ pub enum CodeType {
     Python = 2,
     Kernel = 3,
     ProcessRoot = 4,
+    Thread = 5,
+    OnCpu = 6,
+    OffCpu = 7,
+    Latency = 8,
+    Truncated = 9,
+    UnwindError = 10,
+    Gil = 11,
 }
 
 impl Default for CodeType {
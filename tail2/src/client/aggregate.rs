@@ -0,0 +1,119 @@
+use std::{sync::Arc, time::{Duration, SystemTime}};
+
+use anyhow::{Result, Context};
+use aya::{maps::{Array, HashMap, MapData}, Bpf};
+use fnv::FnvHashMap;
+use tail2_common::{aggregate::AggStack, bpf_sample::SampleKind};
+use tokio::{sync::{watch, Mutex}, task::JoinHandle};
+
+use crate::{
+    dto::resolved_bpf_sample::{resolve_kernel_frames, ResolvedBpfSample, ResolvedPythonFrames},
+    symbolication::python_symbols::PythonSymbols,
+    tail2::Clients,
+};
+
+/// How often stacks counted in the kernel are drained
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Time for samples that picked the map before the flip to finish counting
+const FLIP_GRACE: Duration = Duration::from_millis(10);
+
+/// Periodically post the stacks of aggregating probes, weighted by how often they were seen
+pub(crate) async fn spawn_drain(
+    bpf: &Mutex<Bpf>,
+    clis: Clients,
+    symbols: Arc<Mutex<PythonSymbols>>,
+    mut stop_rx: watch::Receiver<()>,
+) -> Result<JoinHandle<()>> {
+    let (mut active, mut stacks) = {
+        let bpf = &mut *bpf.lock().await;
+        let active: Array<_, u32> = Array::try_from(bpf.take_map("AGG_ACTIVE").context("no such map")?)?;
+        let stacks: [HashMap<_, u64, AggStack>; 2] = [
+            HashMap::try_from(bpf.take_map("AGG_STACKS_0").context("no such map")?)?,
+            HashMap::try_from(bpf.take_map("AGG_STACKS_1").context("no such map")?)?,
+        ];
+        (active, stacks)
+    };
+
+    Ok(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(DRAIN_INTERVAL);
+        let mut current = 0u32;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = stop_rx.changed() => break,
+            }
+            // samples go to the other map while this one is drained, so no count is lost
+            let drained = current;
+            current ^= 1;
+            if let Err(e) = active.set(0, current, 0) {
+                tracing::error!("flipping aggregation maps failed: {}", e.to_string());
+                current = drained;
+                continue;
+            }
+            tokio::time::sleep(FLIP_GRACE).await;
+            if let Err(e) = drain(&mut stacks[drained as usize], &symbols, &clis).await {
+                tracing::error!("draining aggregated stacks failed: {}", e.to_string());
+            }
+        }
+    }))
+}
+
+async fn drain(
+    stacks: &mut HashMap<MapData, u64, AggStack>,
+    symbols: &Mutex<PythonSymbols>,
+    clis: &Clients,
+) -> Result<()> {
+    let drained: Vec<(u64, AggStack)> = stacks.iter().filter_map(|i| i.ok()).collect();
+    if drained.is_empty() {
+        return Ok(());
+    }
+
+    let ts_ms = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let mut posted = FnvHashMap::default();
+    for (stack_id, st) in drained {
+        let _ = stacks.remove(&stack_id);
+
+        // the probe was detached since
+        let cli = match clis.lock().await.get(&st.probe_id) {
+            Some(cli) => Arc::clone(cli),
+            None => continue,
+        };
+        if st.count == 0 || st.native_stack.unwind_success.is_none() {
+            continue;
+        }
+
+        let python_stack = match st.python_len {
+            0 => None,
+            len => {
+                let mut frames = symbols.lock().await.resolve(&st.python_ids[..len as usize], None, st.pidtgid.tgid());
+                frames.eval_calls = ResolvedPythonFrames::eval_calls(&st.python_entry[..len as usize]);
+                Some(frames)
+            }
+        };
+        let kernel_frames = match st.kernel_len {
+            0 => None,
+            len => Some(resolve_kernel_frames(&st.kernel_frames[..len as usize])),
+        };
+        cli.lock().await.post_stack(ResolvedBpfSample {
+            pid_tgid: st.pidtgid,
+            ts_ms,
+            weight: st.count,
+            kind: SampleKind::OnCpu,
+            value: None,
+            native_stack: Box::new(st.native_stack),
+            python_stack,
+            kernel_frames,
+            meta: None,
+        }).await?;
+        posted.insert(st.probe_id, cli);
+    }
+
+    for cli in posted.into_values() {
+        cli.lock().await.flush().await?;
+    }
+
+    Ok(())
+}
@@ -1,4 +1,7 @@
+pub mod aggregate;
+pub mod pipeline;
 pub mod post_stack_client;
 pub mod run;
+pub mod transport;
 pub mod ws_client;
 pub use post_stack_client::PostStackClient;
\ No newline at end of file
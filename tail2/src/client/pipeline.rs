@@ -0,0 +1,225 @@
+use std::{sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}, time::Duration};
+
+use aya::maps::{MapData, StackTraceMap};
+use fnv::FnvHashMap;
+use serde::Deserialize;
+use tail2_common::bpf_sample::BpfSample;
+use tokio::{sync::{mpsc::{self, error::TrySendError}, Mutex}, task::JoinHandle};
+
+use crate::{
+    client::PostStackClient, config::CONFIG, dto::resolved_bpf_sample::ResolvedBpfSample,
+    probes::probe::ProbeId, symbolication::python_symbols::PythonSymbols, tail2::Clients,
+};
+
+/// Samples read from the kernel, waiting to be resolved
+const RAW_QUEUE: usize = 4096;
+/// Resolved samples waiting to be batched
+const RESOLVED_QUEUE: usize = 4096;
+/// Batches waiting to be posted
+const BATCH_QUEUE: usize = 16;
+const RESOLVERS: usize = 2;
+const SENDERS: usize = 2;
+/// Partial batches are posted at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What readers do with samples once the resolvers can't keep up
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// drop samples that don't fit in the queue
+    #[default]
+    Drop,
+    /// past half full, keep one in 2, 4, 8 or 16 samples and weigh it accordingly
+    Downsample,
+}
+
+static RAW_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static RESOLVED_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static BATCH_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static SEEN: AtomicU64 = AtomicU64::new(0);
+
+/// Current depth of every queue, and samples dropped since the agent started
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineStats {
+    pub raw: usize,
+    pub resolved: usize,
+    pub batches: usize,
+    pub dropped: u64,
+}
+
+pub fn pipeline_stats() -> PipelineStats {
+    PipelineStats {
+        raw: RAW_DEPTH.load(Ordering::Relaxed),
+        resolved: RESOLVED_DEPTH.load(Ordering::Relaxed),
+        batches: BATCH_DEPTH.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+struct Resolved {
+    probe_id: ProbeId,
+    cli: Arc<Mutex<PostStackClient>>,
+    st: ResolvedBpfSample,
+}
+
+struct Batch {
+    cli: Arc<Mutex<PostStackClient>>,
+    stacks: Vec<ResolvedBpfSample>,
+}
+
+/// Entry of the reader -> resolvers -> batcher -> senders pipeline. Every stage has a
+/// bounded queue, a slow server backs up into the raw queue where `OverflowPolicy` applies.
+#[derive(Clone)]
+pub(crate) struct Pipeline {
+    raw_tx: mpsc::Sender<BpfSample>,
+    policy: OverflowPolicy,
+}
+
+impl Pipeline {
+    /// The stages stop once every clone of the returned `Pipeline` is dropped and the queues drained
+    pub(crate) fn spawn(
+        clis: Clients,
+        kernel_stacks: Arc<StackTraceMap<MapData>>,
+        python_symbols: Arc<Mutex<PythonSymbols>>,
+    ) -> (Self, Vec<JoinHandle<()>>) {
+        let (raw_tx, raw_rx) = mpsc::channel(RAW_QUEUE);
+        let (resolved_tx, resolved_rx) = mpsc::channel(RESOLVED_QUEUE);
+        let (batch_tx, batch_rx) = mpsc::channel(BATCH_QUEUE);
+
+        let mut ts = vec![];
+        let raw_rx = Arc::new(Mutex::new(raw_rx));
+        for _ in 0..RESOLVERS {
+            ts.push(tokio::spawn(resolve(
+                Arc::clone(&raw_rx),
+                resolved_tx.clone(),
+                Arc::clone(&clis),
+                Arc::clone(&kernel_stacks),
+                Arc::clone(&python_symbols),
+            )));
+        }
+        drop(resolved_tx);
+
+        ts.push(tokio::spawn(batch(resolved_rx, batch_tx)));
+
+        let batch_rx = Arc::new(Mutex::new(batch_rx));
+        for _ in 0..SENDERS {
+            ts.push(tokio::spawn(send(Arc::clone(&batch_rx))));
+        }
+
+        let policy = CONFIG.agent.overflow;
+        (Self { raw_tx, policy }, ts)
+    }
+
+    /// Never waits, the readers have to keep up with the kernel
+    pub(crate) fn push(&self, mut st: BpfSample) {
+        if self.policy == OverflowPolicy::Downsample {
+            let n = keep_one_in(RAW_DEPTH.load(Ordering::Relaxed));
+            if SEEN.fetch_add(1, Ordering::Relaxed) % n != 0 {
+                return;
+            }
+            st.weight *= n;
+        }
+
+        RAW_DEPTH.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.raw_tx.try_send(st) {
+            RAW_DEPTH.fetch_sub(1, Ordering::Relaxed);
+            if let TrySendError::Full(_) = e {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// 1 below half full, then doubling with every further eighth of the queue
+fn keep_one_in(depth: usize) -> u64 {
+    let half = RAW_QUEUE / 2;
+    if depth < half {
+        1
+    } else {
+        1 << ((depth - half) * 4 / half + 1).min(4)
+    }
+}
+
+async fn resolve(
+    raw_rx: Arc<Mutex<mpsc::Receiver<BpfSample>>>,
+    resolved_tx: mpsc::Sender<Resolved>,
+    clis: Clients,
+    kernel_stacks: Arc<StackTraceMap<MapData>>,
+    python_symbols: Arc<Mutex<PythonSymbols>>,
+) {
+    loop {
+        let st = match raw_rx.lock().await.recv().await {
+            Some(st) => st,
+            None => break,
+        };
+        RAW_DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+        // the probe was detached after the sample was taken
+        let probe_id = st.probe_id;
+        let cli = match clis.lock().await.get(&probe_id) {
+            Some(cli) => Arc::clone(cli),
+            None => continue,
+        };
+        let st = ResolvedBpfSample::resolve(st, &kernel_stacks, &mut *python_symbols.lock().await);
+
+        RESOLVED_DEPTH.fetch_add(1, Ordering::Relaxed);
+        if resolved_tx.send(Resolved { probe_id, cli, st }).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Group samples per probe, posting full batches right away and partial ones every `FLUSH_INTERVAL`
+async fn batch(mut resolved_rx: mpsc::Receiver<Resolved>, batch_tx: mpsc::Sender<Batch>) {
+    let batch_size = CONFIG.server.batch_size.unwrap_or(1000);
+    let mut pending: FnvHashMap<ProbeId, Batch> = FnvHashMap::default();
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            resolved = resolved_rx.recv() => {
+                let Some(Resolved { probe_id, cli, st }) = resolved else { break };
+                RESOLVED_DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+                let batch = pending
+                    .entry(probe_id)
+                    .or_insert_with(|| Batch { cli, stacks: Vec::with_capacity(batch_size) });
+                batch.stacks.push(st);
+                if batch.stacks.len() >= batch_size {
+                    let batch = pending.remove(&probe_id).unwrap();
+                    queue_batch(&batch_tx, batch).await;
+                }
+            },
+            _ = interval.tick() => {
+                for (_, batch) in pending.drain() {
+                    queue_batch(&batch_tx, batch).await;
+                }
+            },
+        }
+    }
+
+    for (_, batch) in pending.drain() {
+        queue_batch(&batch_tx, batch).await;
+    }
+}
+
+async fn queue_batch(batch_tx: &mpsc::Sender<Batch>, batch: Batch) {
+    BATCH_DEPTH.fetch_add(1, Ordering::Relaxed);
+    if batch_tx.send(batch).await.is_err() {
+        BATCH_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn send(batch_rx: Arc<Mutex<mpsc::Receiver<Batch>>>) {
+    loop {
+        let batch = match batch_rx.lock().await.recv().await {
+            Some(batch) => batch,
+            None => break,
+        };
+        BATCH_DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+        if let Err(e) = batch.cli.lock().await.post_stacks(batch.stacks).await {
+            tracing::error!("sending stacks failed: {}", e.to_string());
+        }
+    }
+}
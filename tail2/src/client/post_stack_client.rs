@@ -49,7 +49,7 @@ impl PostStackClient {
         Ok(StatusCode::ACCEPTED)
     }
 
-    async fn post_stacks(&self, stacks: Vec<ResolvedBpfSample>) -> Result<StatusCode> {
+    pub(crate) async fn post_stacks(&self, stacks: Vec<ResolvedBpfSample>) -> Result<StatusCode> {
         // tracing::::info!("posting stack len {}", stacks.len());
         if stacks.is_empty() {
             return Ok(StatusCode::ACCEPTED);
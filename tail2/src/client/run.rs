@@ -1,5 +1,5 @@
 use anyhow::{Result, Context};
-use aya::maps::{AsyncPerfEventArray, HashMap, StackTraceMap};
+use aya::maps::{AsyncPerfEventArray, HashMap, ProgramArray, StackTraceMap};
 use aya::util::online_cpus;
 use bytes::BytesMut;
 use tail2_common::metrics::Metrics;
@@ -8,41 +8,51 @@ use tracing::Level;
 use nix::sys::ptrace;
 
 use nix::unistd::{getuid, Pid};
+use fnv::FnvHashSet;
+use once_cell::sync::Lazy;
 
-use crate::dto::resolved_bpf_sample::ResolvedBpfSample;
+use crate::client::aggregate::spawn_drain;
+use crate::client::pipeline::{pipeline_stats, Pipeline};
+use crate::client::transport::{refresh_loss, spawn_loss_monitor, spawn_perf_readers, spawn_ring_reader, use_ringbuf, SampleHandler};
+use crate::probes::probe::forget_root;
+use crate::symbolication::python_symbols::PythonSymbols;
 
 use std::os::unix::prelude::MetadataExt;
 use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::{exit, Child, Command};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::SystemTime;
-use std::{mem::size_of, sync::Arc};
+use std::sync::Arc;
+use std::time::Duration;
 use tail2_common::bpf_sample::BpfSample;
-use tail2_common::ConfigMapKey;
+use tail2_common::{ConfigMapKey, DEFAULT_OFFCPU_STACKS};
 use tokio::signal;
 use tokio::sync::mpsc;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
 use aya::maps::MapData;
-use aya::{include_bytes_aligned, Bpf};
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
+use aya::programs::{KProbe, PerfEvent, Program, TracePoint};
 use aya_log::BpfLogger;
 use tail2_common::procinfo::ProcInfo;
 use tokio::sync::watch::Receiver;
 use tokio::sync::Mutex;
 
 use crate::processes::Processes;
+use crate::config::CONFIG;
+use crate::tail2::CACHE;
 
-use super::post_stack_client::PostStackClient;
+use crate::tail2::Clients;
 
 pub(crate) async fn run_bpf(
     bpf: Arc<Mutex<Bpf>>,
-    clis: Arc<Mutex<Vec<Arc<Mutex<PostStackClient>>>>>,
+    clis: Clients,
     stop_rx: watch::Receiver<()>,
     output_tx: Option<mpsc::Sender<BpfSample>>,
 ) -> Result<Vec<JoinHandle<()>>> {
-    // tracing::info!("run_bpf");
+    let ringbuf = use_ringbuf();
+    tracing::info!("sending samples through {}", if ringbuf { "ring buffer" } else { "perf event array" });
+
     // send device info
     {
         let bpf_ = &mut *bpf.lock().await;
@@ -55,81 +65,34 @@ pub(crate) async fn run_bpf(
         config
             .insert(ConfigMapKey::INO as u32, stats.ino(), 0)
             .unwrap();
+        config
+            .insert(ConfigMapKey::RINGBUF as u32, ringbuf as u64, 0)
+            .unwrap();
     }
 
     let kernel_stacks =
         Arc::new(StackTraceMap::try_from(bpf.lock().await.take_map("KERNEL_STACKS").unwrap()).unwrap());
-
-    // receiver task
-    let mut ts = vec![];
-
-    let total_time = Arc::new(AtomicU64::new(0));
-    let c = Arc::new(AtomicU64::new(0));
-
-    // open bpf maps
-    let mut stacks = AsyncPerfEventArray::try_from(bpf.lock().await.take_map("STACKS").unwrap()).unwrap();
-
-    // listen to bpf perf buf, send stacks to tx
-    for cpu_id in online_cpus().unwrap() {
-        let mut buf = stacks.open(cpu_id, Some(1024)).unwrap();
-
-        let mut stop_rx2 = stop_rx.clone();
-        let kernel_stacks = Arc::clone(&kernel_stacks);
-        let clis = Arc::clone(&clis);
-        let output_tx = output_tx.clone();
-        let c = c.clone();
-        let total_time = total_time.clone();
-        let t = tokio::spawn(async move {
-            let mut buffers = (0..1)
-                .map(|_| BytesMut::with_capacity(size_of::<BpfSample>()))
-                .collect::<Vec<_>>();
-
-            loop {
-                // poll for events
-                tokio::select! {
-                    evts = buf.read_events(&mut buffers) => {
-                        let events = evts.unwrap();
-                        for buf in buffers.iter_mut().take(events.read) {
-                            let mut st: BpfSample = unsafe { *std::mem::transmute::<_, *const _>(buf.as_ptr()) };
-                            // dbg!(&st.native_stack.unwind_success);
-                            st.ts_ms = SystemTime::now()
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis() as u64;
-                            let start_time = SystemTime::now();
-
-                            if let Some(ref output_tx) = output_tx {
-                                output_tx.send(st).await.unwrap();
-                            }
-
-                            let cli = Arc::clone(&clis.lock().await[st.idx]);
-                            let st = ResolvedBpfSample::resolve(st, &kernel_stacks);
-                            if let Some(st) = st {
-                                let cli2 = Arc::clone(&cli);
-                                tokio::spawn(async move {
-                                    if let Err(e) = cli2.lock().await.post_stack(st).await {
-                                        tracing::error!("sending stack failed: {}", e.to_string());
-                                    }
-                                });
-                            }
-
-                            let elapsed = SystemTime::now().duration_since(start_time).unwrap();
-                            total_time.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
-                            c.fetch_add(1, Ordering::Relaxed);
-                        }
-                    },
-                    _ = stop_rx2.changed() => {
-                        tracing::warn!("stopping cpu: {cpu_id}");
-                        // tracing::warn!("processed: {}, avg: {:?}", c.load(Ordering::Relaxed), total_time.load(Ordering::Relaxed) / c.load(Ordering::Relaxed));
-                        tracing::warn!("processed: {}", c.load(Ordering::Relaxed));
-
-                        break;
-                    },
-                };
-            }
-        });
-        ts.push(t);
+    let python_symbols =
+        Arc::new(Mutex::new(PythonSymbols::new(HashMap::try_from(bpf.lock().await.take_map("PY_SYMBOLS").unwrap()).unwrap())));
+
+    spawn_proc_refresh(Arc::clone(&bpf), Arc::clone(&python_symbols)).await?;
+
+    let (pipeline, mut ts) = Pipeline::spawn(
+        Arc::clone(&clis),
+        Arc::clone(&kernel_stacks),
+        Arc::clone(&python_symbols),
+    );
+    let handler = SampleHandler { pipeline, output_tx };
+
+    // receiver tasks, the pipeline winds down once they stop
+    if ringbuf {
+        ts.push(spawn_ring_reader(&bpf, handler, stop_rx.clone()).await?);
+    } else {
+        ts.extend(spawn_perf_readers(&bpf, handler, stop_rx.clone()).await?);
     }
+
+    ts.push(spawn_loss_monitor(Arc::clone(&bpf), stop_rx.clone()));
+    ts.push(spawn_drain(&bpf, clis, python_symbols, stop_rx).await?);
     Ok(ts)
 }
 
@@ -194,8 +157,10 @@ pub fn get_pid_child(
         }
         // If a PID was provided, but no command was provided, return the PID and None for the child process
         (Some(pid), None) => (Some(pid), None),
+        // Neither was provided, the probe is scoped some other way
+        (None, None) => (None, None),
         // If both a PID and a command were provided, panic
-        _ => panic!("supply one of --pid, --command"),
+        _ => panic!("supply only one of --pid, --command"),
     }
 }
 
@@ -212,6 +177,9 @@ pub async fn init_bpf() -> Result<Bpf> {
     let mut bpf = load_bpf()?;
 
     BpfLogger::init(&mut bpf).unwrap();
+    load_programs(&mut bpf);
+    set_unwind_progs(&mut bpf)?;
+    attach_lifecycle(&mut bpf)?;
 
     Ok(bpf)
 }
@@ -227,7 +195,7 @@ pub enum RunUntil {
 
 pub async fn run_until_exit(
     bpf: Arc<Mutex<Bpf>>,
-    clis: Arc<Mutex<Vec<Arc<Mutex<PostStackClient>>>>>,
+    clis: Clients,
     run_until: RunUntil,
     output_tx: Option<mpsc::Sender<BpfSample>>,
 ) -> Result<()> {
@@ -239,8 +207,6 @@ pub async fn run_until_exit(
             (Some(tx), rx)
         };
 
-    spawn_proc_refresh(Arc::clone(&bpf)).await.unwrap();
-
     let tasks = run_bpf(Arc::clone(&bpf), clis, stop_rx, output_tx).await?;
 
     match run_until {
@@ -272,25 +238,68 @@ pub async fn run_until_exit(
 }
 
 pub async fn pid_refresh(bpf: Arc<Mutex<Bpf>>, pid: u32) {
-    let bpf = &mut bpf.lock().await;
-    let pid_info: HashMap<_, u32, ProcInfo> =
+    let bpf = &mut *bpf.lock().await;
+    let nfo = match Processes::load_pid(bpf, pid as i32).await {
+        Ok(nfo) => nfo,
+        Err(_) => return,
+    };
+
+    let mut pid_info: HashMap<_, u32, ProcInfo> =
         HashMap::try_from(bpf.map_mut("PIDS").unwrap()).unwrap();
-    // HACK: extend lifetime to 'static
-    let mut pid_info = unsafe {
-        std::mem::transmute::<
-            HashMap<&mut MapData, u32, ProcInfo>,
-            HashMap<&'static mut MapData, u32, ProcInfo>,
-        >(pid_info)
+    let _ = pid_info.insert(pid, nfo.as_ref(), 0);
+    // the entry of the process tells apart traced processes when any of their threads maps code
+    if let Ok(status) = procfs::process::Process::new(pid as i32).and_then(|p| p.status()) {
+        if status.tgid as u32 != pid {
+            let _ = pid_info.insert(status.tgid as u32, nfo.as_ref(), 0);
+        }
+    }
+}
+
+/// Processes with a re-detect scheduled
+static MAP_REFRESHES: Lazy<std::sync::Mutex<FnvHashSet<u32>>> = Lazy::new(Default::default);
+/// mmaps usually come in bursts, e.g. a dlopen maps every segment of the library and its dependencies
+const MAP_REFRESH_DELAY: Duration = Duration::from_millis(100);
+
+/// Re-detect a process once its mappings settle instead of once per mmap
+fn schedule_process_refresh(bpf: Arc<Mutex<Bpf>>, tgid: u32) {
+    if !MAP_REFRESHES.lock().unwrap().insert(tgid) {
+        return;
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(MAP_REFRESH_DELAY).await;
+        // mappings after this point schedule another refresh
+        MAP_REFRESHES.lock().unwrap().remove(&tgid);
+        process_refresh(bpf, tgid).await;
+    });
+}
+
+/// Re-detect a process whose mappings changed and update the entries of all its threads
+pub async fn process_refresh(bpf: Arc<Mutex<Bpf>>, tgid: u32) {
+    CACHE.proc_map.lock().await.invalidate(tgid);
+
+    let bpf = &mut *bpf.lock().await;
+    let nfo = match Processes::load_pid(bpf, tgid as i32).await {
+        Ok(nfo) => nfo,
+        Err(_) => return,
+    };
+    let tids: Vec<u32> = match std::fs::read_dir(format!("/proc/{tgid}/task")) {
+        Ok(tasks) => tasks
+            .filter_map(|t| t.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => return,
     };
 
-    let pid_info: &mut HashMap<&mut MapData, u32, ProcInfo> = &mut pid_info;
-    if let Ok(nfo) = Processes::detect_pid(pid as i32).await {
-        let nfo = nfo.as_ref();
-        let _ = pid_info.insert(pid as u32, nfo, 0);
+    let mut pid_info: HashMap<_, u32, ProcInfo> =
+        HashMap::try_from(bpf.map_mut("PIDS").unwrap()).unwrap();
+    for tid in tids {
+        // threads that haven't been sampled yet are detected when they are
+        if pid_info.get(&tid, 0).is_ok() {
+            let _ = pid_info.insert(tid, nfo.as_ref(), 0);
+        }
     }
 }
 
-pub(crate) async fn spawn_proc_refresh(bpf: Arc<Mutex<Bpf>>) -> Result<()> {
+pub(crate) async fn spawn_proc_refresh(bpf: Arc<Mutex<Bpf>>, python_symbols: Arc<Mutex<PythonSymbols>>) -> Result<()> {
     let bpf_mut = &mut *bpf.lock().await;
 
     let pid_event = AsyncPerfEventArray::try_from(bpf_mut.map_mut("PID_EVENT").unwrap())?;
@@ -306,6 +315,7 @@ pub(crate) async fn spawn_proc_refresh(bpf: Arc<Mutex<Bpf>>) -> Result<()> {
         // open a separate perf buffer for each cpu
         let mut buf = pid_event.open(cpu_id, None)?;
         let bpf_ = bpf.clone();
+        let python_symbols = Arc::clone(&python_symbols);
 
         tokio::spawn(async move {
             let mut buffers = (0..10)
@@ -318,8 +328,22 @@ pub(crate) async fn spawn_proc_refresh(bpf: Arc<Mutex<Bpf>>) -> Result<()> {
 
                 for buf in buffers.iter_mut().take(events.read) {
                     let evt: PidEvent = unsafe { *std::mem::transmute::<_, *const _>(buf.as_ptr()) };
-                    if evt.event_type == Metrics::TraceMgmt_NewPid {
-                        pid_refresh(bpf_.clone(), evt.pid).await;
+                    match evt.event_type {
+                        Metrics::TraceMgmt_NewPid => pid_refresh(bpf_.clone(), evt.pid).await,
+                        Metrics::TraceMgmt_PidExit => {
+                            CACHE.invalidate_pid(evt.pid).await;
+                            python_symbols.lock().await.forget_pid(evt.pid);
+                            if let Err(e) = forget_root(&mut *bpf_.lock().await, evt.pid) {
+                                tracing::error!("dropping the descendants of {} failed: {}", evt.pid, e.to_string());
+                            }
+                        }
+                        Metrics::TraceMgmt_PidExec => {
+                            CACHE.invalidate_pid(evt.pid).await;
+                            python_symbols.lock().await.forget_pid(evt.pid);
+                            pid_refresh(bpf_.clone(), evt.pid).await;
+                        }
+                        Metrics::TraceMgmt_NewMap => schedule_process_refresh(bpf_.clone(), evt.pid),
+                        _ => (),
                     }
                 }
             }
@@ -330,18 +354,76 @@ pub(crate) async fn spawn_proc_refresh(bpf: Arc<Mutex<Bpf>>) -> Result<()> {
 }
 
 pub(crate) fn load_bpf() -> Result<Bpf> {
+    let mut loader = BpfLoader::new();
+    loader.set_max_entries("OFFCPU_STACKS", CONFIG.agent.offcpu_stacks.unwrap_or(DEFAULT_OFFCPU_STACKS));
+    if !use_ringbuf() {
+        // the map is always declared, the smallest ring buffer is a page
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        loader.set_max_entries("STACKS_RING", page_size);
+    }
+
     #[cfg(debug_assertions)]
-    let bpf = Bpf::load(include_bytes_aligned!(
+    let bpf = loader.load(include_bytes_aligned!(
         "../../../target/bpfel-unknown-none/debug/tail2"
     ))?;
 
     #[cfg(not(debug_assertions))]
-    let bpf = Bpf::load(include_bytes_aligned!(
+    let bpf = loader.load(include_bytes_aligned!(
         "../../../target/bpfel-unknown-none/release/tail2"
     ))?;
     Ok(bpf)
 }
 
+/// Every probe kind has a single program, probes tell themselves apart by attach cookie
+fn load_programs(bpf: &mut Bpf) {
+    for (name, program) in bpf.programs_mut() {
+        let ret = match program {
+            Program::PerfEvent(p) => p.load(),
+            Program::UProbe(p) => p.load(),
+            Program::KProbe(p) => p.load(),
+            Program::TracePoint(p) => p.load(),
+            _ => continue,
+        };
+        match ret {
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!("{name}: {}", e.to_string());
+            }
+        }
+    }
+}
+
+/// Register the continuations of the user stack unwinder, indices match `Continue::UNWIND_PROG`
+fn set_unwind_progs(bpf: &mut Bpf) -> Result<()> {
+    let perf: &PerfEvent = bpf.program("unwind_perf").context("no such program")?.try_into()?;
+    let perf = perf.fd().context("not loaded")?;
+    let probe: &KProbe = bpf.program("unwind_probe").context("no such program")?.try_into()?;
+    let probe = probe.fd().context("not loaded")?;
+    let tp: &TracePoint = bpf.program("unwind_tp").context("no such program")?.try_into()?;
+    let tp = tp.fd().context("not loaded")?;
+
+    // the kernel empties a program array once user space closes it, so it stays owned by `bpf`
+    let mut progs = ProgramArray::try_from(bpf.map_mut("UNWIND_PROGS").context("no such map")?)?;
+    progs.set(0, perf, 0)?;
+    progs.set(1, probe, 0)?;
+    progs.set(2, tp, 0)?;
+    Ok(())
+}
+
+/// Track exit, exec and new code mappings of every process so unwind info stays current, independent of probes
+fn attach_lifecycle(bpf: &mut Bpf) -> Result<()> {
+    for (name, category, event) in [
+        ("proc_exit", "sched", "sched_process_exit"),
+        ("proc_exec", "sched", "sched_process_exec"),
+        ("mmap_enter", "syscalls", "sys_enter_mmap"),
+        ("mmap_exit", "syscalls", "sys_exit_mmap"),
+    ] {
+        let program: &mut TracePoint = bpf.program_mut(name).context("no such program")?.try_into()?;
+        program.attach(category, event, None)?;
+    }
+    Ok(())
+}
+
 pub(crate) async fn print_stats(bpf: Arc<Mutex<Bpf>>) -> Result<()> {
     let bpf = &mut *bpf.lock().await;
     let info: HashMap<_, u32, u64> =
@@ -351,5 +433,13 @@ pub(crate) async fn print_stats(bpf: Arc<Mutex<Bpf>>) -> Result<()> {
         tracing::info!("{k:?} = {}", info.get(&(*k as u32), 0).unwrap_or(0));
     }
 
+    let loss = refresh_loss(bpf)?;
+    tracing::info!("lost {} of {} samples ({:.2}%)", loss.lost, loss.produced, loss.rate() * 100.);
+    let queues = pipeline_stats();
+    tracing::info!(
+        "queued: {} raw, {} resolved, {} batches, {} dropped by the agent",
+        queues.raw, queues.resolved, queues.batches, queues.dropped
+    );
+
     Ok(())
 }
@@ -0,0 +1,172 @@
+use std::{mem::size_of, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime}};
+
+use anyhow::{Context, Result};
+use aya::{maps::{AsyncPerfEventArray, HashMap, RingBuf}, util::online_cpus, Bpf};
+use bytes::BytesMut;
+use once_cell::sync::Lazy;
+use tail2_common::{bpf_sample::BpfSample, metrics::Metrics};
+use tokio::{io::unix::AsyncFd, sync::{mpsc, watch, Mutex}, task::JoinHandle};
+
+use crate::{client::pipeline::{pipeline_stats, Pipeline}, config::CONFIG, dto::SampleLoss};
+
+/// How often the loss counters are read back from `METRICS`
+const LOSS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Offset from the monotonic clock `bpf_ktime_get_ns` reads to the unix epoch
+static MONOTONIC_TO_WALL_NS: Lazy<u64> = Lazy::new(|| {
+    let mut mono = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut mono) };
+    let wall = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    wall - (mono.tv_sec as u64 * 1_000_000_000 + mono.tv_nsec as u64)
+});
+
+static PRODUCED: AtomicU64 = AtomicU64::new(0);
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+/// The latest loss counters, attached to every batch sent to the server
+pub fn sample_loss() -> SampleLoss {
+    SampleLoss {
+        produced: PRODUCED.load(Ordering::Relaxed),
+        lost: LOST.load(Ordering::Relaxed),
+    }
+}
+
+/// Samples go through the ring buffer. Agents can be configured to send through perf event arrays
+/// with the `perf_transport` setting, there's no automatic fallback: the 5.15 minimum for attach
+/// cookies already guarantees ring buffer support.
+pub(crate) fn use_ringbuf() -> bool {
+    !CONFIG.agent.perf_transport
+}
+
+/// Hands samples from a reader to the pipeline
+#[derive(Clone)]
+pub(crate) struct SampleHandler {
+    pub pipeline: Pipeline,
+    pub output_tx: Option<mpsc::Sender<BpfSample>>,
+}
+
+impl SampleHandler {
+    async fn handle(&self, buf: &[u8]) {
+        let mut st: BpfSample = unsafe { *std::mem::transmute::<_, *const _>(buf.as_ptr()) };
+        st.ts_ms = (*MONOTONIC_TO_WALL_NS + st.ktime_ns) / 1_000_000;
+
+        if let Some(ref output_tx) = self.output_tx {
+            output_tx.send(st).await.unwrap();
+        }
+
+        self.pipeline.push(st);
+    }
+}
+
+/// One reader for the ring buffer shared by all cpus
+pub(crate) async fn spawn_ring_reader(bpf: &Mutex<Bpf>, handler: SampleHandler, mut stop_rx: watch::Receiver<()>) -> Result<JoinHandle<()>> {
+    let ring = RingBuf::try_from(bpf.lock().await.take_map("STACKS_RING").context("no such map")?)?;
+    let mut ring = AsyncFd::new(ring)?;
+
+    Ok(tokio::spawn(async move {
+        let mut processed = 0;
+        loop {
+            tokio::select! {
+                guard = ring.readable_mut() => {
+                    let mut guard = guard.unwrap();
+                    let ring = guard.get_inner_mut();
+                    while let Some(item) = ring.next() {
+                        handler.handle(&item).await;
+                        processed += 1;
+                    }
+                    guard.clear_ready();
+                },
+                _ = stop_rx.changed() => {
+                    tracing::warn!("processed: {processed}");
+                    break;
+                },
+            }
+        }
+    }))
+}
+
+/// One reader per cpu, samples the kernel couldn't fit in a buffer are counted into `METRICS`
+pub(crate) async fn spawn_perf_readers(bpf: &Arc<Mutex<Bpf>>, handler: SampleHandler, stop_rx: watch::Receiver<()>) -> Result<Vec<JoinHandle<()>>> {
+    let mut stacks = AsyncPerfEventArray::try_from(bpf.lock().await.take_map("STACKS").context("no such map")?)?;
+
+    let mut ts = vec![];
+    for cpu_id in online_cpus()? {
+        let mut buf = stacks.open(cpu_id, Some(1024))?;
+
+        let mut stop_rx = stop_rx.clone();
+        let handler = handler.clone();
+        let bpf = Arc::clone(bpf);
+        ts.push(tokio::spawn(async move {
+            let mut buffers = (0..1)
+                .map(|_| BytesMut::with_capacity(size_of::<BpfSample>()))
+                .collect::<Vec<_>>();
+
+            let mut processed = 0;
+            loop {
+                // poll for events
+                tokio::select! {
+                    evts = buf.read_events(&mut buffers) => {
+                        let events = evts.unwrap();
+                        if events.lost > 0 {
+                            if let Err(e) = add_metric(&bpf, Metrics::ErrSend_PerfLost, events.lost as u64).await {
+                                tracing::error!("counting lost samples failed: {}", e.to_string());
+                            }
+                        }
+                        for buf in buffers.iter_mut().take(events.read) {
+                            handler.handle(buf).await;
+                            processed += 1;
+                        }
+                    },
+                    _ = stop_rx.changed() => {
+                        tracing::warn!("stopping cpu: {cpu_id}, processed: {processed}");
+                        break;
+                    },
+                };
+            }
+        }));
+    }
+    Ok(ts)
+}
+
+/// Keep `sample_loss` current
+pub(crate) fn spawn_loss_monitor(bpf: Arc<Mutex<Bpf>>, mut stop_rx: watch::Receiver<()>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LOSS_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = stop_rx.changed() => break,
+            }
+            if let Err(e) = refresh_loss(&mut *bpf.lock().await) {
+                tracing::error!("reading sample loss failed: {}", e.to_string());
+            }
+        }
+    })
+}
+
+/// Perf samples are counted as sent before they can be lost, ring buffer samples aren't.
+/// Samples the pipeline drops were sent, so they only count as lost.
+pub(crate) fn refresh_loss(bpf: &mut Bpf) -> Result<SampleLoss> {
+    let metrics: HashMap<_, u32, u64> =
+        HashMap::try_from(bpf.map("METRICS").context("no such map")?)?;
+    let get = |k: Metrics| metrics.get(&(k as u32), 0).unwrap_or(0);
+
+    let ring_full = get(Metrics::ErrSend_RingFull);
+    let dropped = pipeline_stats().dropped;
+    PRODUCED.store(get(Metrics::SentStackCount) + ring_full, Ordering::Relaxed);
+    LOST.store(ring_full + get(Metrics::ErrSend_PerfLost) + dropped, Ordering::Relaxed);
+    Ok(sample_loss())
+}
+
+/// Bump a counter the bpf programs can't see, e.g. perf buffer overflows
+async fn add_metric(bpf: &Mutex<Bpf>, key: Metrics, n: u64) -> Result<()> {
+    let bpf = &mut *bpf.lock().await;
+    let mut metrics: HashMap<_, u32, u64> =
+        HashMap::try_from(bpf.map_mut("METRICS").context("no such map")?)?;
+    let cnt = metrics.get(&(key as u32), 0).unwrap_or(0);
+    metrics.insert(key as u32, cnt + n, 0)?;
+    Ok(())
+}
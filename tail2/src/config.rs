@@ -4,6 +4,8 @@ use std::{fs::File, io::Read};
 
 use serde::Deserialize;
 
+use crate::client::pipeline::OverflowPolicy;
+
 pub static CONFIG: Lazy<Tail2Config> = Lazy::new(|| {
     Tail2Config::new().unwrap()
 });
@@ -11,6 +13,8 @@ pub static CONFIG: Lazy<Tail2Config> = Lazy::new(|| {
 #[derive(Deserialize, Debug)]
 pub struct Tail2Config {
     pub server: Server,
+    #[serde(default)]
+    pub agent: Agent,
 }
 
 #[derive(Deserialize, Debug)]
@@ -20,6 +24,20 @@ pub struct Server {
     pub batch_size: Option<usize>,
 }
 
+#[derive(Deserialize, Debug, Default)]
+pub struct Agent {
+    /// send samples through perf event arrays even where ring buffers are supported
+    #[serde(default)]
+    pub perf_transport: bool,
+    /// what to do with samples when the agent falls behind, "drop" or "downsample"
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// switched out threads an off-cpu probe can keep stacks for, each entry pins a whole sample
+    /// of kernel memory, `DEFAULT_OFFCPU_STACKS` if unset
+    #[serde(default)]
+    pub offcpu_stacks: Option<u32>,
+}
+
 impl Tail2Config {
     pub fn new() -> Result<Self> {
         Self::from_path("Tail2.toml")
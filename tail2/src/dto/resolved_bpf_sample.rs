@@ -4,9 +4,11 @@ use aya::maps::{MapData, StackTraceMap};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use tail2_common::{
-    bpf_sample::BpfSample, pidtgid::PidTgid, python::state::PythonStack, NativeStack,
+    bpf_sample::{BpfSample, SampleKind}, pidtgid::PidTgid, python::state::{PythonStack, PythonSymbol, FRAME_MAX_LEN}, NativeStack,
 };
 
+use crate::{dto::stack_dto::SampleMeta, symbolication::python_symbols::PythonSymbols};
+
 pub static KSYMS: Lazy<BTreeMap<u64, String>> = Lazy::new(|| {
     aya::util::kernel_symbols().unwrap()
 });
@@ -22,57 +24,143 @@ fn str_from_u8_nul_utf8(utf8_src: &[u8]) -> Result<&str, std::str::Utf8Error> {
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ResolvedPythonFrames {
     pub frames: Vec<String>,
+    /// whether the thread held the GIL, `None` if unknown
+    pub holds_gil: Option<bool>,
+    /// frames each call of the eval loop ran, leaf first
+    #[serde(default)]
+    pub eval_calls: Vec<u32>,
 }
 
 impl ResolvedPythonFrames {
-    pub fn resolve(python_stack: PythonStack) -> Self {
-        let mut frames = vec![];
-        for f in &python_stack.frames[..python_stack.frames_len] {
-            if let Ok(name) = str_from_u8_nul_utf8(&f.name) {
-                frames.push(name.to_owned());
+    pub fn resolve(python_stack: &PythonStack, symbols: &mut PythonSymbols, tgid: u32) -> Self {
+        let len = python_stack.frames_len.min(FRAME_MAX_LEN);
+        let mut ret = symbols.resolve(
+            &python_stack.frames[..len],
+            Some((&python_stack.code[..len], &python_stack.lasti[..len])),
+            tgid,
+        );
+        ret.holds_gil = python_stack.holds_gil();
+        ret.eval_calls = Self::eval_calls(&python_stack.eval_entry[..len]);
+        ret
+    }
+
+    /// Frames per call of the eval loop from the `PythonStack::eval_entry` of every frame.
+    /// Frames after the last entry, e.g. of a truncated stack, go in a call of their own.
+    pub fn eval_calls(entries: &[bool]) -> Vec<u32> {
+        let mut ret = vec![];
+        let mut len = 0;
+        for &entry in entries {
+            len += 1;
+            if entry {
+                ret.push(len);
+                len = 0;
             }
         }
+        if len > 0 {
+            ret.push(len);
+        }
+        ret
+    }
+
+    /// `module.Class.func (file.py:123)`
+    pub fn symbol_name(symbol: &PythonSymbol, line: u32) -> Option<String> {
+        let name = str_from_u8_nul_utf8(&symbol.name).ok()?;
+        let classname = str_from_u8_nul_utf8(&symbol.classname).unwrap_or_default();
+        let file = str_from_u8_nul_utf8(&symbol.file).unwrap_or_default();
+
+        let mut ret = module_name(file);
+        for part in [classname, name] {
+            if part.is_empty() {
+                continue;
+            }
+            if !ret.is_empty() {
+                ret.push('.');
+            }
+            ret.push_str(part);
+        }
+        let base_name = file.rsplit('/').next().unwrap_or(file);
+        Some(format!("{ret} ({base_name}:{line})"))
+    }
+}
+
+/// Best guess of the module a file is imported as: the path below site-packages or the
+/// standard library, else the file name
+fn module_name(file: &str) -> String {
+    let path = file.strip_suffix(".py").unwrap_or(file);
+    let parts: Vec<&str> = path.split('/').collect();
+    let root = parts
+        .iter()
+        .rposition(|p| {
+            *p == "site-packages" || *p == "dist-packages" || (p.starts_with("python") && p[6..].contains('.'))
+        })
+        .map_or(parts.len().saturating_sub(1), |i| i + 1);
 
-        Self { frames }
+    let mut module = parts[root..].to_vec();
+    if module.len() > 1 && module.last() == Some(&"__init__") {
+        module.pop();
     }
+    module.join(".")
 }
 
 #[derive(Debug)]
 pub struct ResolvedBpfSample {
     pub pid_tgid: PidTgid,
     pub ts_ms: u64,
+    pub weight: u64,
+    pub kind: SampleKind,
+    pub value: Option<u64>,
     pub native_stack: Box<NativeStack>,
     pub python_stack: Option<ResolvedPythonFrames>,
     pub kernel_frames: Option<Vec<Option<String>>>,
+    /// `None` for stacks that merge many samples, e.g. aggregated ones
+    pub meta: Option<SampleMeta>,
 }
 
 impl ResolvedBpfSample {
     pub fn resolve(
         sample: BpfSample,
         kernel_stacks: &StackTraceMap<MapData>,
-    ) -> Option<Self> {
-        let mut kernel_frames = None;
-        let stack_id = sample.kernel_stack_id;
-        if stack_id > 0 {
-            if let Ok(mut kernel_stack) = kernel_stacks.get(&(stack_id as u32), 0) {
-                let kfs: Vec<_> = kernel_stack
-                    .resolve(&KSYMS)
-                    .frames()
-                    .iter()
-                    .map(|i| i.symbol_name.clone())
-                    .collect();
-                kernel_frames = Some(kfs);
-            }
-        }
+        symbols: &mut PythonSymbols,
+    ) -> Self {
+        let kernel_frames = resolve_kernel_stack(sample.kernel_stack_id, kernel_stacks);
 
-        sample.native_stack.unwind_success?;
-
-        Some(Self {
+        Self {
             pid_tgid: sample.pidtgid,
             ts_ms: sample.ts_ms,
+            weight: sample.weight,
+            kind: sample.kind,
+            value: sample.value,
             native_stack: Box::new(sample.native_stack),
-            python_stack: sample.python_stack.map(ResolvedPythonFrames::resolve),
+            python_stack: sample.python_stack.map(|s| ResolvedPythonFrames::resolve(&s, symbols, sample.pidtgid.tgid())),
             kernel_frames,
-        })
+            meta: Some(SampleMeta {
+                tid: sample.pidtgid.pid(),
+                cpu: sample.cpu,
+                comm: str_from_u8_nul_utf8(&sample.comm).unwrap_or_default().to_owned(),
+                cgroup_id: sample.cgroup_id,
+                ktime_ns: sample.ktime_ns,
+            }),
+        }
     }
 }
+
+/// Symbol names of a stack in `KERNEL_STACKS`, `None` if there was no kernel stack
+pub fn resolve_kernel_stack(stack_id: i64, kernel_stacks: &StackTraceMap<MapData>) -> Option<Vec<Option<String>>> {
+    if stack_id <= 0 {
+        return None;
+    }
+    let mut kernel_stack = kernel_stacks.get(&(stack_id as u32), 0).ok()?;
+    Some(kernel_stack
+        .resolve(&KSYMS)
+        .frames()
+        .iter()
+        .map(|i| i.symbol_name.clone())
+        .collect())
+}
+
+/// Symbol names of kernel addresses, leaf first
+pub fn resolve_kernel_frames(ips: &[u64]) -> Vec<Option<String>> {
+    ips.iter()
+        .map(|ip| KSYMS.range(..=*ip).next_back().map(|(_, name)| name.clone()))
+        .collect()
+}
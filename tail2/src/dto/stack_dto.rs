@@ -3,11 +3,11 @@ use std::sync::Arc;
 use anyhow::{Context, Result};
 use procfs::process::MemoryMap;
 use serde::{Deserialize, Serialize};
-use tail2_common::{NativeStack, pidtgid::PidTgid};
+use tail2_common::{NativeStack, pidtgid::PidTgid, bpf_sample::SampleKind, metrics::Metrics};
 
 use crate::{
     symbolication::{module::Module, module_cache::ModuleCache, elf::SymbolCache, proc_map_cache::ProcMapCache, process_info_cache::ProcessInfoCache},
-    utils::MMapPathExt, probes::Probe, tail2::HOSTNAME, calltree::SymbolizedFrame,
+    utils::MMapPathExt, probes::Probe, tail2::HOSTNAME, calltree::SymbolizedFrame, client::transport::sample_loss,
 };
 
 use super::resolved_bpf_sample::ResolvedBpfSample;
@@ -39,21 +39,52 @@ pub struct StackDto {
     pub pid_tgid: PidTgid,
     pub ident: String,
     pub ts_ms: u64,
+    /// 1 for a plain sample, nanoseconds blocked for off-cpu samples
+    pub weight: u64,
+    /// Value captured from a probe argument, e.g. malloc size
+    pub value: Option<u64>,
+    /// Only set in wall-clock mode, splits the tree per thread and tags the leaf
+    pub kind: Option<SampleKind>,
+    /// Only set for latency probes, log2 of the call duration in ns, the duration is the value
+    pub latency_bucket: Option<u32>,
+    /// The unwinder gave up before the root of the native stack
+    #[serde(default)]
+    pub truncated: bool,
+    /// The unwinder failed, the native frames are the part of the stack it got through
+    #[serde(default)]
+    pub unwind_error: Option<UnwindError>,
+    #[serde(default)]
+    pub meta: Option<SampleMeta>,
+    /// Only set for python threads, tags the leaf
+    #[serde(default)]
+    pub gil: Option<GilState>,
     pub kernel_frames: Vec<FrameDto>,
     pub native_frames: Vec<FrameDto>,
     pub python_frames: Vec<FrameDto>,
+    /// python frames each call of the eval loop ran, root first, empty if unknown
+    #[serde(default)]
+    pub python_calls: Vec<u32>,
     pub err: Option<()>,
 }
 
 impl StackDto {
-    pub fn new(pid_tgid: PidTgid, ident: String, ts_ms: u64) -> Self {
+    pub fn new(pid_tgid: PidTgid, ident: String, ts_ms: u64, weight: u64) -> Self {
         Self {
             pid_tgid,
             ident,
             ts_ms,
+            weight,
+            value: None,
+            kind: None,
+            latency_bucket: None,
+            truncated: false,
+            unwind_error: None,
+            meta: None,
+            gil: None,
             kernel_frames: vec![],
             native_frames: vec![],
             python_frames: vec![],
+            python_calls: vec![],
             err: None,
         }
     }
@@ -62,21 +93,44 @@ impl StackDto {
     pub fn mix(self, modules: &[Arc<Module>], new_modules: &mut impl ModuleMapping) -> Vec<UnsymbolizedFrame> {
         let mut ret = vec![];
         let mut python_frames = self.python_frames.into_iter();
+        let mut python_calls = self.python_calls.into_iter();
 
         ret.push(UnsymbolizedFrame::ProcessRoot { pid_tgid: self.pid_tgid, ident: self.ident });
+        if self.kind.is_some() {
+            ret.push(UnsymbolizedFrame::ThreadRoot { tid: self.pid_tgid.pid() });
+        }
+        // the outermost frames are missing, so the native frames don't start at the real root
+        if self.truncated {
+            ret.push(UnsymbolizedFrame::Truncated);
+        }
+        if let Some(UnwindError { error, module }) = self.unwind_error {
+            ret.push(UnsymbolizedFrame::UnwindError { error, module });
+        }
 
-        for f in self.native_frames {
+        // every call of the eval loop takes the python frames it ran, one each up to 3.10.
+        // The innermost takes the rest, in case the native stack is missing calls.
+        let is_python_eval = |f: &FrameDto| match f {
+            FrameDto::Native { module_idx, offset } => modules[*module_idx as usize].is_python_eval(*offset),
+            _ => false,
+        };
+        let last_eval = self.native_frames.iter().rposition(is_python_eval);
+
+        for (i, f) in self.native_frames.into_iter().enumerate() {
             match f {
                 FrameDto::Native { module_idx, offset } => {
                     let module = &modules[module_idx as usize];
                     let new_idx = new_modules.get_index_or_insert(Arc::clone(module)).unwrap();
-                    let (py_offset, sz) = module.py_offset;
-                    if py_offset <= offset && offset <= py_offset + sz {
-                        ret.push(
-                            python_frames.next()
-                                .map(|i| i.into())
-                                .unwrap_or_else(|| UnsymbolizedFrame::Native { module_idx: new_idx, offset })
-                        );
+                    if module.is_python_eval(offset) {
+                        let len = ret.len();
+                        let ran = python_calls.next().unwrap_or(1) as usize;
+                        if last_eval == Some(i) {
+                            ret.extend(python_frames.by_ref().map(|p| p.into()));
+                        } else {
+                            ret.extend(python_frames.by_ref().take(ran).map(|p| p.into()));
+                        }
+                        if ret.len() == len {
+                            ret.push(UnsymbolizedFrame::Native { module_idx: new_idx, offset });
+                        }
                     }
                     else {
                         ret.push(UnsymbolizedFrame::Native { module_idx: new_idx, offset });
@@ -100,16 +154,99 @@ impl StackDto {
             }
         }
 
+        if let Some(state) = self.gil {
+            ret.push(UnsymbolizedFrame::Gil { state });
+        }
+
+        if let Some(kind) = self.kind {
+            ret.push(UnsymbolizedFrame::CpuState { kind });
+        }
+
+        // one leaf per power of two, so every call path ends in a latency histogram
+        if let Some(bucket) = self.latency_bucket {
+            ret.push(UnsymbolizedFrame::LatencyBucket { bucket });
+        }
+
         ret
     }
 }
 
+/// Where and when a single sample was taken, to split or filter profiles by thread, cpu or container
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SampleMeta {
+    pub tid: u32,
+    pub cpu: u32,
+    /// name of the thread
+    pub comm: String,
+    /// cgroup v2 id, the inode number of the cgroup directory
+    pub cgroup_id: u64,
+    /// monotonic clock, orders samples more finely than `ts_ms`
+    pub ktime_ns: u64,
+}
+
+/// What a python thread was doing with the GIL when it was sampled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GilState {
+    Held,
+    /// in one of the functions that take the GIL
+    Waiting,
+    /// neither, e.g. blocked on I/O or running native code that let go of it
+    Released,
+}
+
+impl GilState {
+    /// `held` is whether the thread held the GIL, the native frames tell waiting from released
+    fn new(held: bool, native_frames: &[FrameDto], modules: &[Arc<Module>]) -> Self {
+        if held {
+            return Self::Held;
+        }
+        let waiting = native_frames.iter().any(|f| match f {
+            FrameDto::Native { module_idx, offset } => modules[*module_idx as usize].is_gil_wait(*offset),
+            _ => false,
+        });
+        if waiting {
+            Self::Waiting
+        } else {
+            Self::Released
+        }
+    }
+}
+
+/// Why unwinding stopped, and the module of the outermost frame it got to
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UnwindError {
+    /// name of the `Metrics` variant
+    pub error: String,
+    pub module: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StackBatchDto {
     pub hostname: String,
     pub probe: String,
     pub stacks: Vec<StackDto>,
     pub modules: Vec<Arc<Module>>,
+    /// Agent wide, lost samples can't be attributed to a probe
+    #[serde(default)]
+    pub sample_loss: SampleLoss,
+}
+
+/// Samples produced and dropped on the way to user space, since the agent started
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct SampleLoss {
+    pub produced: u64,
+    pub lost: u64,
+}
+
+impl SampleLoss {
+    pub fn rate(&self) -> f64 {
+        if self.produced == 0 {
+            0.
+        } else {
+            self.lost as f64 / self.produced as f64
+        }
+    }
 }
 
 impl StackBatchDto {
@@ -120,6 +257,7 @@ impl StackBatchDto {
             probe,
             stacks: Default::default(),
             modules: Default::default(),
+            sample_loss: sample_loss(),
         }
     }
 
@@ -130,11 +268,30 @@ impl StackBatchDto {
         proc_map_cache: &mut ProcMapCache,
         module_cache: &mut ModuleCache,
     ) -> Result<StackBatchDto> {
+        let wall_clock_period = match &*probe {
+            Probe::WallClock { period, .. } => Some(*period),
+            _ => None,
+        };
+        let is_latency = matches!(&*probe, Probe::Latency { .. });
         let mut batch = StackBatchDto::new(probe);
         for bpf_sample in samples {
-            let ident = process_info_cache.get(bpf_sample.pid_tgid.pid()).map(|i|i.ident).unwrap_or_default();
-            let mut dto = StackDto::new(bpf_sample.pid_tgid, ident, bpf_sample.ts_ms);
+            let ident = process_info_cache.get(bpf_sample.pid_tgid.tgid()).map(|i|i.ident).unwrap_or_default();
+            let mut dto = StackDto::new(bpf_sample.pid_tgid, ident, bpf_sample.ts_ms, bpf_sample.weight);
+            dto.value = bpf_sample.value;
+            dto.meta = bpf_sample.meta;
+            if let Some(period) = wall_clock_period {
+                // task clock period is in ns, so on-cpu samples weigh the same as off-cpu time
+                if bpf_sample.kind == SampleKind::OnCpu {
+                    dto.weight = period;
+                }
+                dto.kind = Some(bpf_sample.kind);
+            }
+            if is_latency {
+                dto.latency_bucket = Some(u64::BITS - bpf_sample.value.unwrap_or(0).max(1).leading_zeros() - 1);
+            }
+            let holds_gil = bpf_sample.python_stack.as_ref().and_then(|s| s.holds_gil);
             if let Some(s) = bpf_sample.python_stack {
+                dto.python_calls = s.eval_calls.into_iter().rev().collect();
                 dto.python_frames = s
                     .frames
                     .into_iter()
@@ -142,13 +299,14 @@ impl StackBatchDto {
                     .map(|name| FrameDto::Python { name })
                     .collect();
             }
-            if let Ok(native_frames) =
-                from_native_stack(&mut batch, bpf_sample.native_stack, bpf_sample.pid_tgid.pid(), proc_map_cache, module_cache)
-            {
-                dto.native_frames = native_frames;
-            } else {
-                continue;
+            dto.truncated = bpf_sample.native_stack.truncated;
+            let (native_frames, unwind_error) =
+                from_native_stack(&mut batch, bpf_sample.native_stack, bpf_sample.pid_tgid.tgid(), proc_map_cache, module_cache);
+            if let Some(held) = holds_gil {
+                dto.gil = Some(GilState::new(held, &native_frames, &batch.modules));
             }
+            dto.native_frames = native_frames;
+            dto.unwind_error = unwind_error;
 
             if let Some(s) = bpf_sample.kernel_frames {
                 dto.kernel_frames = s
@@ -165,24 +323,28 @@ impl StackBatchDto {
     }
 }
 
+/// Frames root first. Walks from the leaf, so an address that can't be mapped ends
+/// the stack the same way an unwinder failure does.
 fn from_native_stack(
     batch: &mut StackBatchDto,
     native_stack: Box<NativeStack>,
-    pid: u32,
+    tgid: u32,
     proc_map_cache: &mut ProcMapCache,
     module_cache: &mut ModuleCache,
-) -> Result<Vec<FrameDto>> {
+) -> (Vec<FrameDto>, Option<UnwindError>) {
     let len = native_stack.unwind_success.unwrap_or(0);
     let mut native_frames = vec![];
-    for address in native_stack.native_stack[..len].iter().rev() {
-        let (offset, entry) = lookup(pid, proc_map_cache, *address).context("address not found")?;
-        let path = entry
-            .pathname
-            .path()
-            .context("not a path we can resolve")?
-            .to_str()
-            .context("unable to convert to str")?;
-        let module = module_cache.resolve(path).context("module not found")?;
+    let mut error = native_stack.unwind_error;
+    let mut last_module = None;
+    for address in native_stack.native_stack[..len].iter() {
+        let (offset, module) = match native_module(tgid, proc_map_cache, module_cache, *address) {
+            Ok(found) => found,
+            Err(_) => {
+                error = Some(Metrics::ErrSample_NoMapping);
+                break;
+            }
+        };
+        last_module = Some(module.name.clone());
         let module_idx = match batch.modules.iter().position(|m| Arc::ptr_eq(m, &module)) {
             Some(idx) => idx,
             None => {
@@ -193,7 +355,28 @@ fn from_native_stack(
 
         native_frames.push(FrameDto::Native { module_idx: module_idx as i32, offset: offset as u32 });
     }
-    Ok(native_frames)
+    native_frames.reverse();
+
+    let error = error.map(|e| UnwindError { error: format!("{e:?}"), module: last_module });
+    (native_frames, error)
+}
+
+/// The module an address falls in and its offset into the file
+pub(crate) fn native_module(
+    pid: u32,
+    proc_map_cache: &mut ProcMapCache,
+    module_cache: &mut ModuleCache,
+    address: usize,
+) -> Result<(usize, Arc<Module>)> {
+    let (offset, entry) = lookup(pid, proc_map_cache, address).context("address not found")?;
+    let path = entry
+        .pathname
+        .path()
+        .context("not a path we can resolve")?
+        .to_str()
+        .context("unable to convert to str")?;
+    let module = module_cache.resolve(path).context("module not found")?;
+    Ok((offset, module))
 }
 
 fn lookup(pid: u32, proc_map_cache: &mut ProcMapCache, address: usize) -> Option<(usize, MemoryMap)> {
@@ -217,6 +400,7 @@ fn lookup(pid: u32, proc_map_cache: &mut ProcMapCache, address: usize) -> Option
     translated()
 }
 
+/// Stored bincode encoded in tail2-db, new variants go at the end
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub enum UnsymbolizedFrame {
     None,
@@ -224,6 +408,12 @@ pub enum UnsymbolizedFrame {
     Native { module_idx: i32, offset: u32 },
     Python { name: String },
     Kernel { name: String },
+    ThreadRoot { tid: u32 },
+    CpuState { kind: SampleKind },
+    LatencyBucket { bucket: u32 },
+    Truncated,
+    UnwindError { error: String, module: Option<String> },
+    Gil { state: GilState },
 }
 
 impl Default for UnsymbolizedFrame {
@@ -252,6 +442,20 @@ impl UnsymbolizedFrame {
                 name: Some(format!("{}:{}", pid_tgid.tgid(), ident)),
                 code_type: crate::calltree::CodeType::ProcessRoot
             },
+            UnsymbolizedFrame::ThreadRoot { tid } => SymbolizedFrame {
+                module_idx: 0,
+                offset: 0,
+                name: Some(format!("thread {tid}")),
+                code_type: crate::calltree::CodeType::Thread
+            },
+            UnsymbolizedFrame::CpuState { kind: SampleKind::OnCpu } => SymbolizedFrame { module_idx: 0, offset: 0, name: Some("[on-cpu]".to_owned()), code_type: crate::calltree::CodeType::OnCpu },
+            UnsymbolizedFrame::LatencyBucket { bucket } => SymbolizedFrame {
+                module_idx: 0,
+                offset: 0,
+                name: Some(format!("[{} - {}]", human_ns(1 << bucket), 1u64.checked_shl(bucket + 1).map_or("inf".to_owned(), human_ns))),
+                code_type: crate::calltree::CodeType::Latency
+            },
+            UnsymbolizedFrame::CpuState { kind: SampleKind::OffCpu } => SymbolizedFrame { module_idx: 0, offset: 0, name: Some("[off-cpu]".to_owned()), code_type: crate::calltree::CodeType::OffCpu },
             UnsymbolizedFrame::Native { module_idx, offset } => {
                 let module = Arc::clone(&modules.get(module_idx as usize));
                 let name = 
@@ -266,10 +470,39 @@ impl UnsymbolizedFrame {
             },
             UnsymbolizedFrame::Python { name } => SymbolizedFrame { module_idx: 0, offset: 0, name: Some(name), code_type: crate::calltree::CodeType::Python },
             UnsymbolizedFrame::Kernel { name } => SymbolizedFrame { module_idx: 0, offset: 0, name: Some(name), code_type: crate::calltree::CodeType::Kernel },
+            UnsymbolizedFrame::Truncated => SymbolizedFrame { module_idx: 0, offset: 0, name: Some("[truncated]".to_owned()), code_type: crate::calltree::CodeType::Truncated },
+            UnsymbolizedFrame::UnwindError { error, module } => SymbolizedFrame {
+                module_idx: 0,
+                offset: 0,
+                name: Some(match module {
+                    Some(module) => format!("[unwind error: {error}] {module}"),
+                    None => format!("[unwind error: {error}]"),
+                }),
+                code_type: crate::calltree::CodeType::UnwindError
+            },
+            UnsymbolizedFrame::Gil { state } => SymbolizedFrame {
+                module_idx: 0,
+                offset: 0,
+                name: Some(match state {
+                    GilState::Held => "[gil:held]",
+                    GilState::Waiting => "[gil:waiting]",
+                    GilState::Released => "[gil:released]",
+                }.to_owned()),
+                code_type: crate::calltree::CodeType::Gil
+            },
         }
     }
 }
 
+fn human_ns(ns: u64) -> String {
+    match ns {
+        0..=999 => format!("{ns}ns"),
+        1_000..=999_999 => format!("{}us", ns / 1_000),
+        1_000_000..=999_999_999 => format!("{}ms", ns / 1_000_000),
+        _ => format!("{}s", ns / 1_000_000_000),
+    }
+}
+
 pub struct ModuleMap {
     map: Vec<Arc<Module>>
 }
@@ -313,4 +546,118 @@ pub trait ModuleMapping {
 
     /// get the module based on index
     fn get(&mut self, idx: usize) -> Arc<Module>;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn module(debug_id: &str, py_ranges: Vec<(u32, u32)>, gil_ranges: Vec<(u32, u32)>) -> Arc<Module> {
+        Arc::new(Module {
+            unwind_table: None,
+            path: format!("/{debug_id}"),
+            name: debug_id.to_owned(),
+            arch: 0,
+            debug_id: debug_id.to_owned(),
+            py_ranges,
+            gil_ranges,
+        })
+    }
+
+    /// Module 0 is the program, module 1 is python with its eval loop at 0x100..0x200
+    /// and a function that waits for the GIL at 0x400..0x420
+    fn modules() -> [Arc<Module>; 2] {
+        [module("app", vec![], vec![]), module("python", vec![(0x100, 0x100)], vec![(0x400, 0x20)])]
+    }
+
+    fn native(module_idx: i32, offset: u32) -> UnsymbolizedFrame {
+        UnsymbolizedFrame::Native { module_idx, offset }
+    }
+
+    fn python(name: &str) -> UnsymbolizedFrame {
+        UnsymbolizedFrame::Python { name: name.to_owned() }
+    }
+
+    fn stack(native_frames: &[(i32, u32)], python_frames: &[&str], python_calls: &[u32]) -> StackDto {
+        let mut dto = StackDto::new(PidTgid::default(), "test".to_owned(), 0, 1);
+        dto.native_frames = native_frames.iter().map(|&(module_idx, offset)| FrameDto::Native { module_idx, offset }).collect();
+        dto.python_frames = python_frames.iter().map(|name| FrameDto::Python { name: name.to_string() }).collect();
+        dto.python_calls = python_calls.to_vec();
+        dto
+    }
+
+    /// Mixed frames below the process root
+    fn mix(dto: StackDto) -> Vec<UnsymbolizedFrame> {
+        let mut ret = dto.mix(&modules(), &mut ModuleMap::new());
+        assert!(matches!(ret.remove(0), UnsymbolizedFrame::ProcessRoot { .. }));
+        ret
+    }
+
+    #[test]
+    fn test_mix_eval_calls() {
+        // 3.11+, python called back from a builtin at 0x300 starts a second call of the eval loop
+        let frames = mix(stack(
+            &[(0, 0x10), (1, 0x150), (1, 0x300), (1, 0x150)],
+            &["<module>", "Root.run", "middle", "leaf", "dump"],
+            &[3, 2],
+        ));
+        assert_eq!(
+            frames,
+            [native(0, 0x10), python("<module>"), python("Root.run"), python("middle"), native(1, 0x300), python("leaf"), python("dump")]
+        );
+
+        // up to 3.10 every call runs one frame
+        let frames = mix(stack(&[(0, 0x10), (1, 0x150), (1, 0x160), (1, 0x300)], &["a", "b"], &[1, 1]));
+        assert_eq!(frames, [native(0, 0x10), python("a"), python("b"), native(1, 0x300)]);
+    }
+
+    #[test]
+    fn test_mix_mismatched_calls() {
+        // unknown calls, one frame per call and the innermost takes the rest
+        let frames = mix(stack(&[(1, 0x150), (1, 0x150)], &["a", "b", "c"], &[]));
+        assert_eq!(frames, [python("a"), python("b"), python("c")]);
+
+        // a call without python frames left stays native
+        let frames = mix(stack(&[(0, 0x10), (1, 0x150), (1, 0x150)], &["a"], &[1, 1]));
+        assert_eq!(frames, [native(0, 0x10), python("a"), native(1, 0x150)]);
+    }
+
+    #[test]
+    fn test_gil_state() {
+        let modules = modules();
+        let frames = |offsets: &[(i32, u32)]| -> Vec<FrameDto> {
+            offsets.iter().map(|&(module_idx, offset)| FrameDto::Native { module_idx, offset }).collect()
+        };
+
+        // holding the GIL wins over the frames, e.g. a thread that just took it
+        assert_eq!(GilState::new(true, &frames(&[(0, 0x10), (1, 0x410)]), &modules), GilState::Held);
+        assert_eq!(GilState::new(false, &frames(&[(0, 0x10), (1, 0x150), (1, 0x410)]), &modules), GilState::Waiting);
+        // 0x420 is past the end of the wait function
+        assert_eq!(GilState::new(false, &frames(&[(0, 0x10), (1, 0x150), (1, 0x420)]), &modules), GilState::Released);
+        assert_eq!(GilState::new(false, &[], &modules), GilState::Released);
+    }
+
+    #[test]
+    fn test_mix_gil_leaf() {
+        // the GIL state goes below the kernel frames and above the cpu state
+        let mut dto = stack(&[(0, 0x10), (1, 0x150), (1, 0x410)], &["a"], &[1]);
+        dto.kind = Some(SampleKind::OffCpu);
+        dto.gil = Some(GilState::Waiting);
+        dto.kernel_frames = vec![FrameDto::Kernel { name: "schedule".to_owned() }];
+        assert_eq!(
+            mix(dto),
+            [
+                UnsymbolizedFrame::ThreadRoot { tid: 0 },
+                native(0, 0x10),
+                python("a"),
+                native(1, 0x410),
+                UnsymbolizedFrame::Kernel { name: "schedule".to_owned() },
+                UnsymbolizedFrame::Gil { state: GilState::Waiting },
+                UnsymbolizedFrame::CpuState { kind: SampleKind::OffCpu },
+            ]
+        );
+
+        // no state for threads that aren't python
+        let frames = mix(stack(&[(0, 0x10)], &[], &[]));
+        assert_eq!(frames, [native(0, 0x10)]);
+    }
+}
@@ -10,6 +10,7 @@ pub mod symbolication;
 pub mod utils;
 pub mod tail2;
 pub mod probes;
+pub mod unwind_report;
 
 pub use crate::tail2::Tail2;
 pub use calltree::traits::Mergeable;
\ No newline at end of file
@@ -0,0 +1,225 @@
+//! Perf event links that carry the probe id as their bpf cookie.
+//! aya's attach calls can't set a cookie, so the perf events are opened here and linked to the
+//! programs aya loaded with `BPF_LINK_CREATE`, which needs kernel 5.15.
+
+use std::{ffi::CString, fs, os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd}, path::{Path, PathBuf}, process::Command};
+
+use anyhow::{Context, Result};
+use aya::programs::SamplePolicy;
+use object::{Object, ObjectSection, ObjectSymbol};
+use procfs::process::{MMapPath, Process};
+
+use super::probe::ProbeId;
+
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_PERF_EVENT: u32 = 41;
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_FLAG_PID_CGROUP: libc::c_ulong = 4;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+/// `perf_event_attr.freq`, the sample period is a frequency
+const PERF_ATTR_FLAG_FREQ: u64 = 1 << 10;
+
+/// `perf_event_attr` up to `config2`, the kernel takes the size of the version it's given
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period_or_freq: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+}
+
+/// `bpf_attr.link_create` for perf events
+#[repr(C)]
+#[derive(Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+    bpf_cookie: u64,
+    _pad: [u64; 4],
+}
+
+/// Tasks a sampling perf event counts
+pub enum PerfTarget {
+    Process(i32),
+    AllProcesses,
+    /// open directory of a cgroup v2, which covers the cgroups below it as well
+    Cgroup(RawFd),
+}
+
+/// Detaches when dropped
+pub struct CookieLink {
+    _link: OwnedFd,
+    _perf_event: OwnedFd,
+}
+
+impl CookieLink {
+    pub fn detach(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sampling perf event on one cpu
+pub fn attach_perf_event(prog_fd: RawFd, id: ProbeId, perf_type: u32, config: u64, policy: &SamplePolicy, target: &PerfTarget, cpu: i32) -> Result<CookieLink> {
+    let mut attr = PerfEventAttr { kind: perf_type, config, ..Default::default() };
+    match policy {
+        SamplePolicy::Period(period) => attr.sample_period_or_freq = *period,
+        SamplePolicy::Frequency(hz) => {
+            attr.sample_period_or_freq = *hz;
+            attr.flags |= PERF_ATTR_FLAG_FREQ;
+        }
+    }
+    let perf_event = match target {
+        PerfTarget::Process(pid) => perf_event_open(attr, *pid, cpu, 0)?,
+        PerfTarget::AllProcesses => perf_event_open(attr, -1, cpu, 0)?,
+        PerfTarget::Cgroup(fd) => perf_event_open(attr, *fd, cpu, PERF_FLAG_PID_CGROUP)?,
+    };
+    link(prog_fd, id, perf_event)
+}
+
+/// `uprobe` is in the form of "module:function", the module is a path or a library name like "libc"
+pub fn attach_uprobe(prog_fd: RawFd, id: ProbeId, uprobe: &str, pid: Option<i32>, retprobe: bool) -> Result<CookieLink> {
+    let (src, func) = uprobe.split_once(':').context("expected module:function")?;
+    let path = resolve_module(src, pid)?;
+    let offset = symbol_offset(&path, func)?;
+    let path = CString::new(path.to_string_lossy().as_bytes())?;
+
+    let mut attr = PerfEventAttr {
+        kind: pmu_type("uprobe")?,
+        config1: path.as_ptr() as u64,
+        config2: offset,
+        ..Default::default()
+    };
+    if retprobe {
+        attr.config |= 1 << retprobe_bit("uprobe")?;
+    }
+    let cpu = if pid.is_some() { -1 } else { 0 };
+    let perf_event = perf_event_open(attr, pid.unwrap_or(-1), cpu, 0)?;
+    link(prog_fd, id, perf_event)
+}
+
+pub fn attach_kprobe(prog_fd: RawFd, id: ProbeId, function: &str) -> Result<CookieLink> {
+    let function = CString::new(function)?;
+    let attr = PerfEventAttr {
+        kind: pmu_type("kprobe")?,
+        config1: function.as_ptr() as u64,
+        ..Default::default()
+    };
+    let perf_event = perf_event_open(attr, -1, 0, 0)?;
+    link(prog_fd, id, perf_event)
+}
+
+pub fn attach_tracepoint(prog_fd: RawFd, id: ProbeId, category: &str, name: &str) -> Result<CookieLink> {
+    let attr = PerfEventAttr {
+        kind: PERF_TYPE_TRACEPOINT,
+        config: tracepoint_id(category, name)?,
+        ..Default::default()
+    };
+    let perf_event = perf_event_open(attr, -1, 0, 0)?;
+    link(prog_fd, id, perf_event)
+}
+
+/// `pid` is a cgroup fd with `PERF_FLAG_PID_CGROUP`
+fn perf_event_open(mut attr: PerfEventAttr, pid: i32, cpu: i32, flags: libc::c_ulong) -> Result<OwnedFd> {
+    attr.size = std::mem::size_of::<PerfEventAttr>() as u32;
+    let fd = unsafe { libc::syscall(libc::SYS_perf_event_open, &attr, pid, cpu, -1, flags | PERF_FLAG_FD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("perf_event_open failed");
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn link(prog_fd: RawFd, id: ProbeId, perf_event: OwnedFd) -> Result<CookieLink> {
+    let attr = LinkCreateAttr {
+        prog_fd: prog_fd as u32,
+        target_fd: perf_event.as_raw_fd() as u32,
+        attach_type: BPF_PERF_EVENT,
+        bpf_cookie: id as u64,
+        ..Default::default()
+    };
+    let fd = unsafe { libc::syscall(libc::SYS_bpf, BPF_LINK_CREATE, &attr, std::mem::size_of::<LinkCreateAttr>()) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("bpf link_create failed");
+    }
+    Ok(CookieLink {
+        _link: unsafe { OwnedFd::from_raw_fd(fd as RawFd) },
+        _perf_event: perf_event,
+    })
+}
+
+/// Dynamic pmu type of kprobes and uprobes
+fn pmu_type(pmu: &str) -> Result<u32> {
+    let path = format!("/sys/bus/event_source/devices/{pmu}/type");
+    let kind = fs::read_to_string(&path).with_context(|| format!("unable to read {path}"))?;
+    Ok(kind.trim().parse()?)
+}
+
+/// Bit of `config` that makes a probe a return probe, the file reads e.g. "config:0"
+fn retprobe_bit(pmu: &str) -> Result<u32> {
+    let path = format!("/sys/bus/event_source/devices/{pmu}/format/retprobe");
+    let format = fs::read_to_string(&path).with_context(|| format!("unable to read {path}"))?;
+    let bit = format.trim().strip_prefix("config:").context("unexpected retprobe format")?;
+    Ok(bit.parse()?)
+}
+
+fn tracepoint_id(category: &str, name: &str) -> Result<u64> {
+    let id = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"]
+        .iter()
+        .find_map(|root| fs::read_to_string(format!("{root}/events/{category}/{name}/id")).ok())
+        .with_context(|| format!("no such tracepoint: {category}:{name}"))?;
+    Ok(id.trim().parse()?)
+}
+
+/// A library is looked up in the process if there is one, then in the ld cache
+fn resolve_module(src: &str, pid: Option<i32>) -> Result<PathBuf> {
+    if src.contains('/') {
+        return Ok(PathBuf::from(src));
+    }
+    let is_lib = |name: &str| {
+        let name = name.strip_prefix("lib").unwrap_or(name);
+        let src = src.strip_prefix("lib").unwrap_or(src);
+        name.strip_prefix(src).is_some_and(|rest| rest.starts_with(".so") || rest.starts_with('-'))
+    };
+
+    if let Some(pid) = pid {
+        let found = Process::new(pid)?.maps()?.into_iter().find_map(|m| match m.pathname {
+            MMapPath::Path(p) if p.file_name().and_then(|n| n.to_str()).is_some_and(is_lib) => Some(p),
+            _ => None,
+        });
+        if let Some(path) = found {
+            return Ok(path);
+        }
+    }
+
+    // lines look like "	libc.so.6 (libc6,x86-64) => /lib/x86_64-linux-gnu/libc.so.6"
+    let cache = Command::new("ldconfig").arg("-p").output()?;
+    String::from_utf8_lossy(&cache.stdout)
+        .lines()
+        .filter_map(|line| line.trim().split_once(" => "))
+        .find(|(lib, _)| lib.split_whitespace().next().is_some_and(is_lib))
+        .map(|(_, path)| PathBuf::from(path))
+        .with_context(|| format!("no such library: {src}"))
+}
+
+/// File offset of `func`, which is where uprobes are placed
+fn symbol_offset(path: &Path, func: &str) -> Result<u64> {
+    let data = fs::read(path).with_context(|| format!("unable to read {}", path.display()))?;
+    let obj = object::File::parse(&*data)?;
+    let sym = obj
+        .dynamic_symbols()
+        .chain(obj.symbols())
+        .find(|s| s.name().is_ok_and(|n| n == func) && s.address() != 0)
+        .with_context(|| format!("no symbol {func} in {}", path.display()))?;
+    let section = obj.section_by_index(sym.section_index().context("symbol without a section")?)?;
+    let (file_start, _) = section.file_range().context("section without file data")?;
+    Ok(sym.address() - section.address() + file_start)
+}
@@ -0,0 +1,122 @@
+use std::{sync::Arc, time::{Duration, SystemTime}};
+
+use anyhow::{Result, Context};
+use aya::{maps::HashMap, Bpf};
+use fnv::FnvHashMap;
+use tail2_common::{bpf_sample::SampleKind, memory::{AllocKey, LiveAlloc, MemStack}, metrics::Metrics, pidtgid::PidTgid, NativeStack};
+use tokio::{sync::Mutex, task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{client::PostStackClient, dto::resolved_bpf_sample::ResolvedBpfSample};
+
+use super::probe::ProbeId;
+
+/// How often outstanding allocations are reported
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Library the allocation functions are hooked in
+pub(crate) const ALLOC_LIBRARY: &str = "libc";
+
+/// Drop the allocations tracked for a probe once it is detached
+pub(crate) fn clear_live_allocs(bpf: &mut Bpf, id: ProbeId) -> Result<()> {
+    let mut live: HashMap<_, AllocKey, LiveAlloc> =
+        HashMap::try_from(bpf.map_mut("LIVE_ALLOCS").context("no such map")?)?;
+    let stale: Vec<AllocKey> = live
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| k.probe_id == id)
+        .collect();
+    for k in stale {
+        let _ = live.remove(&k);
+    }
+    Ok(())
+}
+
+/// Periodically post a snapshot of the allocations that are still outstanding, per allocation stack.
+/// The maps are borrowed for each snapshot, other probes clear their entries in between.
+pub(crate) fn spawn_reporter(bpf: Arc<Mutex<Bpf>>, id: ProbeId, cli: Arc<Mutex<PostStackClient>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // halfway through the intervals since the epoch, so every interval gets one snapshot
+        let period = REPORT_INTERVAL.as_millis() as u64;
+        let now = now_ms();
+        let first = now - now % period + period / 2;
+        let first = if first <= now { first + period } else { first };
+        let start = tokio::time::Instant::now() + Duration::from_millis(first - now);
+        let mut interval = tokio::time::interval_at(start, REPORT_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if let Err(e) = report(&bpf, id, &cli).await {
+                tracing::error!("reporting live allocations failed: {}", e.to_string());
+            }
+        }
+    })
+}
+
+/// Time range of the last complete snapshot at `now_ms`, the profile of a memory probe.
+/// Snapshots aren't deltas, summing them over a longer range counts allocations several times.
+pub fn snapshot_range(now_ms: i64) -> (i64, i64) {
+    let interval = REPORT_INTERVAL.as_millis() as i64;
+    let end = now_ms - now_ms % interval;
+    (end - interval, end)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// One sample per allocation site, weighted by the number of outstanding allocations and
+/// valued by the bytes they hold
+async fn report(bpf: &Mutex<Bpf>, id: ProbeId, cli: &Mutex<PostStackClient>) -> Result<()> {
+    let mut outstanding: FnvHashMap<(u32, u64), (u64, u64)> = FnvHashMap::default();
+    let mut samples = vec![];
+    {
+        let bpf = bpf.lock().await;
+        let live: HashMap<_, AllocKey, LiveAlloc> =
+            HashMap::try_from(bpf.map("LIVE_ALLOCS").context("no such map")?)?;
+        let stacks: HashMap<_, u64, MemStack> =
+            HashMap::try_from(bpf.map("MEM_STACKS").context("no such map")?)?;
+        for (key, alloc) in live.iter().filter_map(|i| i.ok()) {
+            if key.probe_id != id {
+                continue;
+            }
+            let (count, bytes) = outstanding.entry((key.tgid, alloc.stack_id)).or_default();
+            *count += 1;
+            *bytes += alloc.size;
+        }
+        for ((tgid, stack_id), (count, bytes)) in outstanding {
+            let st = match stacks.get(&stack_id, 0) {
+                Ok(st) => st,
+                // the stack was evicted from MEM_STACKS, the allocations go under the process
+                Err(_) => {
+                    let mut native_stack = NativeStack::new();
+                    native_stack.unwind_success = Some(0);
+                    native_stack.unwind_error = Some(Metrics::ErrMem_NoStack);
+                    MemStack { pidtgid: PidTgid::current(tgid, tgid), native_stack }
+                }
+            };
+            samples.push((st, count, bytes));
+        }
+    }
+
+    let ts_ms = now_ms();
+    let mut cli = cli.lock().await;
+    for (st, count, bytes) in samples {
+        cli.post_stack(ResolvedBpfSample {
+            pid_tgid: st.pidtgid,
+            ts_ms,
+            weight: count,
+            kind: SampleKind::OnCpu,
+            value: Some(bytes),
+            native_stack: Box::new(st.native_stack),
+            python_stack: None,
+            kernel_frames: None,
+            meta: None,
+        }).await?;
+    }
+    cli.flush().await?;
+
+    Ok(())
+}
@@ -1,5 +1,12 @@
 pub mod scope;
 pub use scope::Scope;
 
+pub mod perf;
+pub use perf::{PerfEventKind, Sampling};
+
+pub mod memory;
+
+pub mod link;
+
 pub mod probe;
 pub use probe::Probe;
\ No newline at end of file
@@ -0,0 +1,90 @@
+use std::fmt::{Display, Formatter};
+
+use aya::programs::{perf_event::{perf_hw_id, perf_sw_ids}, PerfTypeId, SamplePolicy};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Event that triggers a sample
+#[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Copy, Debug, Default, ValueEnum)]
+pub enum PerfEventKind {
+    #[default]
+    TaskClock,
+    CpuClock,
+    PageFaults,
+    ContextSwitches,
+    CpuMigrations,
+    /// hardware counters, not available on most VMs
+    Cycles,
+    Instructions,
+    CacheMisses,
+    BranchMisses,
+}
+
+impl PerfEventKind {
+    pub fn type_and_config(&self) -> (PerfTypeId, u64) {
+        match self {
+            PerfEventKind::TaskClock => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_TASK_CLOCK as u64),
+            PerfEventKind::CpuClock => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_CPU_CLOCK as u64),
+            PerfEventKind::PageFaults => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_PAGE_FAULTS as u64),
+            PerfEventKind::ContextSwitches => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_CONTEXT_SWITCHES as u64),
+            PerfEventKind::CpuMigrations => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_CPU_MIGRATIONS as u64),
+            PerfEventKind::Cycles => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_CPU_CYCLES as u64),
+            PerfEventKind::Instructions => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_INSTRUCTIONS as u64),
+            PerfEventKind::CacheMisses => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_CACHE_MISSES as u64),
+            PerfEventKind::BranchMisses => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_BRANCH_MISSES as u64),
+        }
+    }
+}
+
+impl Display for PerfEventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PerfEventKind::TaskClock => write!(f, "task_clock"),
+            PerfEventKind::CpuClock => write!(f, "cpu_clock"),
+            PerfEventKind::PageFaults => write!(f, "page_faults"),
+            PerfEventKind::ContextSwitches => write!(f, "context_switches"),
+            PerfEventKind::CpuMigrations => write!(f, "cpu_migrations"),
+            PerfEventKind::Cycles => write!(f, "cycles"),
+            PerfEventKind::Instructions => write!(f, "instructions"),
+            PerfEventKind::CacheMisses => write!(f, "cache_misses"),
+            PerfEventKind::BranchMisses => write!(f, "branch_misses"),
+        }
+    }
+}
+
+/// Sample every `period` events, or `hz` times a second
+#[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "type")]
+pub enum Sampling {
+    Period {
+        period: u64,
+    },
+    Frequency {
+        hz: u64,
+    },
+}
+
+impl Default for Sampling {
+    /// Same as the cli default
+    fn default() -> Self {
+        Sampling::Period { period: 4000000 }
+    }
+}
+
+impl Sampling {
+    pub fn policy(&self) -> SamplePolicy {
+        match self {
+            Sampling::Period { period } => SamplePolicy::Period(*period),
+            Sampling::Frequency { hz } => SamplePolicy::Frequency(*hz),
+        }
+    }
+}
+
+impl Display for Sampling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sampling::Period { period } => write!(f, "p{}", period),
+            Sampling::Frequency { hz } => write!(f, "f{}hz", hz),
+        }
+    }
+}
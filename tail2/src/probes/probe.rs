@@ -1,174 +1,360 @@
-use std::{sync::{atomic::AtomicBool, Arc}, fmt::{Display, Formatter}};
+use std::{sync::Arc, fmt::{Display, Formatter}};
+use std::{fs::File, os::fd::{AsRawFd, RawFd}};
 
 use anyhow::{Result, Context};
-use aya::{programs::{UProbe, PerfEvent, SamplePolicy, perf_event, PerfEventScope, PerfTypeId, Program, Link}, util::online_cpus, Bpf};
-use aya::programs::uprobe::UProbeLink;
-use aya::programs::perf_event::PerfEventLink;
+use aya::{programs::{SamplePolicy, Program}, util::online_cpus, Bpf};
+use aya::maps::{Array, HashMap};
 use serde::{Deserialize, Serialize};
+use tail2_common::{scope::{ProbeScope, ScopeKind}, MAX_PIDS_SCOPES};
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
-use crate::{tail2::Probes, client::PostStackClient};
+use crate::{tail2::{Probes, Clients}, client::PostStackClient};
 
-use super::Scope;
+use super::{Scope, PerfEventKind, Sampling, link::{self, CookieLink, PerfTarget}, scope::cgroup_dir, memory::{clear_live_allocs, spawn_reporter, ALLOC_LIBRARY}};
 
-pub struct ProbePool {
-    available: Vec<Arc<AtomicBool>>,
-}
-
-impl ProbePool {
-    pub(crate) fn new(n: usize) -> ProbePool {
-        Self {
-            available: vec![true; n].into_iter().map(|i| Arc::new(AtomicBool::new(i))).collect(),
-        }
-    }
-
-    pub(crate) fn next_avail(&self) -> Option<(usize, Arc<AtomicBool>)> {
-        for (i, n) in self.available.iter().enumerate() {
-            if n.load(std::sync::atomic::Ordering::Relaxed) {
-                n.store(false, std::sync::atomic::Ordering::Relaxed);
-                return Some((i, n.clone()));
-            }
-        }
-
-        None
-    }
-}
+/// Identifies a probe in the bpf programs, passed as the attach cookie of every link
+pub type ProbeId = u32;
 
 #[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum Probe {
     Perf {
         scope: Scope,
-        period: u64,
+        #[serde(default)]
+        event: PerfEventKind,
+        #[serde(default)]
+        sampling: Sampling,
+        /// count unique stacks in the kernel and send them periodically instead of every sample
+        #[serde(default)]
+        aggregate: bool,
     },
     Uprobe {
         scope: Scope,
         uprobe: String,
+        /// capture this argument with every sample, e.g. 0 for the size passed to malloc
+        #[serde(default)]
+        value_arg: Option<u32>,
+    },
+    /// Stacks of blocked tasks, weighted by nanoseconds spent off cpu
+    OffCpu {
+        scope: Scope,
+    },
+    /// Time calls to a userspace function, every call counts once with the nanoseconds
+    /// from entry to return as its value
+    Latency {
+        scope: Scope,
+        uprobe: String,
+    },
+    /// Allocations that outlive a report interval, from paired malloc/free probes. Each one
+    /// is reported once, valued by its size, so reports add up over time
+    Memory {
+        scope: Scope,
+    },
+    /// Attach to a kernel function, e.g. "tcp_sendmsg"
+    Kprobe {
+        scope: Scope,
+        function: String,
+    },
+    /// Attach to a kernel tracepoint, e.g. "syscalls:sys_enter_write"
+    Tracepoint {
+        scope: Scope,
+        category: String,
+        name: String,
+    },
+    /// On-cpu samples every `period` ns plus off-cpu samples, split per thread
+    WallClock {
+        scope: Scope,
+        period: u64,
     },
 }
 
 impl Display for Probe {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Probe::Perf { scope, period } => write!(f, "perf_{}{}", scope, period),
-            Probe::Uprobe { scope, uprobe } => write!(f, "uprobe_{}_{}", scope, uprobe),
+            Probe::Perf { scope, event, sampling, aggregate: false } => write!(f, "perf_{}_{}_{}", scope, event, sampling),
+            Probe::Perf { scope, event, sampling, aggregate: true } => write!(f, "perf_{}_{}_{}_agg", scope, event, sampling),
+            Probe::Uprobe { scope, uprobe, value_arg: None } => write!(f, "uprobe_{}_{}", scope, uprobe),
+            Probe::Uprobe { scope, uprobe, value_arg: Some(arg) } => write!(f, "uprobe_{}_{}_arg{}", scope, uprobe, arg),
+            Probe::OffCpu { scope } => write!(f, "offcpu_{}", scope),
+            Probe::WallClock { scope, period } => write!(f, "wallclock_{}{}", scope, period),
+            Probe::Latency { scope, uprobe } => write!(f, "latency_{}_{}", scope, uprobe),
+            Probe::Memory { scope } => write!(f, "memory_{}", scope),
+            Probe::Kprobe { scope, function } => write!(f, "kprobe_{}_{}", scope, function),
+            Probe::Tracepoint { scope, category, name } => write!(f, "tracepoint_{}_{}:{}", scope, category, name),
         }
     }
 }
 
-pub enum MyPerfLink {
-    UProbe(UProbeLink),
-    PerfEvent(PerfEventLink),
-}
-
-impl From<UProbeLink> for MyPerfLink {
-    fn from(l: UProbeLink) -> Self {
-        Self::UProbe(l)
-    }
-}
-
-impl From<PerfEventLink> for MyPerfLink {
-    fn from(l: PerfEventLink) -> Self {
-        Self::PerfEvent(l)
-    }
-}
-
-impl MyPerfLink {
-    pub fn detach(self) -> Result<()> {
-        Ok(match self {
-            MyPerfLink::UProbe(l) => l.detach()?,
-            MyPerfLink::PerfEvent(l) => l.detach()?,
-        })
-    }
-}
-
 pub struct Attachment {
-    pub links: Vec<MyPerfLink>,
-    pub idx: usize,
-    pub avail: Arc<AtomicBool>,
+    pub links: Vec<CookieLink>,
+    pub id: ProbeId,
     pub cli: Arc<Mutex<PostStackClient>>,
+    /// Periodic task for probes that report state instead of streaming samples
+    pub reporter: Option<JoinHandle<()>>,
 }
 
 impl Attachment {
-    pub async fn detach(self) {
-        self.avail.store(true, std::sync::atomic::Ordering::Relaxed);
+    pub async fn detach(self, bpf: &mut Bpf, clients: &Clients) {
+        if let Some(reporter) = self.reporter {
+            reporter.abort();
+        }
 
         for link in self.links {
             link.detach().unwrap();
         }
 
+        // samples still in flight for this id are dropped by the receiver
+        clients.lock().await.remove(&self.id);
+        let _ = set_scope_filter(bpf, self.id, &Scope::SystemWide);
+        let _ = set_value_arg(bpf, self.id, None);
+        let _ = set_aggregate(bpf, self.id, false);
+        let _ = clear_live_allocs(bpf, self.id);
+
         self.cli.lock().await.flush().await.unwrap();
     }
 }
 
 impl Probe {
-    pub fn to_program<'a>(&'a self, bpf: &'a mut Bpf, probe_pool: &ProbePool) -> Option<(&mut Program, usize, Arc<AtomicBool>)> {
-        let (i, avail) = probe_pool.next_avail()?;
-        let ret = match self {
-            Probe::Perf{ .. } => bpf.program_mut(&format!("capture_stack_{i}")).unwrap(),
-            Probe::Uprobe { .. } => bpf.program_mut(&format!("malloc_enter_{i}")).unwrap(),
-        };
+    pub async fn attach(&self, shared_bpf: &Arc<Mutex<Bpf>>, probes: &Probes) -> Result<Attachment> {
+        let id = probes.next_id();
 
-        Some((ret, i, avail))
-    }
+        let cli = Arc::new(Mutex::new(PostStackClient::new(Arc::new(self.clone()))));
+        probes.clients.lock().await.insert(id, Arc::clone(&cli));
 
-    pub async fn attach(&self, bpf: &mut Bpf, probes: &Probes) -> Result<Attachment> {
-        let (program, idx, avail) = self.to_program(bpf, &probes.probe_pool).context("No probe function available")?;
+        let bpf = &mut *shared_bpf.lock().await;
+        let ret = set_scope_filter(bpf, id, self.scope())
+            .and_then(|_| self.attach_links(bpf, id))
+            .and_then(|mut links| {
+                if let Scope::Descendants{..} = self.scope() {
+                    let program = program_fd(bpf, "follow_fork")?;
+                    links.push(link::attach_tracepoint(program, id, "task", "task_newtask")?);
+                    let program = program_fd(bpf, "follow_exec")?;
+                    links.push(link::attach_tracepoint(program, id, "sched", "sched_process_exec")?);
+                }
+                Ok(links)
+            });
+        if ret.is_err() {
+            probes.clients.lock().await.remove(&id);
+        }
+        let links = ret?;
+        // memory probes report the allocations that are still outstanding
+        let reporter = match self {
+            Probe::Memory { .. } => Some(spawn_reporter(Arc::clone(shared_bpf), id, Arc::clone(&cli))),
+            _ => None,
+        };
+        Ok(Attachment{links, id, cli, reporter})
+    }
 
-        let cli = Arc::new(Mutex::new(PostStackClient::new(Arc::new(self.clone()))));
-        probes.clients.lock().await.insert(idx, Arc::clone(&cli));
+    pub fn scope(&self) -> &Scope {
+        match self {
+            Probe::Perf { scope, .. }
+            | Probe::Uprobe { scope, .. }
+            | Probe::OffCpu { scope }
+            | Probe::Latency { scope, .. }
+            | Probe::Memory { scope }
+            | Probe::Kprobe { scope, .. }
+            | Probe::Tracepoint { scope, .. }
+            | Probe::WallClock { scope, .. } => scope,
+        }
+    }
 
+    fn attach_links(&self, bpf: &mut Bpf, id: ProbeId) -> Result<Vec<CookieLink>> {
         match self {
-            Probe::Perf{ scope, period } => {
-                let program: &mut PerfEvent = program.try_into().unwrap();
-                match program.load() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        tracing::error!("{}", e.to_string());
-                    }
-                }
+            Probe::Perf{ scope, event, sampling, aggregate } => {
+                set_aggregate(bpf, id, *aggregate)?;
+                let program = program_fd(bpf, "capture_stack")?;
+                let links = attach_perf_event(program, id, scope, *event, sampling.policy())
+                    .with_context(|| format!("unable to sample on {event}"))?;
+                Ok(links)
+            }
+            Probe::Uprobe{scope, uprobe, value_arg} => {
+                set_value_arg(bpf, id, *value_arg)?;
+                let program = program_fd(bpf, "uprobe_enter")?;
+                let link = link::attach_uprobe(program, id, uprobe, uprobe_pid(scope), false)?;
+                Ok(vec![link])
+            }
+            Probe::Latency{scope, uprobe} => {
+                let program = program_fd(bpf, "latency_enter")?;
+                let mut links = vec![link::attach_uprobe(program, id, uprobe, uprobe_pid(scope), false)?];
 
+                let program = program_fd(bpf, "latency_exit")?;
+                links.push(link::attach_uprobe(program, id, uprobe, uprobe_pid(scope), true)?);
+                Ok(links)
+            }
+            Probe::OffCpu{ .. } => {
+                let program = program_fd(bpf, "offcpu")?;
+                let link = link::attach_tracepoint(program, id, "sched", "sched_switch")?;
+                Ok(vec![link])
+            }
+            Probe::Memory{ scope } => {
                 let mut links = vec![];
-                for cpu in online_cpus()? {
-                    let scope = match scope {
-                        Scope::Pid{pid: 0} => PerfEventScope::OneProcessOneCpu { cpu, pid: std::process::id() },
-                        Scope::Pid{pid} => PerfEventScope::OneProcessOneCpu { cpu, pid: *pid },
-                        Scope::SystemWide => PerfEventScope::AllProcessesOneCpu { cpu },
-                    };
-                    let link_id = program.attach(
-                        PerfTypeId::Software,
-                        perf_event::perf_sw_ids::PERF_COUNT_SW_TASK_CLOCK as u64,
-                        scope,
-                        SamplePolicy::Period(*period),
-                    )?;
-                    let link = program.take_link(link_id)?;
-                    links.push(link.into());
+                for func in ["malloc", "calloc", "realloc", "free"] {
+                    let program = program_fd(bpf, &format!("mem_{func}"))?;
+                    links.push(link::attach_uprobe(program, id, &format!("{ALLOC_LIBRARY}:{func}"), uprobe_pid(scope), false)?);
                 }
-                Ok(Attachment{links, idx, cli, avail})
-            }
-            Probe::Uprobe{scope, uprobe} => {
-                let program: &mut UProbe = program.try_into().unwrap();
-                match program.load() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        tracing::error!("{}", e.to_string());
-                    }
+
+                let program = program_fd(bpf, "mem_alloc_ret")?;
+                for func in ["malloc", "calloc", "realloc"] {
+                    links.push(link::attach_uprobe(program, id, &format!("{ALLOC_LIBRARY}:{func}"), uprobe_pid(scope), true)?);
                 }
+                Ok(links)
+            }
+            Probe::Kprobe{ function, .. } => {
+                let program = program_fd(bpf, "kprobe_enter")?;
+                let link = link::attach_kprobe(program, id, function)?;
+                Ok(vec![link])
+            }
+            Probe::Tracepoint{ category, name, .. } => {
+                let program = program_fd(bpf, "tracepoint_enter")?;
+                let link = link::attach_tracepoint(program, id, category, name)?;
+                Ok(vec![link])
+            }
+            Probe::WallClock{ scope, period } => {
+                let program = program_fd(bpf, "capture_stack")?;
+                let mut links = attach_perf_event(program, id, scope, PerfEventKind::TaskClock, SamplePolicy::Period(*period))?;
 
-                let mut uprobe = uprobe.split(':');
-                let src = uprobe.next().unwrap();
-                let func = uprobe.next().unwrap();
-                let pid = match scope {
-                    Scope::Pid{pid: 0} => Some(std::process::id() as i32),
-                    Scope::Pid{pid} => Some(*pid as i32),
-                    Scope::SystemWide => None,
-                };
-                let uprobe_linkid = program
-                    .attach(Some(func), 0, src, pid)
-                    .unwrap();
-                let link = program.take_link(uprobe_linkid)?;
-                Ok(Attachment{links: vec![link.into()], idx, avail, cli})
+                // the off-cpu half shares the same id so both halves go to the same client
+                let program = program_fd(bpf, "offcpu")?;
+                links.push(link::attach_tracepoint(program, id, "sched", "sched_switch")?);
+                Ok(links)
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Programs are loaded once in `init_bpf`, every probe of a kind links the same program
+fn program_fd(bpf: &Bpf, name: &str) -> Result<RawFd> {
+    let fd = match bpf.program(name).with_context(|| format!("no such program: {name}"))? {
+        Program::PerfEvent(p) => p.fd(),
+        Program::UProbe(p) => p.fd(),
+        Program::KProbe(p) => p.fd(),
+        Program::TracePoint(p) => p.fd(),
+        _ => None,
+    };
+    Ok(fd.with_context(|| format!("{name} is not loaded"))?.as_raw_fd())
+}
+
+fn attach_perf_event(program: RawFd, id: ProbeId, scope: &Scope, event: PerfEventKind, policy: SamplePolicy) -> Result<Vec<CookieLink>> {
+    let (perf_type, config) = event.type_and_config();
+    let perf_type = perf_type as u32;
+    // the events only need the directory while they are opened
+    let cgroup;
+    let targets = match scope {
+        Scope::Pid{pid: 0} => vec![PerfTarget::Process(std::process::id() as i32)],
+        Scope::Pid{pid} => vec![PerfTarget::Process(*pid as i32)],
+        Scope::Pids{pids} => pids.iter().map(|pid| PerfTarget::Process(*pid as i32)).collect(),
+        Scope::Cgroup{path} => {
+            cgroup = File::open(cgroup_dir(path)?)?;
+            vec![PerfTarget::Cgroup(cgroup.as_raw_fd())]
+        }
+        // filtered in the bpf program
+        Scope::Descendants{..} | Scope::ProcessName{..} | Scope::SystemWide => vec![PerfTarget::AllProcesses],
+    };
+    let mut links = vec![];
+    for cpu in online_cpus()? {
+        for target in &targets {
+            links.push(link::attach_perf_event(program, id, perf_type, config, &policy, target, cpu as i32)?);
+        }
+    }
+    Ok(links)
+}
+
+fn set_value_arg(bpf: &mut Bpf, id: ProbeId, value_arg: Option<u32>) -> Result<()> {
+    let mut args: HashMap<_, u32, u32> =
+        HashMap::try_from(bpf.map_mut("VALUE_ARG").context("no such map")?)?;
+    match value_arg {
+        Some(arg) => args.insert(id, arg, 0)?,
+        None => {
+            let _ = args.remove(&id);
+        }
+    }
+    Ok(())
+}
+
+fn set_aggregate(bpf: &mut Bpf, id: ProbeId, aggregate: bool) -> Result<()> {
+    let mut probes: HashMap<_, u32, u8> =
+        HashMap::try_from(bpf.map_mut("AGGREGATE").context("no such map")?)?;
+    if aggregate {
+        probes.insert(id, 1, 0)?;
+    } else {
+        let _ = probes.remove(&id);
+    }
+    Ok(())
+}
+
+/// Uprobes only fire in the process of a single pid scope, other scopes are filtered in the bpf program
+fn uprobe_pid(scope: &Scope) -> Option<i32> {
+    match scope {
+        Scope::Pid{pid: 0} => Some(std::process::id() as i32),
+        Scope::Pid{pid} => Some(*pid as i32),
+        Scope::Pids{..} | Scope::Descendants{..} | Scope::Cgroup{..} | Scope::ProcessName{..} | Scope::SystemWide => None,
+    }
+}
+
+/// kernel side programs fire for every task, so the scope is enforced in the bpf program
+fn set_scope_filter(bpf: &mut Bpf, id: ProbeId, scope: &Scope) -> Result<()> {
+    let filter = scope.to_filter()?;
+
+    clear_scope_pids(bpf, "SCOPE_PIDS", id)?;
+    let mut scope_pids: HashMap<_, u64, u8> =
+        HashMap::try_from(bpf.map_mut("SCOPE_PIDS").context("no such map")?)?;
+    for pid in scope.pids() {
+        scope_pids.insert((id as u64) << 32 | pid as u64, 1, 0)?;
+    }
+
+    // exiting processes are dropped from the pid sets of the probes in this list
+    let mut pids_scopes: Array<_, u32> =
+        Array::try_from(bpf.map_mut("PIDS_SCOPES").context("no such map")?)?;
+    let slots: Vec<u32> = (0..MAX_PIDS_SCOPES).map(|i| pids_scopes.get(&i, 0).unwrap_or(0)).collect();
+    if let Some(i) = slots.iter().position(|slot| *slot == id + 1) {
+        pids_scopes.set(i as u32, 0, 0)?;
+    }
+    if matches!(filter, Some(ProbeScope { kind: ScopeKind::Pids, .. })) {
+        let free = slots.iter().position(|slot| *slot == 0).context("too many probes scoped to pids")?;
+        pids_scopes.set(free as u32, id + 1, 0)?;
+    }
+
+    let mut probe_scope: HashMap<_, u32, ProbeScope> =
+        HashMap::try_from(bpf.map_mut("PROBE_SCOPE").context("no such map")?)?;
+    match filter {
+        Some(filter) => probe_scope.insert(id, filter, 0)?,
+        None => {
+            let _ = probe_scope.remove(&id);
+        }
+    }
+    Ok(())
+}
+
+/// Drop the pid set of every `Scope::Descendants` probe whose root process `tgid` exited,
+/// so the descendants that outlive it leave the scope
+pub(crate) fn forget_root(bpf: &mut Bpf, tgid: u32) -> Result<()> {
+    let probe_scope: HashMap<_, u32, ProbeScope> =
+        HashMap::try_from(bpf.map("PROBE_SCOPE").context("no such map")?)?;
+    let ended: Vec<ProbeId> = probe_scope
+        .iter()
+        .filter_map(|i| i.ok())
+        .filter(|(_, filter)| filter.kind == ScopeKind::Pids && filter.tgid == tgid)
+        .map(|(id, _)| id)
+        .collect();
+    for id in ended {
+        clear_scope_pids(bpf, "SCOPE_PIDS", id)?;
+        clear_scope_pids(bpf, "SCOPE_PENDING", id)?;
+    }
+    Ok(())
+}
+
+/// Remove the entries of probe `id` from a map keyed by (probe id, pid)
+fn clear_scope_pids(bpf: &mut Bpf, map: &str, id: ProbeId) -> Result<()> {
+    let mut pids: HashMap<_, u64, u8> =
+        HashMap::try_from(bpf.map_mut(map).with_context(|| format!("no such map: {map}"))?)?;
+    let stale: Vec<u64> = pids
+        .keys()
+        .filter_map(|k| k.ok())
+        .filter(|k| (k >> 32) as ProbeId == id)
+        .collect();
+    for k in stale {
+        let _ = pids.remove(&k);
+    }
+    Ok(())
+}
@@ -1,6 +1,11 @@
-use std::fmt::{Display, Formatter};
+use std::{fmt::{Display, Formatter}, os::unix::prelude::MetadataExt, path::PathBuf};
 
+use anyhow::{Result, Context};
 use serde::{Deserialize, Serialize};
+use tail2_common::scope::{ProbeScope, ScopeKind, COMM_LEN};
+
+/// cgroup v2 paths are relative to this mount
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 
 #[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
@@ -8,6 +13,22 @@ pub enum Scope {
     Pid {
         pid: u32,
     },
+    /// A process and every process it forks, until the root exits
+    Descendants {
+        pid: u32,
+    },
+    /// A set of processes, e.g. the workers of a server
+    Pids {
+        pids: Vec<u32>,
+    },
+    /// Every process in a cgroup v2 or the cgroups below it, e.g. "system.slice/docker-<id>.scope"
+    Cgroup {
+        path: String,
+    },
+    /// Every process whose name starts with `pattern`
+    ProcessName {
+        pattern: String,
+    },
     SystemWide,
 }
 
@@ -15,7 +36,76 @@ impl Display for Scope {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Scope::Pid { pid } => write!(f, "pid{}", pid),
+            Scope::Descendants { pid } => write!(f, "descendants{}", pid),
+            Scope::Pids { pids } => {
+                write!(f, "pids")?;
+                for pid in pids {
+                    write!(f, "_{}", pid)?;
+                }
+                Ok(())
+            }
+            Scope::Cgroup { path } => write!(f, "cgroup{}", path.trim_matches('/').replace('/', "_")),
+            Scope::ProcessName { pattern } => write!(f, "name{}", pattern),
             Scope::SystemWide => write!(f, "system_wide"),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Scope {
+    /// Processes to seed the `SCOPE_PIDS` set with
+    pub(crate) fn pids(&self) -> Vec<u32> {
+        match self {
+            Scope::Pids { pids } => pids.clone(),
+            Scope::Descendants { pid: 0 } => vec![std::process::id()],
+            Scope::Descendants { pid } => vec![*pid],
+            _ => vec![],
+        }
+    }
+
+    /// The in-kernel filter for this scope, `None` for system wide
+    pub(crate) fn to_filter(&self) -> Result<Option<ProbeScope>> {
+        Ok(Some(match self {
+            Scope::Pid { pid: 0 } => ProbeScope { tgid: std::process::id(), ..ProbeScope::empty(ScopeKind::Pid) },
+            Scope::Pid { pid } => ProbeScope { tgid: *pid, ..ProbeScope::empty(ScopeKind::Pid) },
+            Scope::Pids { .. } => ProbeScope::empty(ScopeKind::Pids),
+            // the root, so the pid set is dropped when it exits, see `forget_root`
+            Scope::Descendants { pid: 0 } => ProbeScope { tgid: std::process::id(), ..ProbeScope::empty(ScopeKind::Pids) },
+            Scope::Descendants { pid } => ProbeScope { tgid: *pid, ..ProbeScope::empty(ScopeKind::Pids) },
+            Scope::Cgroup { path } => {
+                let dir = cgroup_dir(path)?;
+                let meta = std::fs::metadata(&dir)?;
+                let level = dir.strip_prefix(CGROUP_ROOT)?.components().count() as u32;
+                ProbeScope { cgroup_id: meta.ino(), cgroup_level: level, ..ProbeScope::empty(ScopeKind::Cgroup) }
+            }
+            Scope::ProcessName { pattern } => {
+                let pattern = comm_pattern(pattern)?;
+                // the kernel keeps the first COMM_LEN - 1 bytes of the name
+                let len = pattern.len().min(COMM_LEN - 1);
+                let mut comm = [0; COMM_LEN];
+                comm[..len].copy_from_slice(&pattern.as_bytes()[..len]);
+                ProbeScope { comm, comm_len: len as u32, ..ProbeScope::empty(ScopeKind::ProcessName) }
+            }
+            Scope::SystemWide => return Ok(None),
+        }))
+    }
+}
+
+/// Directory of a cgroup v2, the path may or may not start with the mount point.
+/// The id of the cgroup is the inode number of the directory.
+pub(crate) fn cgroup_dir(path: &str) -> Result<PathBuf> {
+    let path = path.strip_prefix(CGROUP_ROOT).unwrap_or(path);
+    let dir = PathBuf::from(CGROUP_ROOT).join(path.trim_matches('/'));
+    let dir = dir.canonicalize().with_context(|| format!("no such cgroup: {}", dir.display()))?;
+    anyhow::ensure!(dir.starts_with(CGROUP_ROOT), "not a cgroup: {}", dir.display());
+    Ok(dir)
+}
+
+/// The name of a task is the file name of its executable, so a path is cut to that
+fn comm_pattern(pattern: &str) -> Result<&str> {
+    let pattern = pattern.trim();
+    let pattern = pattern.rsplit('/').next().unwrap_or(pattern);
+    if pattern.is_empty() || pattern.contains('\0') {
+        anyhow::bail!("invalid process name: {pattern:?}");
+    }
+    Ok(pattern)
+}
@@ -1,15 +1,16 @@
-use std::sync::Arc;
+use std::{os::fd::AsRawFd, sync::Arc};
 
 use crate::{symbolication::module_cache::ModuleCache, tail2::CACHE};
-use anyhow::Result;
-use fnv::FnvHashMap;
+use anyhow::{Context, Result};
+use aya::{maps::{Map, MapData}, Bpf};
+use fnv::{FnvHashMap, FnvHashSet};
 use procfs::process::{Process, MMPermissions};
-use tail2_common::procinfo::{user::ProcMapRow, ProcInfo};
+use tail2_common::procinfo::{user::ProcMapRow, ProcInfo, UnwindRow, MAX_UNWIND_ROWS};
 
 #[derive(Debug)]
 pub struct Processes {
     // TODO: use pid + starttime
-    pub processes: FnvHashMap<i32, Box<ProcInfo>>,
+    pub processes: FnvHashMap<i32, Vec<ProcMapRow>>,
 }
 
 impl Default for Processes {
@@ -37,15 +38,28 @@ impl Processes {
         }
     }
 
-    pub async fn detect_pid(pid: i32) -> Result<Box<ProcInfo>> {
+    pub async fn detect_pid(pid: i32) -> Result<Vec<ProcMapRow>> {
         let cache = &mut *CACHE.module.lock().await;
         let process = Process::new(pid)?;
         Processes::detect(&process, cache)
     }
 
+    /// Detect the process and make sure the unwind tables of its modules are loaded.
+    /// `pid` can be any thread of the process, the tables are held by the process.
+    pub async fn load_pid(bpf: &mut Bpf, pid: i32) -> Result<Box<ProcInfo>> {
+        let tgid = Process::new(pid)?.status()?.tgid as u32;
+        let maps = Self::detect_pid(pid).await?;
+        let rows = match bpf.map("UNWIND_ROWS").context("no such map")? {
+            Map::Array(rows) => rows,
+            _ => anyhow::bail!("UNWIND_ROWS is not an array"),
+        };
+        let loaded = &mut *CACHE.unwind_rows.lock().await;
+        loaded.load_process(rows, tgid, &maps)
+    }
+
     /// Detects the process information from the process maps.
     /// Find executable maps and resolve them
-    fn detect(process: &Process, cache: &mut ModuleCache) -> Result<Box<ProcInfo>> {
+    fn detect(process: &Process, cache: &mut ModuleCache) -> Result<Vec<ProcMapRow>> {
         let paths = process
             .maps()?
             .into_iter()
@@ -54,18 +68,16 @@ impl Processes {
                     if let procfs::process::MMapPath::Path(p) = e.pathname {
                         let path = p.to_string_lossy().to_string();
 
-                        let table = {
-                            if let Some(ret) = cache.resolve(&path) {
-                                Arc::clone(ret.unwind_table.as_ref().unwrap())
-                            } else {
-                                return None;
-                            }
-                        };
+                        let module = cache.resolve(&path)?;
 
                         return Some(ProcMapRow {
                             avma: e.address.0 as usize,
+                            avma_end: e.address.1 as usize,
                             mod_name: path,
-                            unwind_table: table,
+                            dev: e.dev,
+                            inode: e.inode,
+                            debug_id: module.debug_id.clone(),
+                            unwind_table: Arc::clone(module.unwind_table.as_ref()?),
                         });
                     }
                 }
@@ -73,6 +85,202 @@ impl Processes {
             })
             .collect::<Vec<_>>();
 
-        ProcInfo::build(paths.as_slice())
+        Ok(paths)
+    }
+}
+
+/// Placement of unwind tables in the `UNWIND_ROWS` pool, shared by every process
+/// that maps the same module. A table is freed once the last process mapping it is gone.
+#[derive(Default)]
+pub struct UnwindRows {
+    /// module key -> placement
+    loaded: FnvHashMap<String, Table>,
+    /// tgid -> keys of the modules it maps
+    processes: FnvHashMap<u32, FnvHashSet<String>>,
+    /// unused ranges below `next` as (start, len), sorted by start
+    free: Vec<(u32, u32)>,
+    next: u32,
+}
+
+struct Table {
+    start: u32,
+    len: u32,
+    /// processes mapping the module
+    users: u32,
+}
+
+/// `bpf_attr.batch`
+#[repr(C)]
+#[derive(Default)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+const BPF_MAP_UPDATE_BATCH: libc::c_long = 26;
+/// rows written per syscall
+const ROW_BATCH: usize = 8192;
+
+impl UnwindRows {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build the ProcInfo of a process, loading the tables of the modules it maps.
+    /// The modules the process mapped before and doesn't anymore are released.
+    pub(crate) fn load_process(&mut self, rows: &MapData, tgid: u32, maps: &[ProcMapRow]) -> Result<Box<ProcInfo>> {
+        let mut keys = FnvHashSet::default();
+        let info = ProcInfo::build(maps, |m| {
+            let key = Self::key(m);
+            let placement = self.load(rows, &key, m)?;
+            keys.insert(key);
+            Ok(placement)
+        });
+
+        for key in &keys {
+            if let Some(table) = self.loaded.get_mut(key) {
+                table.users += 1;
+            }
+        }
+        if let Some(old) = self.processes.insert(tgid, keys) {
+            self.release_all(old);
+        }
+        info
+    }
+
+    /// Release the tables of a process that exited or exec'd
+    pub fn release(&mut self, tgid: u32) {
+        if let Some(keys) = self.processes.remove(&tgid) {
+            self.release_all(keys);
+        }
+    }
+
+    fn release_all(&mut self, keys: FnvHashSet<String>) {
+        for key in keys {
+            let Some(table) = self.loaded.get_mut(&key) else { continue };
+            table.users -= 1;
+            if table.users == 0 {
+                let (start, len) = (table.start, table.len);
+                self.loaded.remove(&key);
+                self.free(start, len);
+            }
+        }
+    }
+
+    fn load(&mut self, rows: &MapData, key: &str, module: &ProcMapRow) -> Result<(u32, u32)> {
+        if let Some(table) = self.loaded.get(key) {
+            return Ok((table.start, table.len));
+        }
+
+        let len = module.unwind_table.rows.len() as u32;
+        let start = self.alloc(len).context("unwind row pool is full")?;
+        if let Err(e) = write_rows(rows, start, module) {
+            self.free(start, len);
+            return Err(e);
+        }
+        self.loaded.insert(key.to_owned(), Table { start, len, users: 0 });
+        Ok((start, len))
+    }
+
+    /// First fit in the free ranges, then the end of the pool
+    fn alloc(&mut self, len: u32) -> Option<u32> {
+        if let Some(i) = self.free.iter().position(|&(_, free)| free >= len) {
+            let (start, free) = self.free[i];
+            if free == len {
+                self.free.remove(i);
+            } else {
+                self.free[i] = (start + len, free - len);
+            }
+            return Some(start);
+        }
+        if self.next + len > MAX_UNWIND_ROWS {
+            return None;
+        }
+        let start = self.next;
+        self.next += len;
+        Some(start)
+    }
+
+    fn free(&mut self, start: u32, len: u32) {
+        let i = self.free.partition_point(|&(s, _)| s < start);
+        self.free.insert(i, (start, len));
+        // merge with the following range, then with the preceding one
+        if i + 1 < self.free.len() && start + len == self.free[i + 1].0 {
+            self.free[i].1 += self.free.remove(i + 1).1;
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == start {
+            self.free[i - 1].1 += self.free.remove(i).1;
+        }
+        // a range at the end goes back to the unused tail of the pool
+        if let Some(&(start, len)) = self.free.last() {
+            if start + len == self.next {
+                self.free.pop();
+                self.next = start;
+            }
+        }
+    }
+
+    /// modules without a build id are told apart by their file
+    fn key(module: &ProcMapRow) -> String {
+        if module.debug_id.chars().all(|c| c == '0' || c == '-') {
+            format!("{}:{:x}:{:x}:{}", module.mod_name, module.dev.0, module.dev.1, module.inode)
+        } else {
+            module.debug_id.clone()
+        }
+    }
+}
+
+/// Write the table of a module at `start`, a batch of rows per syscall
+fn write_rows(rows: &MapData, start: u32, module: &ProcMapRow) -> Result<()> {
+    let values: Vec<UnwindRow> = module.unwind_rows().collect();
+    let keys: Vec<u32> = (start..start + values.len() as u32).collect();
+    for (keys, values) in keys.chunks(ROW_BATCH).zip(values.chunks(ROW_BATCH)) {
+        let attr = BatchAttr {
+            keys: keys.as_ptr() as u64,
+            values: values.as_ptr() as u64,
+            count: keys.len() as u32,
+            map_fd: rows.as_raw_fd() as u32,
+            ..Default::default()
+        };
+        let ret = unsafe { libc::syscall(libc::SYS_bpf, BPF_MAP_UPDATE_BATCH, &attr, std::mem::size_of::<BatchAttr>()) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).context("unable to write unwind rows");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_freed_rows() {
+        let mut pool = UnwindRows::new();
+        let a = pool.alloc(10).unwrap();
+        let b = pool.alloc(20).unwrap();
+        let c = pool.alloc(30).unwrap();
+        assert_eq!((a, b, c), (0, 10, 30));
+
+        pool.free(a, 10);
+        pool.free(b, 20);
+        assert_eq!(pool.free, vec![(0, 30)]);
+        assert_eq!(pool.alloc(25), Some(0));
+        assert_eq!(pool.free, vec![(25, 5)]);
+
+        pool.free(c, 30);
+        assert_eq!(pool.free, vec![(25, 5)]);
+        assert_eq!(pool.next, 30);
+        pool.free(0, 25);
+        assert!(pool.free.is_empty());
+        assert_eq!(pool.next, 0);
+
+        assert_eq!(pool.alloc(MAX_UNWIND_ROWS + 1), None);
     }
 }
@@ -2,12 +2,15 @@ use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
+use crate::processes::UnwindRows;
+
 use super::{module_cache::ModuleCache, proc_map_cache::ProcMapCache, process_info_cache::ProcessInfoCache};
 
 pub struct Cache {
     pub module: Arc<Mutex<ModuleCache>>,
     pub proc_map: Arc<Mutex<ProcMapCache>>,
     pub process_info: Arc<Mutex<ProcessInfoCache>>,
+    pub unwind_rows: Arc<Mutex<UnwindRows>>,
 }
 
 impl Cache {
@@ -16,6 +19,14 @@ impl Cache {
             module: Arc::new(Mutex::new(ModuleCache::new())),
             proc_map: Arc::new(Mutex::new(ProcMapCache::new())),
             process_info: Arc::new(Mutex::new(ProcessInfoCache::new())),
+            unwind_rows: Arc::new(Mutex::new(UnwindRows::new())),
         }
     }
+
+    /// Drop everything known about a process that exited or exec'd, the caches are keyed by tgid
+    pub async fn invalidate_pid(&self, pid: u32) {
+        self.proc_map.lock().await.invalidate(pid);
+        self.process_info.lock().await.invalidate(pid);
+        self.unwind_rows.lock().await.release(pid);
+    }
 }
\ No newline at end of file
@@ -3,4 +3,6 @@ pub mod module;
 pub mod module_cache;
 pub mod proc_map_cache;
 pub mod process_info_cache;
-pub mod caches;
\ No newline at end of file
+pub mod caches;
+pub mod python_symbols;
+pub mod python_lines;
@@ -1,6 +1,5 @@
 use anyhow::Result;
-use fnv::FnvHashMap;
-use once_cell::sync::Lazy;
+use object::{Object, ObjectSection, ObjectSymbol};
 use serde::{Deserialize, Serialize};
 use std::{fmt::Debug, sync::Arc, path::Path};
 
@@ -13,13 +12,21 @@ use tail2_common::native::unwinding::aarch64::unwind_table::UnwindTable;
 #[cfg(feature = "x86_64")]
 use tail2_common::native::unwinding::x86_64::unwind_table::UnwindTable;
 
-/// a map from debug_id to the offset and size of method _PyEval_EvalFrameDefault
-pub static PYTHON_DEBUG_IDS: Lazy<FnvHashMap<&'static str, (u32, u32)>> = Lazy::new(|| {
-    let mut ret = FnvHashMap::default();
-    ret.insert("e41d84a1-ecf4-4594-a10b-ff638afa4c72", (966624, 42316)); // 3.10
-    ret.insert("d719f365-a65f-1262-0654-ed39ed608b11", (0x4a3f10, 0)); // 3.11
-    ret
-});
+/// Functions that run python bytecode, one call per python frame up to 3.10. From 3.11 on,
+/// calls from python to python stay in the same call, which then runs several frames.
+const PYTHON_EVAL_FUNCTIONS: &[&str] = &["_PyEval_EvalFrameDefault", "PyEval_EvalFrameEx"];
+
+/// Functions a thread without the GIL runs while it waits for it. `take_gil` is often inlined
+/// into its callers, so those are here too.
+const GIL_WAIT_FUNCTIONS: &[&str] = &[
+    "take_gil",
+    "PyEval_RestoreThread",
+    "PyEval_AcquireThread",
+    "PyEval_AcquireLock",
+    "PyGILState_Ensure",
+    "eval_frame_handle_pending",
+    "_Py_HandlePending",
+];
 
 #[derive(Serialize, Deserialize)]
 pub struct Module {
@@ -29,7 +36,12 @@ pub struct Module {
     pub name: String,
     pub arch: i32,
     pub debug_id: String,
-    pub py_offset: (u32, u32),
+    /// (file offset, size) of the python eval loop, empty for modules without one
+    #[serde(default)]
+    pub py_ranges: Vec<(u32, u32)>,
+    /// (file offset, size) of the functions that wait for the GIL
+    #[serde(default)]
+    pub gil_ranges: Vec<(u32, u32)>,
 }
 
 impl Eq for Module {}
@@ -55,7 +67,10 @@ impl Module {
         let obj = ElfObject::parse(&buffer)?;
         let unwind_table = Arc::new(UnwindTable::from_path(path)?);
         let debug_id = obj.debug_id().to_string();
-        let py_offset = PYTHON_DEBUG_IDS.get(debug_id.as_str()).copied().unwrap_or_default();
+        let (py_ranges, gil_ranges) = match object::File::parse(&*buffer) {
+            Ok(file) => (python_eval_ranges(&file).unwrap_or_default(), gil_wait_ranges(&file).unwrap_or_default()),
+            Err(_) => Default::default(),
+        };
         let name = Path::file_stem(Path::new(path)).unwrap_or_default().to_string_lossy().to_string();
         Ok(Self {
             unwind_table: Some(unwind_table),
@@ -63,7 +78,88 @@ impl Module {
             arch: obj.arch() as i32,
             name,
             debug_id,
-            py_offset,
+            py_ranges,
+            gil_ranges,
         })
     }
+
+    /// Whether `offset` is in the python eval loop, where python frames go in a mixed stack
+    pub fn is_python_eval(&self, offset: u32) -> bool {
+        self.py_ranges.iter().any(|&(start, size)| start <= offset && offset < start + size)
+    }
+
+    /// Whether `offset` is in a function that waits for the GIL
+    pub fn is_gil_wait(&self, offset: u32) -> bool {
+        self.gil_ranges.iter().any(|&(start, size)| start <= offset && offset < start + size)
+    }
+}
+
+/// File ranges of the first of `PYTHON_EVAL_FUNCTIONS` in the symbol table. 3.x still has
+/// `PyEval_EvalFrameEx`, but only as a wrapper around the real loop.
+fn python_eval_ranges(obj: &object::File) -> Result<Vec<(u32, u32)>> {
+    for func in PYTHON_EVAL_FUNCTIONS {
+        let ret = symbol_ranges(obj, func)?;
+        if !ret.is_empty() {
+            return Ok(ret);
+        }
+    }
+    Ok(vec![])
+}
+
+/// File ranges of `GIL_WAIT_FUNCTIONS` in the symbol table
+fn gil_wait_ranges(obj: &object::File) -> Result<Vec<(u32, u32)>> {
+    let mut ret = vec![];
+    for func in GIL_WAIT_FUNCTIONS {
+        ret.extend(symbol_ranges(obj, func)?);
+    }
+    Ok(ret)
+}
+
+/// File ranges of `func`, including the parts the compiler split off or specialized,
+/// e.g. `_PyEval_EvalFrameDefault.cold` or `take_gil.isra.0`
+fn symbol_ranges(obj: &object::File, func: &str) -> Result<Vec<(u32, u32)>> {
+    let mut ret = vec![];
+    for sym in obj.symbols().chain(obj.dynamic_symbols()) {
+        let Ok(name) = sym.name() else { continue };
+        let matches = name.strip_prefix(func).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'));
+        if !matches || sym.size() == 0 {
+            continue;
+        }
+        let Some(idx) = sym.section_index() else { continue };
+        let section = obj.section_by_index(idx)?;
+        let Some((file_start, _)) = section.file_range() else { continue };
+        let range = ((sym.address() - section.address() + file_start) as u32, sym.size() as u32);
+        if !ret.contains(&range) {
+            ret.push(range);
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(fixture: &str, main: (u32, u32), start: (u32, u32)) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures").join(fixture);
+        let data = std::fs::read(path).unwrap();
+        let obj = object::File::parse(&*data).unwrap();
+
+        assert_eq!(symbol_ranges(&obj, "main").unwrap(), [main], "{fixture}");
+        assert_eq!(symbol_ranges(&obj, "_start").unwrap(), [start], "{fixture}");
+        // only the parts split off with a '.' match, not names that start the same
+        assert!(symbol_ranges(&obj, "mai").unwrap().is_empty(), "{fixture}");
+        // `completed.N` is in .bss, which has no file range
+        assert!(symbol_ranges(&obj, "completed").unwrap().is_empty(), "{fixture}");
+        // `_init` has no size
+        assert!(symbol_ranges(&obj, "_init").unwrap().is_empty(), "{fixture}");
+
+        assert!(python_eval_ranges(&obj).unwrap().is_empty(), "{fixture}");
+    }
+
+    #[test]
+    fn test_symbol_ranges() {
+        check("x86_64/malloc", (0x1149, 0x2b), (0x1060, 0x2f));
+        check("aarch64/malloc", (0x754, 0x24), (0x640, 0x34));
+    }
 }
@@ -31,6 +31,11 @@ impl ProcMapCache {
         Ok(maps)
     }
 
+    /// The pid exited or exec'd, its mappings are gone
+    pub fn invalidate(&mut self, pid: u32) {
+        self.cache.pop(&pid);
+    }
+
     pub fn get_proc_map(pid: u32) -> Result<MemoryMaps> {
         let proc = Process::new(pid as i32).context(format!("Failed to get process {}", pid))?;
         let maps = proc.maps().context(format!("Failed to get maps for process {}", pid))?;
@@ -29,6 +29,11 @@ impl ProcessInfoCache {
         }
     }
 
+    /// The pid exited or exec'd, it may be reused by another process
+    pub fn invalidate(&mut self, pid: u32) {
+        self.cache.pop(&pid);
+    }
+
     pub fn refresh(&mut self, pid: u32) -> Option<ProcessInfo> {
         let ident = Process::new(pid as i32)
             .ok()?
@@ -0,0 +1,272 @@
+use std::{fs::File, num::NonZeroUsize, os::unix::fs::FileExt, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use lru::LruCache;
+use procfs::process::{MMapPath, Process};
+use tail2_common::{
+    python::{offsets::PythonOffsets, state::PythonSymbol, PythonVersion},
+    runtime_type::user::{python_offsets, python_version},
+};
+
+/// Line tables longer than this are ignored
+const MAX_TABLE_LEN: usize = 64 * 1024;
+
+/// Python of a process, and its memory to read code objects from
+struct Runtime {
+    version: PythonVersion,
+    offsets: PythonOffsets,
+    mem: File,
+}
+
+/// Executing lines of python frames, from the line table of their code object.
+/// Tables are read from the process memory the first time a code object shows up.
+pub struct LineTables {
+    runtimes: LruCache<u32, Option<Runtime>>,
+    /// by process and address of the code object
+    tables: LruCache<(u32, usize), Option<Arc<[u8]>>>,
+}
+
+impl Default for LineTables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineTables {
+    pub fn new() -> Self {
+        Self {
+            runtimes: LruCache::new(NonZeroUsize::new(256).unwrap()),
+            tables: LruCache::new(NonZeroUsize::new(8192).unwrap()),
+        }
+    }
+
+    /// Line of `symbol` at `lasti` in the code object at `code`,
+    /// the first line of the function if the table can't be read
+    pub fn line(&mut self, tgid: u32, code: usize, symbol: &PythonSymbol, lasti: i32) -> u32 {
+        let runtime = self
+            .runtimes
+            .get_or_insert(tgid, || Runtime::new(tgid).ok())
+            .as_ref();
+        let Some(runtime) = runtime else { return symbol.lineno };
+
+        let table = self
+            .tables
+            .get_or_insert((tgid, code), || runtime.line_table(code).ok().map(Arc::from));
+        match table {
+            Some(table) => addr2line(&runtime.version, table, symbol.lineno, lasti).unwrap_or(symbol.lineno),
+            None => symbol.lineno,
+        }
+    }
+
+    /// Drop the runtime and tables of a process that exited or exec'd,
+    /// its code objects are gone and their addresses get reused
+    pub fn forget(&mut self, tgid: u32) {
+        self.runtimes.pop(&tgid);
+        let stale: Vec<_> = self.tables.iter().map(|(k, _)| *k).filter(|(t, _)| *t == tgid).collect();
+        for key in stale {
+            self.tables.pop(&key);
+        }
+    }
+}
+
+impl Runtime {
+    fn new(tgid: u32) -> Result<Self> {
+        let process = Process::new(tgid as i32)?;
+        let (path, version) = process
+            .maps()?
+            .into_iter()
+            .find_map(|m| {
+                let MMapPath::Path(p) = m.pathname else { return None };
+                let base_name = p.file_name()?.to_str()?;
+                if !base_name.starts_with("python") && !base_name.starts_with("libpython") {
+                    return None;
+                }
+                Some((p.to_string_lossy().to_string(), python_version(base_name)?))
+            })
+            .context("no python in the process")?;
+
+        Ok(Self {
+            version,
+            offsets: python_offsets(&path, &version).context("unsupported python version")?,
+            mem: File::open(Path::new("/proc").join(tgid.to_string()).join("mem"))?,
+        })
+    }
+
+    fn read_usize(&self, addr: usize) -> Result<usize> {
+        let mut buf = [0; 8];
+        self.mem.read_exact_at(&mut buf, addr as u64)?;
+        Ok(usize::from_ne_bytes(buf))
+    }
+
+    /// Contents of the bytes object at `co_linetable`
+    fn line_table(&self, code: usize) -> Result<Vec<u8>> {
+        let bytes = self.read_usize(code + self.offsets.py_code_object.co_linetable)?;
+        let len = self.read_usize(bytes + self.offsets.py_bytes_object.ob_size)?;
+        anyhow::ensure!(len <= MAX_TABLE_LEN, "line table of {len} bytes");
+
+        let mut table = vec![0; len];
+        self.mem.read_exact_at(&mut table, (bytes + self.offsets.py_bytes_object.ob_sval) as u64)?;
+        Ok(table)
+    }
+}
+
+/// Line of the instruction at `lasti`, see `PythonStack::lasti`. `None` for instructions
+/// without a line, e.g. the ones python adds.
+pub fn addr2line(version: &PythonVersion, table: &[u8], firstlineno: u32, lasti: i32) -> Option<u32> {
+    if lasti < 0 {
+        return Some(firstlineno);
+    }
+    match (version.major, version.minor) {
+        (2, _) => lnotab(table, firstlineno, lasti as usize, false),
+        (3, ..=9) => lnotab(table, firstlineno, lasti as usize, true),
+        (3, 10) => linetable(table, firstlineno, lasti as usize * 2),
+        _ => locations(table, firstlineno, lasti as usize / 2),
+    }
+}
+
+/// `co_lnotab`: pairs of byte offset and line increments, the increments are signed from 3.6
+fn lnotab(table: &[u8], firstlineno: u32, lasti: usize, signed: bool) -> Option<u32> {
+    let mut line = firstlineno as i64;
+    let mut addr = 0;
+    for pair in table.chunks_exact(2) {
+        addr += pair[0] as usize;
+        if addr > lasti {
+            break;
+        }
+        line += if signed { pair[1] as i8 as i64 } else { pair[1] as i64 };
+    }
+    Some(line as u32)
+}
+
+/// 3.10 `co_linetable`: pairs of byte length and line increment of each range,
+/// -128 means the range has no line
+fn linetable(table: &[u8], firstlineno: u32, addr: usize) -> Option<u32> {
+    let mut line = firstlineno as i64;
+    let mut end = 0;
+    for pair in table.chunks_exact(2) {
+        let start = end;
+        end += pair[0] as usize;
+        let delta = pair[1] as i8;
+        if delta != -128 {
+            line += delta as i64;
+        }
+        if (start..end).contains(&addr) {
+            return (delta != -128).then_some(line as u32);
+        }
+    }
+    None
+}
+
+/// 3.11+ location table, see Objects/locations.md in cpython. `unit` counts code units.
+fn locations(table: &[u8], firstlineno: u32, unit: usize) -> Option<u32> {
+    let mut bytes = table.iter().copied().peekable();
+    let mut line = firstlineno as i64;
+    let mut end = 0;
+    while let Some(first) = bytes.next() {
+        let code = (first >> 3) & 15;
+        let start = end;
+        end += (first & 7) as usize + 1;
+        let has_line = match code {
+            // no location
+            15 => false,
+            // long form: line delta, end line delta, column, end column
+            14 => {
+                line += svarint(&mut bytes);
+                for _ in 0..3 {
+                    varint(&mut bytes);
+                }
+                true
+            }
+            // no column
+            13 => {
+                line += svarint(&mut bytes);
+                true
+            }
+            // one line forms, the line delta is in the code
+            10..=12 => {
+                line += (code - 10) as i64;
+                bytes.next();
+                bytes.next();
+                true
+            }
+            // short forms, same line
+            _ => {
+                bytes.next();
+                true
+            }
+        };
+        // the entries that follow start with the high bit set
+        while bytes.peek().is_some_and(|b| b & 128 == 0) {
+            bytes.next();
+        }
+        if (start..end).contains(&unit) {
+            return has_line.then_some(line as u32);
+        }
+    }
+    None
+}
+
+/// 6 bits per byte, little endian, bit 6 tells if more follow
+fn varint(bytes: &mut impl Iterator<Item = u8>) -> i64 {
+    let mut ret = 0;
+    let mut shift = 0;
+    for b in bytes {
+        ret |= ((b & 63) as i64) << shift;
+        shift += 6;
+        if b & 64 == 0 {
+            break;
+        }
+    }
+    ret
+}
+
+/// `varint` with the sign in the lowest bit
+fn svarint(bytes: &mut impl Iterator<Item = u8>) -> i64 {
+    let v = varint(bytes);
+    if v & 1 != 0 {
+        -(v >> 1)
+    } else {
+        v >> 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Tables of this function, with the lines of its instructions from `dis`:
+    /// def f(a):
+    ///     x = a + 1
+    ///     if x:
+    ///         y = [i
+    ///              for i in range(x)]
+    ///     return x
+    fn check(major: u8, minor: u8, table: &[u8], lines: &[(i32, Option<u32>)]) {
+        let version = PythonVersion { major, minor, patch: 0 };
+        for &(lasti, line) in lines {
+            assert_eq!(addr2line(&version, table, 1, lasti), line, "{major}.{minor} at {lasti}");
+        }
+    }
+
+    #[test]
+    fn test_lnotab() {
+        check(2, 7, &[0, 1, 10, 1, 6, 1, 3, 1, 31, 1], &[(0, Some(2)), (12, Some(3)), (18, Some(4)), (50, Some(6))]);
+        check(3, 8, &[0, 1, 8, 1, 4, 1, 6, 1, 6, 255, 6, 2], &[(0, Some(2)), (8, Some(3)), (20, Some(5)), (24, Some(4)), (30, Some(6))]);
+    }
+
+    #[test]
+    fn test_linetable() {
+        check(3, 10, &[8, 1, 4, 1, 6, 1, 6, 1, 6, 255, 4, 2], &[(0, Some(2)), (5, Some(3)), (9, Some(5)), (12, Some(4)), (16, Some(6))]);
+    }
+
+    #[test]
+    fn test_locations() {
+        let py312 = [
+            128, 0, 216, 8, 9, 136, 65, 137, 5, 128, 65, 217, 7, 8, 228, 22, 27, 152, 65, 147, 104, 246, 3, 1, 13, 32, 216,
+            17, 18, 242, 3, 0, 14, 15, 240, 0, 1, 13, 32, 136, 1, 240, 0, 1, 13, 32, 224, 11, 12, 128, 72, 249, 242, 5, 1,
+            13, 32,
+        ];
+        check(3, 12, &py312, &[(-2, Some(1)), (0, Some(1)), (2, Some(2)), (12, Some(3)), (16, Some(5)), (36, Some(4)),
+            (50, Some(5)), (64, Some(6)), (68, None), (76, Some(4))]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::num::NonZeroUsize;
+
+use aya::maps::{HashMap, MapData};
+use lru::LruCache;
+use tail2_common::python::state::{PythonSymbol, PY_SYMBOLS_ENTRIES, UNKNOWN_SYMBOL_ID};
+
+use super::python_lines::LineTables;
+use crate::dto::resolved_bpf_sample::ResolvedPythonFrames;
+
+/// Frame of a symbol that's unknown or has a name that isn't UTF-8
+const UNKNOWN_FRAME: &str = "[python:unknown]";
+
+/// Python symbols by id, read from `PY_SYMBOLS` when an unknown id shows up.
+/// Ids are never reassigned, so cached symbols stay valid. Both caches hold as many ids as
+/// the map, older ids are evicted from the map first.
+pub struct PythonSymbols {
+    map: HashMap<MapData, PythonSymbol, i32>,
+    symbols: LruCache<i32, PythonSymbol>,
+    /// ids that weren't in the map when it was read, e.g. evicted before the sample arrived
+    missing: LruCache<i32, ()>,
+    lines: LineTables,
+}
+
+impl PythonSymbols {
+    pub fn new(map: HashMap<MapData, PythonSymbol, i32>) -> Self {
+        Self {
+            map,
+            symbols: LruCache::new(NonZeroUsize::new(PY_SYMBOLS_ENTRIES as usize).unwrap()),
+            missing: LruCache::new(NonZeroUsize::new(PY_SYMBOLS_ENTRIES as usize).unwrap()),
+            lines: LineTables::new(),
+        }
+    }
+
+    fn refresh(&mut self) {
+        for (symbol, id) in self.map.iter().filter_map(|i| i.ok()) {
+            self.symbols.put(id, symbol);
+        }
+    }
+
+    /// Frame names of `ids` in process `tgid`. `lines` has the code object and last instruction
+    /// of every frame, without them, e.g. for aggregated stacks, frames point at the first line
+    /// of their function.
+    pub fn resolve(&mut self, ids: &[i32], lines: Option<(&[usize], &[i32])>, tgid: u32) -> ResolvedPythonFrames {
+        let unseen = |id: &i32| *id != UNKNOWN_SYMBOL_ID && !self.symbols.contains(id) && !self.missing.contains(id);
+        if ids.iter().any(unseen) {
+            self.refresh();
+            // samples arrive after their ids are stored, an id that's still not there never will be
+            for id in ids {
+                if !self.symbols.contains(id) {
+                    self.missing.put(*id, ());
+                }
+            }
+        }
+        let frames = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let Some(symbol) = self.symbols.get(id) else { return UNKNOWN_FRAME.to_owned() };
+                let line = match lines {
+                    Some((code, lasti)) => self.lines.line(tgid, code[i], symbol, lasti[i]),
+                    None => symbol.lineno,
+                };
+                ResolvedPythonFrames::symbol_name(symbol, line).unwrap_or_else(|| UNKNOWN_FRAME.to_owned())
+            })
+            .collect();
+        ResolvedPythonFrames { frames, holds_gil: None, eval_calls: vec![] }
+    }
+
+    /// Forget the line tables of a process that exited or exec'd
+    pub fn forget_pid(&mut self, tgid: u32) {
+        self.lines.forget(tgid);
+    }
+}
@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use anyhow::Result;
 
@@ -20,7 +21,7 @@ use crate::client::ws_client::messages::{AgentMessage, NewConnection};
 
 
 use crate::probes::Probe;
-use crate::probes::probe::{ProbePool, Attachment};
+use crate::probes::probe::{Attachment, ProbeId};
 use crate::symbolication::caches::Cache;
 use crate::{config::Tail2Config};
 
@@ -34,19 +35,20 @@ pub static HOSTNAME: Lazy<String> = Lazy::new(||
     gethostname::gethostname().to_string_lossy().to_string()
 );
 
+/// Sample sinks, keyed by the id the bpf programs tag each sample with
+pub type Clients = Arc<Mutex<FnvHashMap<ProbeId, Arc<Mutex<PostStackClient>>>>>;
+
+#[derive(Default)]
 pub struct Probes {
     probes: FnvHashMap<Arc<Probe>, Attachment>,
-    pub probe_pool: ProbePool,
-    pub clients: Arc<Mutex<Vec<Arc<Mutex<PostStackClient>>>>>
+    next_id: AtomicU32,
+    pub clients: Clients,
 }
 
-impl Default for Probes {
-    fn default() -> Self {
-        Self {
-            probes: FnvHashMap::default(),
-            probe_pool: ProbePool::new(5),
-            clients: Default::default(),
-        }
+impl Probes {
+    /// ids are never reused, so late samples of a detached probe can't end up in a new one
+    pub(crate) fn next_id(&self) -> ProbeId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
     }
 }
 
@@ -87,7 +89,7 @@ impl Tail2 {
                     return;
                 }
 
-                let attachment = probe.attach(&mut *bpf.lock().await, &*probes.lock().await).await.unwrap();
+                let attachment = probe.attach(&bpf, &*probes.lock().await).await.unwrap();
                 probes.lock().await.probes.insert(probe.clone(), attachment);
                 tracing::info!("Probe attached: {:?}", probe);
 
@@ -117,7 +119,8 @@ impl Tail2 {
                 }
 
                 let links = probes.lock().await.probes.remove(&probe).unwrap();
-                links.detach().await;
+                let clients = Arc::clone(&probes.lock().await.clients);
+                links.detach(&mut *bpf.lock().await, &clients).await;
 
                 tracing::info!("Probe detached: {:?}", &probe);
                 ws_tx.send(agent_msg).unwrap();
@@ -126,7 +129,14 @@ impl Tail2 {
                 if let Some(halt_tx) = &mut *halt_tx.lock().await {
                     halt_tx.send(()).unwrap();
                     ws_tx.send(AgentMessage::Halt).unwrap();
-                    probes.lock().await.probes.clear();
+                    // detach stops the reporters and drops the probes' entries in the bpf maps
+                    let mut probes = probes.lock().await;
+                    let clients = Arc::clone(&probes.clients);
+                    let bpf = &mut *bpf.lock().await;
+                    for (_, attachment) in probes.probes.drain() {
+                        attachment.detach(bpf, &clients).await;
+                    }
+                    clients.lock().await.clear();
                 } else {
                     ws_tx.send(AgentMessage::AgentError {
                         message: "Unable to halt".to_string()
@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+
+use tail2_common::bpf_sample::BpfSample;
+
+use crate::{dto::stack_dto::native_module, tail2::CACHE};
+
+/// Unwinder failures per module, tallied from raw samples
+#[derive(Debug, Default)]
+pub struct UnwindReport {
+    samples: u64,
+    failures: u64,
+    /// module where unwinding stopped -> error -> samples
+    modules: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl UnwindReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn add(&mut self, sample: &BpfSample) {
+        self.samples += 1;
+        let error = match sample.native_stack.unwind_error {
+            Some(error) => error,
+            None => return,
+        };
+        self.failures += 1;
+
+        // the outermost frame the unwinder got to
+        let len = sample.native_stack.unwind_success.unwrap_or(0);
+        let module = match len.checked_sub(1) {
+            Some(last) => {
+                let address = sample.native_stack.native_stack[last];
+                let mut module_cache = CACHE.module.lock().await;
+                let mut proc_map_cache = CACHE.proc_map.lock().await;
+                native_module(sample.pidtgid.tgid(), &mut proc_map_cache, &mut module_cache, address)
+                    .map(|(_, module)| module.name.clone())
+                    .unwrap_or_else(|_| "[unmapped]".to_owned())
+            }
+            None => "[no frames]".to_owned(),
+        };
+        *self.modules
+            .entry(module)
+            .or_default()
+            .entry(format!("{error:?}"))
+            .or_default() += 1;
+    }
+
+    pub fn print(&self) {
+        println!("{} of {} samples failed to unwind", self.failures, self.samples);
+
+        let mut modules: Vec<_> = self.modules
+            .iter()
+            .map(|(module, errors)| (module, errors, errors.values().sum::<u64>()))
+            .collect();
+        modules.sort_by(|a, b| b.2.cmp(&a.2));
+        for (module, errors, failures) in modules {
+            println!("{:>8} {:>6.2}%  {}", failures, failures as f64 * 100. / self.samples as f64, module);
+            for (error, n) in errors {
+                println!("{:>8}          {}", n, error);
+            }
+        }
+    }
+}
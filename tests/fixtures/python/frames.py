@@ -0,0 +1,60 @@
+# Dumps the memory tail2 reads to walk the python stack of this process, see `test_walk_frames`
+# in tail2-common/src/python/offsets.rs. 3.X-frames.txt were generated with:
+#   python3.X frames.py 3.X.txt > 3.X-frames.txt
+# `dump` reads through ctypes types only, calling python functions would push frames on top of it.
+import ctypes
+import sys
+
+offsets = dict(line.split(": ") for line in open(sys.argv[1]).read().splitlines())
+offsets = {k: int(v) for k, v in offsets.items()}
+Ptr = ctypes.c_uint64
+Str = ctypes.c_char * 64
+
+
+def dump():
+    reads = {}
+    runtime = ctypes.addressof(ctypes.c_void_p.in_dll(ctypes.pythonapi, "_PyRuntime"))
+    addr = runtime + offsets["runtime.interpreters.main"]
+    interp = reads[addr] = Ptr.from_address(addr).value
+    addr = interp + offsets["interp.threads.head"]
+    tstate = reads[addr] = Ptr.from_address(addr).value
+    addr = tstate + offsets["ts.cframe"]
+    cframe = reads[addr] = Ptr.from_address(addr).value
+    addr = cframe + offsets["cframe.current_frame"]
+    frame = reads[addr] = Ptr.from_address(addr).value
+    while frame:
+        for flag in ("frame.owner", "frame.is_entry"):
+            if flag in offsets:
+                addr = frame + offsets[flag]
+                reads[addr] = ctypes.c_uint8.from_address(addr).value.to_bytes(1, "little")
+        addr = frame + offsets["frame.f_code"]
+        code = reads[addr] = Ptr.from_address(addr).value
+        addr = code + offsets["co_qualname"]
+        name = reads[addr] = Ptr.from_address(addr).value
+        addr = name + offsets["str.data"]
+        reads[addr] = Str.from_address(addr).raw
+        addr = frame + offsets["frame.previous"]
+        frame = reads[addr] = Ptr.from_address(addr).value
+
+    print(f"runtime: {runtime:x}")
+    for addr, value in sorted(reads.items()):
+        if isinstance(value, int):
+            value = value.to_bytes(8, "little")
+        print(f"{addr:x}: {value.hex()}")
+
+
+def leaf(_):
+    dump()
+
+
+def middle():
+    # called back from C, so leaf starts a call of the eval loop of its own
+    sorted([0], key=leaf)
+
+
+class Root:
+    def run(self):
+        middle()
+
+
+Root().run()
@@ -0,0 +1,65 @@
+// Prints the struct offsets tail2 reads from a CPython build, using its internal headers.
+// 3.X.txt were generated from the pyenv builds of 3.7.16, 3.8.18, 3.9.18, 3.10.13, 3.11.7
+// and 3.12.1 with:
+//   gcc -I$PREFIX/include/python3.X -I$PREFIX/include/python3.Xm offsets.c -o offsets && ./offsets > 3.X.txt
+// and are checked against the tables in tail2-common/src/python/offsets.rs.
+// Before 3.11 only the GIL holder is printed.
+#define Py_BUILD_CORE 1
+#include <Python.h>
+#if PY_VERSION_HEX >= 0x03090000
+#include <internal/pycore_interp.h>
+#include <internal/pycore_runtime.h>
+#elif PY_VERSION_HEX >= 0x03080000
+#include <internal/pycore_pystate.h>
+#else
+#include <internal/pystate.h>
+#endif
+#if PY_VERSION_HEX >= 0x030B0000
+#include <internal/pycore_frame.h>
+#endif
+#if PY_VERSION_HEX >= 0x030C0000
+#include <internal/pycore_gil.h>
+#endif
+#include <stddef.h>
+#include <stdio.h>
+#define P(n, e) printf("%s: %zu\n", n, (size_t)(e))
+int main() {
+#if PY_VERSION_HEX >= 0x030B0000
+  P("ob_type", offsetof(PyObject, ob_type));
+  P("str.data", sizeof(PyASCIIObject));
+  P("ob_size", offsetof(PyVarObject, ob_size));
+  P("tp_name", offsetof(PyTypeObject, tp_name));
+  P("ts.next", offsetof(PyThreadState, next));
+  P("ts.interp", offsetof(PyThreadState, interp));
+  P("ts.cframe", offsetof(PyThreadState, cframe));
+  P("ts.thread_id", offsetof(PyThreadState, thread_id));
+  P("interp.threads.head", offsetof(PyInterpreterState, threads.head));
+  P("runtime.interpreters.main", offsetof(_PyRuntimeState, interpreters.main));
+  P("cframe.current_frame", offsetof(_PyCFrame, current_frame));
+  P("frame.previous", offsetof(_PyInterpreterFrame, previous));
+  P("frame.f_code", offsetof(_PyInterpreterFrame, f_code));
+  P("frame.owner", offsetof(_PyInterpreterFrame, owner));
+  P("frame.localsplus", offsetof(_PyInterpreterFrame, localsplus));
+  P("co_filename", offsetof(PyCodeObject, co_filename));
+  P("co_name", offsetof(PyCodeObject, co_name));
+  P("co_qualname", offsetof(PyCodeObject, co_qualname));
+  P("co_localsplusnames", offsetof(PyCodeObject, co_localsplusnames));
+  P("co_firstlineno", offsetof(PyCodeObject, co_firstlineno));
+  P("tuple.ob_item", offsetof(PyTupleObject, ob_item));
+  P("frame.prev_instr", offsetof(_PyInterpreterFrame, prev_instr));
+  P("co_linetable", offsetof(PyCodeObject, co_linetable));
+  P("co_code_adaptive", offsetof(PyCodeObject, co_code_adaptive));
+  P("bytes.ob_sval", offsetof(PyBytesObject, ob_sval));
+#endif
+#if PY_VERSION_HEX < 0x030C0000
+#if PY_VERSION_HEX >= 0x030B0000
+  P("frame.is_entry", offsetof(_PyInterpreterFrame, is_entry));
+#endif
+  P("runtime.gilstate.tstate_current", offsetof(_PyRuntimeState, gilstate.tstate_current));
+#else
+  P("interp.ceval.gil", offsetof(PyInterpreterState, ceval.gil));
+  P("gil.locked", offsetof(struct _gil_runtime_state, locked));
+  P("gil.last_holder", offsetof(struct _gil_runtime_state, last_holder));
+#endif
+  return 0;
+}
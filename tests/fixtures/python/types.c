@@ -0,0 +1,12 @@
+// Python types with their debug info, for the tests of tail2-common/src/python/dwarf.rs.
+// 3.X-types.debug were built against the pyenv builds of 3.11.7 and 3.12.1 with:
+//   gcc -g -fno-eliminate-unused-debug-types -shared -nostdlib -I$PREFIX/include/python3.X types.c -o types.so
+//   objcopy --only-keep-debug --compress-debug-sections=zlib types.so 3.X-types.debug
+#define Py_BUILD_CORE 1
+#include <Python.h>
+#include <internal/pycore_frame.h>
+#include <internal/pycore_interp.h>
+#include <internal/pycore_runtime.h>
+#if PY_VERSION_HEX >= 0x030C0000
+#include <internal/pycore_gil.h>
+#endif
@@ -29,6 +29,7 @@ async fn main() -> Result<()> {
             let probe = Arc::new(Probe::Uprobe {
                 scope: Scope::Pid{pid: pid.unwrap()},
                 uprobe,
+                value_arg: None,
             });
             let _attachment = probe.attach(&mut *t2.bpf.lock().await, &*t2.probes.lock().await).await.unwrap();
 